@@ -8,5 +8,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
         .out_dir("./src/router")
         .compile(&["./proto/router/router.proto"], &["./proto/router"])?;
+    tonic_build::configure()
+        .out_dir("./src/northbound")
+        .compile(
+            &["./proto/northbound/northbound.proto"],
+            &["./proto/northbound"],
+        )?;
     Ok(())
 }
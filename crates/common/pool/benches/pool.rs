@@ -0,0 +1,67 @@
+// Concurrent gets of built items, run with `cargo bench -p pool`.
+use criterion::{criterion_group, criterion_main, Criterion};
+use pool::{MutexPool, PoolConfig, PoolItemBuilder, RwLockPool};
+use std::future::Future;
+use std::sync::Arc;
+
+const CONCURRENCY: usize = 64;
+const TOKENS: usize = 8;
+
+#[derive(Debug, Clone)]
+struct NumberBuilder;
+
+#[async_trait::async_trait]
+impl PoolItemBuilder for NumberBuilder {
+    type Token = usize;
+    type Item = usize;
+    type Error = ();
+
+    async fn build(&self, token: &Self::Token) -> Result<Self::Item, Self::Error> {
+        Ok(*token)
+    }
+}
+
+async fn concurrent_gets<F>(get: impl Fn(usize) -> F)
+where
+    F: Future<Output = Result<usize, Arc<()>>> + Send + 'static,
+{
+    let gets = (0..CONCURRENCY)
+        .map(|i| tokio::spawn(get(i % TOKENS)))
+        .collect::<Vec<_>>();
+    for get in gets {
+        get.await.unwrap().unwrap();
+    }
+}
+
+fn bench_concurrent_gets(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("concurrent_gets");
+
+    let pool = MutexPool::new(NumberBuilder, PoolConfig::default());
+    group.bench_function("mutex_pool", |b| {
+        b.to_async(&runtime).iter(|| {
+            concurrent_gets(|token| {
+                let pool = pool.clone();
+                async move { pool.get(&token).await.map(|item| *item) }
+            })
+        })
+    });
+
+    let pool = RwLockPool::new(NumberBuilder, PoolConfig::default());
+    group.bench_function("rwlock_pool", |b| {
+        b.to_async(&runtime).iter(|| {
+            concurrent_gets(|token| {
+                let pool = pool.clone();
+                async move { pool.get(&token).await.map(|item| *item) }
+            })
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_concurrent_gets);
+criterion_main!(benches);
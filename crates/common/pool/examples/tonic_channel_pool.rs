@@ -1,11 +1,11 @@
-use pool::MutexPool;
+use pool::{MutexPool, PoolConfig};
 use tonic::transport::Channel;
 
 #[tokio::main]
 async fn main() {
     let channel_builder = ChannelBuilder;
     // hold by app or static
-    let channel_pool = MutexPool::new(channel_builder, None);
+    let channel_pool = MutexPool::new(channel_builder, PoolConfig::default());
 
     let addr = "http://127.0.0.1:50000".to_string();
     let channel = channel_pool.get(&addr).await;
@@ -1,42 +1,597 @@
-use std::collections::HashMap;
-use std::fmt::Debug;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Formatter};
+use std::future::Future;
 use std::hash::Hash;
-use std::sync::Arc;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
 use std::time::Duration;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{watch, Mutex, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+mod stats;
+
+use stats::Stats;
+pub use stats::TokenStats;
+
+// Events are traced if the tracing feature enabled.
+macro_rules! event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)+)
+    };
+}
+
+// Retry interval is doubled after each failed build, up to this.
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+// Identify the items, so an item replaced while checking is not evicted.
+static NEXT_SLOT_ID: AtomicU64 = AtomicU64::new(0);
 
-// FIXME support concurrent items per token and retry build.
-// FIXME Add check item alive policy.
 #[async_trait::async_trait]
-pub trait PoolItemBuilder: Clone {
-    type Token;
-    type Item;
-    type Error;
+pub trait PoolItemBuilder: Clone + Send + Sync {
+    type Token: Send + Sync;
+    type Item: Send + Sync;
+    type Error: Send;
 
     async fn build(&self, token: &Self::Token) -> Result<Self::Item, Self::Error>;
 
-    /// When check is false, pool remove item.
-    async fn check(&self) -> Result<bool, Self::Error> {
-        todo!()
+    /// When check is false or failed, pool remove item. Items are always alive by default.
+    async fn check(&self, _token: &Self::Token, _item: &Self::Item) -> Result<bool, Self::Error> {
+        Ok(true)
     }
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct PoolConfig {
+    /// Failed build is returned at once if none.
+    pub retry: Option<RetryConfig>,
+    /// Items not got in the idle timeout are evicted when checking.
+    pub idle_timeout: Option<Duration>,
+    /// Interval of the checker spawned by the pool owner, items are checked only on demand if
+    /// none.
+    pub check_interval: Option<Duration>,
+    pub items: ItemsConfig,
+}
+
+/// Items of a token, one item by default.
 #[derive(Debug, Clone)]
-pub struct MutexPool<ItemBuilder: PoolItemBuilder + Debug> {
-    inner: Arc<Mutex<HashMap<ItemBuilder::Token, ItemBuilder::Item>>>,
+pub struct ItemsConfig {
+    /// Items built one by one for the first gets, idle items are not evicted below it while
+    /// the token is used.
+    pub min: usize,
+    /// Items at most, at least the min.
+    pub max: usize,
+    pub balance: Balance,
+    /// Another item is built when every item has the calls in flight, until the max.
+    pub scale_up_in_flight: usize,
+}
 
-    item_builder: ItemBuilder,
+impl Default for ItemsConfig {
+    fn default() -> Self {
+        ItemsConfig {
+            min: 1,
+            max: 1,
+            balance: Balance::default(),
+            scale_up_in_flight: 1,
+        }
+    }
+}
 
-    retry_build_config: Option<RetryConfig>,
+/// How a item of the token is selected.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    LeastInFlight,
 }
 
-#[derive(Debug, Clone)]
-pub struct RwLockPool<ItemBuilder: PoolItemBuilder + Debug> {
-    inner: Arc<RwLock<HashMap<ItemBuilder::Token, ItemBuilder::Item>>>,
+/// Item got from the pool, the call is in flight until it's dropped.
+#[derive(Debug)]
+pub struct Pooled<Item> {
+    item: Item,
+    _in_flight: InFlight,
+}
+
+impl<Item> Deref for Pooled<Item> {
+    type Target = Item;
+
+    fn deref(&self) -> &Self::Target {
+        &self.item
+    }
+}
+
+#[derive(Debug)]
+struct InFlight(Arc<AtomicUsize>);
 
+impl InFlight {
+    fn new(in_flight: &Arc<AtomicUsize>) -> InFlight {
+        in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(in_flight.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn is_idle(last_used: &AtomicU64, idle_timeout: Option<Duration>, now: u64) -> bool {
+    idle_timeout.is_some_and(|idle_timeout| {
+        now.saturating_sub(last_used.load(Ordering::Relaxed)) >= idle_timeout.as_millis() as u64
+    })
+}
+
+// Result of the build shared by the callers selected the slot.
+#[derive(Debug)]
+enum Build<Item, Error> {
+    Pending,
+    Built(Item),
+    Failed(Arc<Error>),
+}
+
+#[derive(Debug)]
+struct Slot<Item, Error> {
+    id: u64,
+    // Taken by the caller building the item, the others wait for the build result.
+    building: AtomicBool,
+    build: watch::Sender<Build<Item, Error>>,
+    in_flight: Arc<AtomicUsize>,
+    last_used: AtomicU64,
+}
+
+impl<Item: Clone, Error> Slot<Item, Error> {
+    fn new(now: u64) -> Slot<Item, Error> {
+        Slot {
+            id: NEXT_SLOT_ID.fetch_add(1, Ordering::Relaxed),
+            building: AtomicBool::new(false),
+            build: watch::Sender::new(Build::Pending),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            last_used: AtomicU64::new(now),
+        }
+    }
+
+    fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    fn item(&self) -> Option<Item> {
+        match &*self.build.borrow() {
+            Build::Built(item) => Some(item.clone()),
+            _ => None,
+        }
+    }
+
+    fn is_built(&self) -> bool {
+        matches!(*self.build.borrow(), Build::Built(_))
+    }
+
+    // Single flight, the first caller builds the item and the others get the same result, so
+    // a failed build fails all its waiters together. The build is taken over by a waiter if
+    // the building caller is cancelled.
+    async fn get_or_build<F, Fut>(&self, build: F) -> Result<Item, Arc<Error>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Item, Error>>,
+    {
+        loop {
+            // Subscribed before taking the build, so an abandoned build is never missed.
+            let mut result = self.build.subscribe();
+            if !self.building.swap(true, Ordering::AcqRel) {
+                let mut abandoned = Abandoned(Some(self));
+                let built = build().await.map_err(Arc::new);
+                abandoned.0 = None;
+                self.build.send_replace(match &built {
+                    Ok(item) => Build::Built(item.clone()),
+                    Err(err) => Build::Failed(err.clone()),
+                });
+                return built;
+            }
+            match &*result.borrow_and_update() {
+                Build::Built(item) => return Ok(item.clone()),
+                Build::Failed(err) => return Err(err.clone()),
+                Build::Pending => {}
+            }
+            // Never closed, the sender is owned by the slot.
+            let _ = result.changed().await;
+        }
+    }
+}
+
+// Release the build of a cancelled caller, and wake the waiters to take it over.
+struct Abandoned<'a, Item, Error>(Option<&'a Slot<Item, Error>>);
+
+impl<Item, Error> Drop for Abandoned<'_, Item, Error> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.0 {
+            slot.building.store(false, Ordering::Release);
+            slot.build.send_replace(Build::Pending);
+        }
+    }
+}
+
+// Items of a token.
+#[derive(Debug)]
+struct Entry<Item, Error> {
+    // Milliseconds since the pool created, updated without the write lock.
+    last_used: AtomicU64,
+    next: AtomicUsize,
+    // Never locked across awaits.
+    slots: std::sync::Mutex<Vec<Arc<Slot<Item, Error>>>>,
+}
+
+impl<Item: Clone, Error> Entry<Item, Error> {
+    fn new(now: u64) -> Entry<Item, Error> {
+        Entry {
+            last_used: AtomicU64::new(now),
+            next: AtomicUsize::new(0),
+            slots: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    // Select a built item by the balance, or the build in progress to wait for when more
+    // items are needed, or a new slot to build if none in progress.
+    fn select(&self, config: &ItemsConfig, now: u64) -> (Arc<Slot<Item, Error>>, InFlight) {
+        let mut slots = self.slots.lock().unwrap();
+        let built = slots
+            .iter()
+            .filter(|slot| slot.is_built())
+            .collect::<Vec<_>>();
+        let selected = match config.balance {
+            _ if built.is_empty() => None,
+            Balance::RoundRobin => {
+                Some(built[self.next.fetch_add(1, Ordering::Relaxed) % built.len()])
+            }
+            Balance::LeastInFlight => built.iter().copied().min_by_key(|slot| slot.in_flight()),
+        };
+        let busy = built
+            .iter()
+            .all(|slot| slot.in_flight() >= config.scale_up_in_flight);
+        let max = config.max.max(config.min).max(1);
+        let scale_up = slots.len() < config.min || (busy && slots.len() < max);
+
+        let slot = match selected {
+            Some(selected) if !scale_up => selected.clone(),
+            _ => match slots.iter().find(|slot| !slot.is_built()) {
+                Some(building) => building.clone(),
+                None if slots.len() < max => {
+                    let slot = Arc::new(Slot::new(now));
+                    slots.push(slot.clone());
+                    slot
+                }
+                // Can't be none, no slot is only when it's less than the max.
+                None => selected.unwrap().clone(),
+            },
+        };
+        slot.last_used.store(now, Ordering::Relaxed);
+        let in_flight = InFlight::new(&slot.in_flight);
+        (slot, in_flight)
+    }
+
+    // Failed slot is removed, so the item is built again in a new slot.
+    fn remove_failed(&self, failed: &Slot<Item, Error>) {
+        self.slots
+            .lock()
+            .unwrap()
+            .retain(|slot| slot.id != failed.id || slot.is_built());
+    }
+}
+
+type EntryMap<Token, Item, Error> = HashMap<Token, Arc<Entry<Item, Error>>>;
+
+// The lock is held only to find or insert entries, items are built out of the lock.
+enum EntriesLock<Token, Item, Error> {
+    Mutex(Mutex<EntryMap<Token, Item, Error>>),
+    RwLock(RwLock<EntryMap<Token, Item, Error>>),
+}
+
+impl<Token, Item, Error> EntriesLock<Token, Item, Error> {
+    async fn read<R>(&self, f: impl FnOnce(&EntryMap<Token, Item, Error>) -> R) -> R {
+        match self {
+            EntriesLock::Mutex(entries) => f(&*entries.lock().await),
+            EntriesLock::RwLock(entries) => f(&*entries.read().await),
+        }
+    }
+
+    async fn write<R>(&self, f: impl FnOnce(&mut EntryMap<Token, Item, Error>) -> R) -> R {
+        match self {
+            EntriesLock::Mutex(entries) => f(&mut *entries.lock().await),
+            EntriesLock::RwLock(entries) => f(&mut *entries.write().await),
+        }
+    }
+}
+
+// Shared by the clones of a pool.
+struct Shared<ItemBuilder: PoolItemBuilder> {
+    entries: EntriesLock<ItemBuilder::Token, ItemBuilder::Item, ItemBuilder::Error>,
     item_builder: ItemBuilder,
+    config: PoolConfig,
+    stats: Stats<ItemBuilder::Token>,
+    created: Instant,
+}
+
+impl<ItemBuilder: PoolItemBuilder + Debug> Debug for Shared<ItemBuilder> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Shared")
+            .field("item_builder", &self.item_builder)
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<ItemBuilder> Shared<ItemBuilder>
+where
+    ItemBuilder: PoolItemBuilder,
+    ItemBuilder::Token: Clone + Eq + Hash + Debug,
+    ItemBuilder::Item: Clone,
+    ItemBuilder::Error: Debug,
+{
+    fn new(
+        entries: EntriesLock<ItemBuilder::Token, ItemBuilder::Item, ItemBuilder::Error>,
+        item_builder: ItemBuilder,
+        config: PoolConfig,
+    ) -> Arc<Self> {
+        Arc::new(Shared {
+            entries,
+            item_builder,
+            config,
+            stats: Stats::new(),
+            created: Instant::now(),
+        })
+    }
+
+    fn now(&self) -> u64 {
+        self.created.elapsed().as_millis() as u64
+    }
+
+    async fn get(
+        &self,
+        token: &ItemBuilder::Token,
+    ) -> Result<Pooled<ItemBuilder::Item>, Arc<ItemBuilder::Error>> {
+        let found = self
+            .entries
+            .read(|entries| entries.get(token).cloned())
+            .await;
+        let entry = match found {
+            Some(entry) => entry,
+            None => {
+                self.entries
+                    .write(|entries| {
+                        entries
+                            .entry(token.clone())
+                            .or_insert_with(|| Arc::new(Entry::new(self.now())))
+                            .clone()
+                    })
+                    .await
+            }
+        };
+        let now = self.now();
+        entry.last_used.store(now, Ordering::Relaxed);
+        let (slot, in_flight) = entry.select(&self.config.items, now);
+
+        // Callers selected the slot share the result of the build in progress.
+        let result = slot.get_or_build(|| self.build(token)).await;
+        match result {
+            Ok(item) => Ok(Pooled {
+                item,
+                _in_flight: in_flight,
+            }),
+            Err(err) => {
+                entry.remove_failed(&slot);
+                Err(err)
+            }
+        }
+    }
+
+    // Build the item, retried by the config if failed.
+    async fn build(
+        &self,
+        token: &ItemBuilder::Token,
+    ) -> Result<ItemBuilder::Item, ItemBuilder::Error> {
+        let mut retries = 0;
+        loop {
+            self.stats.record(token, |counters| counters.builds += 1);
+            let err = match self.item_builder.build(token).await {
+                Ok(item) => {
+                    event!(debug, "Pool built item of {:?}", token);
+                    return Ok(item);
+                }
+                Err(err) => err,
+            };
+            self.stats
+                .record(token, |counters| counters.build_failures += 1);
+            match self.config.retry.as_ref() {
+                Some(retry) if retry.should_retry(retries) => {
+                    event!(
+                        warn,
+                        "Pool build item of {:?} failed, retry {}, cause: {:?}",
+                        token,
+                        retries + 1,
+                        err
+                    );
+                    self.stats.record(token, |counters| counters.retries += 1);
+                    tokio::time::sleep(retry.interval(retries)).await;
+                    retries += 1;
+                }
+                _ => {
+                    event!(
+                        warn,
+                        "Pool build item of {:?} failed, cause: {:?}",
+                        token,
+                        err
+                    );
+                    return Err(err);
+                }
+            }
+        }
+    }
 
-    retry_build_config: Option<RetryConfig>,
+    async fn invalidate(&self, token: &ItemBuilder::Token) -> bool {
+        let removed = self.entries.write(|entries| entries.remove(token)).await;
+        let Some(entry) = removed else {
+            return false;
+        };
+        let evicted = entry.slots.lock().unwrap().len() as u64;
+        self.stats
+            .record(token, |counters| counters.evictions += evicted);
+        event!(debug, "Pool invalidated {} items of {:?}", evicted, token);
+        true
+    }
+
+    async fn check(&self) -> usize {
+        let items = self
+            .entries
+            .read(|entries| {
+                let mut items = Vec::new();
+                for (token, entry) in entries.iter() {
+                    for slot in entry.slots.lock().unwrap().iter() {
+                        if let Some(item) = slot.item() {
+                            items.push((token.clone(), slot.id, item));
+                        }
+                    }
+                }
+                items
+            })
+            .await;
+        let mut dead = HashSet::new();
+        for (token, id, item) in items {
+            if !matches!(self.item_builder.check(&token, &item).await, Ok(true)) {
+                dead.insert(id);
+            }
+        }
+
+        let idle_timeout = self.config.idle_timeout;
+        let min = self.config.items.min;
+        self.entries
+            .write(|entries| {
+                // Checked under the lock, so the entries got while checking are not idle.
+                let now = self.now();
+                let mut evicted = 0;
+                entries.retain(|token, entry| {
+                    // Held by the gets selecting or waiting for the build, they add slots to it.
+                    let getting = Arc::strong_count(entry) > 1;
+                    let mut slots = entry.slots.lock().unwrap();
+                    let before = slots.len();
+                    slots.retain(|slot| !dead.contains(&slot.id));
+                    let alive = slots.len();
+                    if alive < before {
+                        event!(
+                            info,
+                            "Pool evicted {} dead items of {:?}",
+                            before - alive,
+                            token
+                        );
+                    }
+                    // Entries with builds pending are not idle, the built items wait for them.
+                    let pending = getting || slots.iter().any(|slot| !slot.is_built());
+                    if !pending && is_idle(&entry.last_used, idle_timeout, now) {
+                        slots.clear();
+                    } else {
+                        // Scale down the items not selected in the idle timeout.
+                        let mut removable = slots.len().saturating_sub(min);
+                        slots.retain(|slot| {
+                            let idle = slot.in_flight() == 0
+                                && slot.is_built()
+                                && is_idle(&slot.last_used, idle_timeout, now);
+                            if idle && removable > 0 {
+                                removable -= 1;
+                                return false;
+                            }
+                            true
+                        });
+                    }
+                    if slots.len() < alive {
+                        event!(
+                            debug,
+                            "Pool evicted {} idle items of {:?}",
+                            alive - slots.len(),
+                            token
+                        );
+                    }
+                    let token_evicted = before - slots.len();
+                    if token_evicted > 0 {
+                        self.stats
+                            .record(token, |counters| counters.evictions += token_evicted as u64);
+                    }
+                    evicted += token_evicted;
+                    getting || !slots.is_empty()
+                });
+                evicted
+            })
+            .await
+    }
+
+    async fn stats(&self) -> Vec<TokenStats<ItemBuilder::Token>> {
+        let mut counters = self.stats.snapshot();
+        let now = self.now();
+        let mut stats = self
+            .entries
+            .read(|entries| {
+                entries
+                    .iter()
+                    .map(|(token, entry)| {
+                        let slots = entry.slots.lock().unwrap();
+                        let counters = counters.remove(token).unwrap_or_default();
+                        let last_used = entry.last_used.load(Ordering::Relaxed);
+                        TokenStats {
+                            items: slots.len(),
+                            in_flight: slots.iter().map(|slot| slot.in_flight()).sum(),
+                            since_last_used: Some(Duration::from_millis(
+                                now.saturating_sub(last_used),
+                            )),
+                            ..TokenStats::new(token.clone(), counters)
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .await;
+        // Tokens holding no items now, like all items failed or evicted.
+        stats.extend(
+            counters
+                .into_iter()
+                .map(|(token, counters)| TokenStats::new(token, counters)),
+        );
+        stats
+    }
+}
+
+// Check items every interval in the background, stopped when all the pool clones dropped.
+fn spawn_checker<ItemBuilder>(
+    shared: Weak<Shared<ItemBuilder>>,
+    interval: Duration,
+) -> JoinHandle<()>
+where
+    ItemBuilder: PoolItemBuilder + 'static,
+    ItemBuilder::Token: Clone + Eq + Hash + Debug + 'static,
+    ItemBuilder::Item: Clone + 'static,
+    ItemBuilder::Error: Sync + Debug + 'static,
+{
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick completes immediately.
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let Some(shared) = shared.upgrade() else {
+                return;
+            };
+            shared.check().await;
+        }
+    })
+}
+
+/// All callers wait for the lock, suitable for few callers.
+#[derive(Debug, Clone)]
+pub struct MutexPool<ItemBuilder: PoolItemBuilder + Debug> {
+    inner: Arc<Shared<ItemBuilder>>,
+}
+
+/// Callers of built items only wait for the read lock, suitable for many concurrent callers.
+#[derive(Debug, Clone)]
+pub struct RwLockPool<ItemBuilder: PoolItemBuilder + Debug> {
+    inner: Arc<Shared<ItemBuilder>>,
 }
 
 impl<ItemBuilder> MutexPool<ItemBuilder>
@@ -46,30 +601,104 @@ where
     ItemBuilder::Item: Clone + Sync + Send + Debug,
     ItemBuilder::Error: Sync + Debug,
 {
-    pub fn new(item_builder: ItemBuilder, retry_build_config: Option<RetryConfig>) -> Self {
+    pub fn new(item_builder: ItemBuilder, config: PoolConfig) -> Self {
         MutexPool {
-            inner: Arc::new(Mutex::new(HashMap::new())),
-            item_builder,
-            retry_build_config,
+            inner: Shared::new(
+                EntriesLock::Mutex(Mutex::new(HashMap::new())),
+                item_builder,
+                config,
+            ),
         }
     }
 
+    /// Select an item of the token by the balance, the item is built first if more items are
+    /// needed. Hold the pooled item until the call finished, so the load is known. A failed
+    /// build fails all the callers waiting for it with the same error.
     pub async fn get(
         &self,
         token: &ItemBuilder::Token,
-    ) -> Result<ItemBuilder::Item, ItemBuilder::Error> {
-        if let Some(item) = self.inner.lock().await.get(token) {
-            return Ok(item.clone());
+    ) -> Result<Pooled<ItemBuilder::Item>, Arc<ItemBuilder::Error>> {
+        self.inner.get(token).await
+    }
+
+    /// Remove the items of token, they're built again when got next time. Call it when the
+    /// items are known broken, like the connection reset.
+    pub async fn invalidate(&self, token: &ItemBuilder::Token) -> bool {
+        self.inner.invalidate(token).await
+    }
+
+    /// Evict the items dead or idle, return how many evicted. Items are checked without
+    /// holding the lock, so gets are not blocked by slow checks.
+    pub async fn check(&self) -> usize {
+        self.inner.check().await
+    }
+
+    /// Items and counters of every token got.
+    pub async fn stats(&self) -> Vec<TokenStats<ItemBuilder::Token>> {
+        self.inner.stats().await
+    }
+
+    /// Check items every interval in the background, stopped when all the pool clones dropped.
+    pub fn spawn_checker(&self, interval: Duration) -> JoinHandle<()>
+    where
+        ItemBuilder: 'static,
+        ItemBuilder::Token: 'static,
+        ItemBuilder::Item: 'static,
+        ItemBuilder::Error: 'static,
+    {
+        spawn_checker(Arc::downgrade(&self.inner), interval)
+    }
+}
+
+impl<ItemBuilder> RwLockPool<ItemBuilder>
+where
+    ItemBuilder: PoolItemBuilder + Debug,
+    ItemBuilder::Token: Clone + Eq + Hash + Send + Debug,
+    ItemBuilder::Item: Clone + Sync + Send + Debug,
+    ItemBuilder::Error: Sync + Debug,
+{
+    pub fn new(item_builder: ItemBuilder, config: PoolConfig) -> Self {
+        RwLockPool {
+            inner: Shared::new(
+                EntriesLock::RwLock(RwLock::new(HashMap::new())),
+                item_builder,
+                config,
+            ),
         }
+    }
 
-        return match self.item_builder.build(token).await {
-            Ok(item) => {
-                let mut lock = self.inner.lock().await;
-                lock.insert(token.clone(), item.clone());
-                Ok(item)
-            }
-            Err(err) => Err(err),
-        };
+    /// See `MutexPool::get`.
+    pub async fn get(
+        &self,
+        token: &ItemBuilder::Token,
+    ) -> Result<Pooled<ItemBuilder::Item>, Arc<ItemBuilder::Error>> {
+        self.inner.get(token).await
+    }
+
+    /// See `MutexPool::invalidate`.
+    pub async fn invalidate(&self, token: &ItemBuilder::Token) -> bool {
+        self.inner.invalidate(token).await
+    }
+
+    /// See `MutexPool::check`.
+    pub async fn check(&self) -> usize {
+        self.inner.check().await
+    }
+
+    /// See `MutexPool::stats`.
+    pub async fn stats(&self) -> Vec<TokenStats<ItemBuilder::Token>> {
+        self.inner.stats().await
+    }
+
+    /// See `MutexPool::spawn_checker`.
+    pub fn spawn_checker(&self, interval: Duration) -> JoinHandle<()>
+    where
+        ItemBuilder: 'static,
+        ItemBuilder::Token: 'static,
+        ItemBuilder::Item: 'static,
+        ItemBuilder::Error: 'static,
+    {
+        spawn_checker(Arc::downgrade(&self.inner), interval)
     }
 }
 
@@ -85,3 +714,381 @@ pub struct RetryConfig {
     initial_retry_interval: Duration,
     retry_policy: RetryPolicy,
 }
+
+impl RetryConfig {
+    /// Retry times is ignored when retrying forever.
+    pub fn new(
+        retry_times: u32,
+        initial_retry_interval: Duration,
+        retry_policy: RetryPolicy,
+    ) -> RetryConfig {
+        RetryConfig {
+            retry_times,
+            initial_retry_interval,
+            retry_policy,
+        }
+    }
+
+    fn should_retry(&self, retries: u32) -> bool {
+        match self.retry_policy {
+            RetryPolicy::FixedRetry => retries < self.retry_times,
+            RetryPolicy::Forever => true,
+        }
+    }
+
+    fn interval(&self, retries: u32) -> Duration {
+        self.initial_retry_interval
+            .saturating_mul(2u32.saturating_pow(retries))
+            .min(MAX_RETRY_INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+
+    #[derive(Debug, Clone, Default)]
+    struct MockBuilder {
+        builds: Arc<AtomicUsize>,
+        // Builds failed before succeeded.
+        failures: usize,
+        alive: Arc<AtomicBool>,
+        delay: Duration,
+    }
+
+    impl MockBuilder {
+        fn new(failures: usize) -> MockBuilder {
+            MockBuilder {
+                failures,
+                alive: Arc::new(AtomicBool::new(true)),
+                ..Default::default()
+            }
+        }
+
+        fn builds(&self) -> usize {
+            self.builds.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl PoolItemBuilder for MockBuilder {
+        type Token = String;
+        type Item = usize;
+        type Error = String;
+
+        async fn build(&self, _token: &Self::Token) -> Result<Self::Item, Self::Error> {
+            tokio::time::sleep(self.delay).await;
+            let builds = self.builds.fetch_add(1, Ordering::SeqCst);
+            if builds < self.failures {
+                Err(format!("build {} failed", builds))
+            } else {
+                Ok(builds)
+            }
+        }
+
+        async fn check(&self, _token: &Self::Token, _item: &Self::Item) -> Result<bool, String> {
+            Ok(self.alive.load(Ordering::SeqCst))
+        }
+    }
+
+    fn retry(retry_times: u32) -> PoolConfig {
+        PoolConfig {
+            retry: Some(RetryConfig::new(
+                retry_times,
+                Duration::from_millis(1),
+                RetryPolicy::FixedRetry,
+            )),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_cached_item() {
+        let builder = MockBuilder::new(0);
+        let pool = MutexPool::new(builder.clone(), PoolConfig::default());
+        let token = "token".to_string();
+        assert_eq!(pool.get(&token).await.map(|item| *item), Ok(0));
+        assert_eq!(pool.get(&token).await.map(|item| *item), Ok(0));
+        assert_eq!(builder.builds(), 1);
+    }
+
+    async fn concurrent_gets<F>(get: impl Fn() -> F) -> Vec<Result<usize, Arc<String>>>
+    where
+        F: std::future::Future<Output = Result<usize, Arc<String>>> + Send + 'static,
+    {
+        let gets = (0..16).map(|_| tokio::spawn(get())).collect::<Vec<_>>();
+        let mut items = Vec::new();
+        for get in gets {
+            items.push(get.await.unwrap());
+        }
+        items
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_single_flight_build() {
+        let builder = MockBuilder {
+            delay: Duration::from_millis(20),
+            ..MockBuilder::new(0)
+        };
+        let pool = MutexPool::new(builder.clone(), PoolConfig::default());
+        let items = concurrent_gets(|| {
+            let pool = pool.clone();
+            async move { pool.get(&"token".to_string()).await.map(|item| *item) }
+        })
+        .await;
+        assert!(items.iter().all(|item| item == &Ok(0)));
+        assert_eq!(builder.builds(), 1);
+
+        let builder = MockBuilder {
+            delay: Duration::from_millis(20),
+            ..MockBuilder::new(0)
+        };
+        let pool = RwLockPool::new(builder.clone(), PoolConfig::default());
+        let items = concurrent_gets(|| {
+            let pool = pool.clone();
+            async move { pool.get(&"token".to_string()).await.map(|item| *item) }
+        })
+        .await;
+        assert!(items.iter().all(|item| item == &Ok(0)));
+        assert_eq!(builder.builds(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_failed_build_shared() {
+        let builder = MockBuilder {
+            delay: Duration::from_millis(20),
+            ..MockBuilder::new(usize::MAX)
+        };
+        let pool = MutexPool::new(builder.clone(), retry(2));
+        let items = concurrent_gets(|| {
+            let pool = pool.clone();
+            async move { pool.get(&"token".to_string()).await.map(|item| *item) }
+        })
+        .await;
+        // Waiters fail with the error of the single build, instead of building one by one.
+        let failed = Err(Arc::new("build 2 failed".to_string()));
+        assert!(items.iter().all(|item| item == &failed));
+        assert_eq!(builder.builds(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_failed_build_not_cached() {
+        let builder = MockBuilder::new(1);
+        let pool = RwLockPool::new(builder.clone(), PoolConfig::default());
+        let token = "token".to_string();
+        assert!(pool.get(&token).await.is_err());
+        assert_eq!(pool.get(&token).await.map(|item| *item), Ok(1));
+        assert_eq!(pool.get(&token).await.map(|item| *item), Ok(1));
+        assert_eq!(builder.builds(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_build() {
+        let builder = MockBuilder::new(2);
+        let pool = MutexPool::new(builder.clone(), retry(2));
+        assert_eq!(
+            pool.get(&"token".to_string()).await.map(|item| *item),
+            Ok(2)
+        );
+
+        let builder = MockBuilder::new(3);
+        let pool = MutexPool::new(builder.clone(), retry(2));
+        assert!(pool.get(&"token".to_string()).await.is_err());
+        assert_eq!(builder.builds(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_forever() {
+        let builder = MockBuilder::new(5);
+        let config = PoolConfig {
+            retry: Some(RetryConfig::new(
+                0,
+                Duration::from_millis(1),
+                RetryPolicy::Forever,
+            )),
+            ..Default::default()
+        };
+        let pool = MutexPool::new(builder.clone(), config);
+        assert_eq!(
+            pool.get(&"token".to_string()).await.map(|item| *item),
+            Ok(5)
+        );
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let retry = RetryConfig::new(3, Duration::from_secs(1), RetryPolicy::FixedRetry);
+        assert_eq!(retry.interval(0), Duration::from_secs(1));
+        assert_eq!(retry.interval(2), Duration::from_secs(4));
+        assert_eq!(retry.interval(10), MAX_RETRY_INTERVAL);
+    }
+
+    #[tokio::test]
+    async fn test_invalidate() {
+        let builder = MockBuilder::new(0);
+        let pool = MutexPool::new(builder.clone(), PoolConfig::default());
+        let token = "token".to_string();
+        pool.get(&token).await.unwrap();
+        assert!(pool.invalidate(&token).await);
+        assert!(!pool.invalidate(&token).await);
+        assert_eq!(pool.get(&token).await.map(|item| *item), Ok(1));
+    }
+
+    #[tokio::test]
+    async fn test_check_evict_dead_items() {
+        let builder = MockBuilder::new(0);
+        let pool = MutexPool::new(builder.clone(), PoolConfig::default());
+        let token = "token".to_string();
+        pool.get(&token).await.unwrap();
+        assert_eq!(pool.check().await, 0);
+        builder.alive.store(false, Ordering::SeqCst);
+        assert_eq!(pool.check().await, 1);
+        assert_eq!(pool.get(&token).await.map(|item| *item), Ok(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_check_evict_idle_items() {
+        let builder = MockBuilder::new(0);
+        let config = PoolConfig {
+            idle_timeout: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+        let pool = MutexPool::new(builder.clone(), config);
+        let idle = "idle".to_string();
+        let used = "used".to_string();
+        pool.get(&idle).await.unwrap();
+        pool.get(&used).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(15)).await;
+        pool.get(&used).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(pool.check().await, 1);
+        assert_eq!(pool.get(&used).await.map(|item| *item), Ok(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_check_skip_pending_build() {
+        let builder = MockBuilder {
+            delay: Duration::from_millis(50),
+            ..MockBuilder::new(0)
+        };
+        let config = PoolConfig {
+            idle_timeout: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+        let pool = MutexPool::new(builder.clone(), config);
+        let token = "token".to_string();
+        let get = tokio::spawn({
+            let pool = pool.clone();
+            let token = token.clone();
+            async move { pool.get(&token).await.map(|item| *item) }
+        });
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(pool.check().await, 0);
+        assert_eq!(get.await.unwrap(), Ok(0));
+        assert_eq!(pool.get(&token).await.map(|item| *item), Ok(0));
+        assert_eq!(builder.builds(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let builder = MockBuilder::new(2);
+        let pool = RwLockPool::new(builder.clone(), retry(2));
+        let token = "token".to_string();
+        let item = pool.get(&token).await.unwrap();
+        let stats = pool.stats().await;
+        assert_eq!(stats.len(), 1);
+        assert_eq!(
+            (stats[0].items, stats[0].in_flight, stats[0].builds),
+            (1, 1, 3)
+        );
+        assert_eq!((stats[0].build_failures, stats[0].retries), (2, 2));
+        assert!(stats[0].since_last_used.is_some());
+
+        drop(item);
+        pool.invalidate(&token).await;
+        let stats = pool.stats().await;
+        assert_eq!((stats[0].items, stats[0].evictions), (0, 1));
+        assert_eq!(stats[0].since_last_used, None);
+    }
+
+    fn items(min: usize, max: usize, balance: Balance) -> PoolConfig {
+        PoolConfig {
+            items: ItemsConfig {
+                min,
+                max,
+                balance,
+                scale_up_in_flight: 1,
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_min_items_round_robin() {
+        let builder = MockBuilder::new(0);
+        let pool = MutexPool::new(builder.clone(), items(2, 2, Balance::RoundRobin));
+        let token = "token".to_string();
+        let mut got = Vec::new();
+        for _ in 0..4 {
+            got.push(*pool.get(&token).await.unwrap());
+        }
+        assert_eq!(got, vec![0, 1, 1, 0]);
+        assert_eq!(builder.builds(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_scale_up_least_in_flight() {
+        let builder = MockBuilder::new(0);
+        let pool = RwLockPool::new(builder.clone(), items(1, 2, Balance::LeastInFlight));
+        let token = "token".to_string();
+        let first = pool.get(&token).await.unwrap();
+        let second = pool.get(&token).await.unwrap();
+        let third = pool.get(&token).await.unwrap();
+        assert_eq!((*first, *second, *third), (0, 1, 0));
+        assert_eq!(builder.builds(), 2);
+
+        drop(first);
+        drop(second);
+        assert_eq!(*pool.get(&token).await.unwrap(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_scale_down_idle_items() {
+        let builder = MockBuilder::new(0);
+        let config = PoolConfig {
+            idle_timeout: Some(Duration::from_millis(20)),
+            ..items(1, 2, Balance::LeastInFlight)
+        };
+        let pool = MutexPool::new(builder.clone(), config);
+        let token = "token".to_string();
+        let first = pool.get(&token).await.unwrap();
+        let second = pool.get(&token).await.unwrap();
+        drop((first, second));
+        tokio::time::sleep(Duration::from_millis(15)).await;
+        assert_eq!(pool.get(&token).await.map(|item| *item), Ok(0));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(pool.check().await, 1);
+        assert_eq!(pool.get(&token).await.map(|item| *item), Ok(0));
+        assert_eq!(builder.builds(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_checker_stopped_when_pool_dropped() {
+        let builder = MockBuilder::new(0);
+        let pool = MutexPool::new(builder.clone(), PoolConfig::default());
+        pool.get(&"token".to_string()).await.unwrap();
+        builder.alive.store(false, Ordering::SeqCst);
+        let checker = pool.spawn_checker(Duration::from_millis(5));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(
+            pool.get(&"token".to_string()).await.map(|item| *item),
+            Ok(1)
+        );
+        drop(pool);
+        tokio::time::timeout(Duration::from_secs(1), checker)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
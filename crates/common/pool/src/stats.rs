@@ -0,0 +1,72 @@
+// Counters by token, kept when the items evicted so flapping items are visible.
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Items held by a token, and the counters since the pool created.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenStats<Token> {
+    pub token: Token,
+    /// Items built or in building.
+    pub items: usize,
+    /// Calls holding the items.
+    pub in_flight: usize,
+    /// Build attempts, with the retries.
+    pub builds: u64,
+    pub build_failures: u64,
+    pub retries: u64,
+    /// Items evicted by checks or invalidated.
+    pub evictions: u64,
+    /// None if the token holds no items.
+    pub since_last_used: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Counters {
+    pub builds: u64,
+    pub build_failures: u64,
+    pub retries: u64,
+    pub evictions: u64,
+}
+
+impl<Token> TokenStats<Token> {
+    pub(crate) fn new(token: Token, counters: Counters) -> TokenStats<Token> {
+        TokenStats {
+            token,
+            items: 0,
+            in_flight: 0,
+            builds: counters.builds,
+            build_failures: counters.build_failures,
+            retries: counters.retries,
+            evictions: counters.evictions,
+            since_last_used: None,
+        }
+    }
+}
+
+// Never locked across awaits.
+#[derive(Debug)]
+pub(crate) struct Stats<Token> {
+    counters: Mutex<HashMap<Token, Counters>>,
+}
+
+impl<Token: Clone + Eq + Hash> Stats<Token> {
+    pub fn new() -> Stats<Token> {
+        Stats {
+            counters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, token: &Token, f: impl FnOnce(&mut Counters)) {
+        let mut counters = self.counters.lock().unwrap();
+        match counters.get_mut(token) {
+            Some(counters) => f(counters),
+            None => f(counters.entry(token.clone()).or_default()),
+        }
+    }
+
+    pub fn snapshot(&self) -> HashMap<Token, Counters> {
+        self.counters.lock().unwrap().clone()
+    }
+}
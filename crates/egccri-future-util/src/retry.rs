@@ -0,0 +1,223 @@
+// A future can't be awaited again after it failed, even a shared one returns the same output.
+// So the attempts are made by a future factory, which is called again for every retry.
+use rand::Rng;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Delay before every retry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backoff {
+    Fixed(Duration),
+    /// Doubled after every retry from the initial up to the max. With the jitter, a random
+    /// delay between the half and the whole is taken, so callers failed together are spread.
+    Exponential {
+        initial: Duration,
+        max: Duration,
+        jitter: bool,
+    },
+}
+
+impl Backoff {
+    /// Delay before the retry, retries are counted from 0.
+    pub fn delay(&self, retries: u32) -> Duration {
+        match *self {
+            Backoff::Fixed(interval) => interval,
+            Backoff::Exponential {
+                initial,
+                max,
+                jitter,
+            } => {
+                let delay = initial
+                    .saturating_mul(2u32.saturating_pow(retries))
+                    .min(max);
+                if !jitter {
+                    return delay;
+                }
+                let half = delay / 2;
+                half + Duration::from_nanos(
+                    rand::thread_rng().gen_range(0..=(delay - half).as_nanos() as u64),
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub backoff: Backoff,
+    /// Retried forever if none.
+    pub max_retries: Option<u32>,
+    /// Not retried if the next attempt would start later than it since the first attempt.
+    pub max_elapsed: Option<Duration>,
+}
+
+impl RetryPolicy {
+    pub const fn fixed(interval: Duration, max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            backoff: Backoff::Fixed(interval),
+            max_retries: Some(max_retries),
+            max_elapsed: None,
+        }
+    }
+
+    /// Exponential backoff with the jitter.
+    pub const fn exponential(initial: Duration, max: Duration, max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            backoff: Backoff::Exponential {
+                initial,
+                max,
+                jitter: true,
+            },
+            max_retries: Some(max_retries),
+            max_elapsed: None,
+        }
+    }
+
+    // Delay before the retry, none if it should not be retried.
+    fn next_delay(&self, retries: u32, elapsed: Duration) -> Option<Duration> {
+        if self
+            .max_retries
+            .is_some_and(|max_retries| retries >= max_retries)
+        {
+            return None;
+        }
+        let delay = self.backoff.delay(retries);
+        match self.max_elapsed {
+            Some(max_elapsed) if elapsed + delay > max_elapsed => None,
+            _ => Some(delay),
+        }
+    }
+}
+
+/// Retry every error by the policy, see `retry_if`.
+pub async fn retry<T, E, Fut>(policy: &RetryPolicy, make: impl FnMut() -> Fut) -> Result<T, E>
+where
+    Fut: Future<Output = Result<T, E>>,
+{
+    retry_if(policy, make, |_| true).await
+}
+
+/// Await the futures made by `make` until one is ok, the last error is returned when the
+/// error is not retried by `retry_if` or the policy gives up.
+///
+/// ```
+/// use egccri_future_util::{retry_if, RetryPolicy};
+/// use std::time::Duration;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let mut attempts = 0;
+/// let policy = RetryPolicy::exponential(Duration::from_millis(1), Duration::from_millis(10), 5);
+/// let result = retry_if(
+///     &policy,
+///     || {
+///         attempts += 1;
+///         let attempt = attempts;
+///         async move {
+///             match attempt {
+///                 1 | 2 => Err("unavailable"),
+///                 _ => Ok(attempt),
+///             }
+///         }
+///     },
+///     |err| *err == "unavailable",
+/// )
+/// .await;
+/// assert_eq!(result, Ok(3));
+/// # }
+/// ```
+pub async fn retry_if<T, E, Fut>(
+    policy: &RetryPolicy,
+    mut make: impl FnMut() -> Fut,
+    mut retry_if: impl FnMut(&E) -> bool,
+) -> Result<T, E>
+where
+    Fut: Future<Output = Result<T, E>>,
+{
+    let start = Instant::now();
+    let mut retries = 0;
+    loop {
+        let err = match make().await {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+        if !retry_if(&err) {
+            return Err(err);
+        }
+        let Some(delay) = policy.next_delay(retries, start.elapsed()) else {
+            return Err(err);
+        };
+        tokio::time::sleep(delay).await;
+        retries += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    // Fails the attempts before the succeeded one.
+    async fn attempt(attempts: &Cell<u32>, succeeded: u32) -> Result<u32, u32> {
+        attempts.set(attempts.get() + 1);
+        if attempts.get() < succeeded {
+            Err(attempts.get())
+        } else {
+            Ok(attempts.get())
+        }
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let backoff = Backoff::Exponential {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+            jitter: false,
+        };
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(2), Duration::from_millis(400));
+        assert_eq!(backoff.delay(10), Duration::from_secs(1));
+
+        let backoff = Backoff::Exponential {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+            jitter: true,
+        };
+        for _ in 0..100 {
+            let delay = backoff.delay(2);
+            assert!(delay >= Duration::from_millis(200) && delay <= Duration::from_millis(400));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_times() {
+        let attempts = Cell::new(0);
+        let policy = RetryPolicy::fixed(Duration::from_millis(10), 2);
+        assert_eq!(retry(&policy, || attempt(&attempts, 3)).await, Ok(3));
+
+        let attempts = Cell::new(0);
+        assert_eq!(retry(&policy, || attempt(&attempts, 4)).await, Err(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_if() {
+        let attempts = Cell::new(0);
+        let policy = RetryPolicy::fixed(Duration::from_millis(10), 5);
+        let result = retry_if(&policy, || attempt(&attempts, 5), |err| *err < 2).await;
+        assert_eq!(result, Err(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_max_elapsed() {
+        let attempts = Cell::new(0);
+        let policy = RetryPolicy {
+            max_retries: None,
+            max_elapsed: Some(Duration::from_millis(35)),
+            ..RetryPolicy::fixed(Duration::from_millis(10), 0)
+        };
+        let start = Instant::now();
+        assert_eq!(retry(&policy, || attempt(&attempts, 10)).await, Err(4));
+        assert_eq!(start.elapsed(), Duration::from_millis(30));
+    }
+}
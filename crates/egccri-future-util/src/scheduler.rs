@@ -0,0 +1,138 @@
+// Timers of many keys in one timer wheel, instead of a sleep per key.
+use std::collections::HashMap;
+use std::future::poll_fn;
+use std::hash::Hash;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio_util::time::{delay_queue, DelayQueue};
+
+/// Values scheduled by key, the expired are taken in order of the deadlines. Inserting,
+/// resetting and cancelling are O(1), so it holds hundreds of thousands of timers.
+#[derive(Debug)]
+pub struct Scheduler<K, V> {
+    queue: DelayQueue<K>,
+    scheduled: HashMap<K, (delay_queue::Key, V)>,
+}
+
+impl<K, V> Default for Scheduler<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    fn default() -> Self {
+        Scheduler::new()
+    }
+}
+
+impl<K, V> Scheduler<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    pub fn new() -> Scheduler<K, V> {
+        Scheduler {
+            queue: DelayQueue::new(),
+            scheduled: HashMap::new(),
+        }
+    }
+
+    /// Schedule the value expired after the timeout, the value scheduled with the same key
+    /// is replaced and returned.
+    pub fn insert(&mut self, key: K, value: V, timeout: Duration) -> Option<V> {
+        match self.scheduled.get_mut(&key) {
+            Some((queue_key, scheduled)) => {
+                self.queue.reset(queue_key, timeout);
+                Some(std::mem::replace(scheduled, value))
+            }
+            None => {
+                let queue_key = self.queue.insert(key.clone(), timeout);
+                self.scheduled.insert(key, (queue_key, value));
+                None
+            }
+        }
+    }
+
+    /// Expire the key after the timeout from now, false if it's not scheduled.
+    pub fn reset(&mut self, key: &K, timeout: Duration) -> bool {
+        match self.scheduled.get(key) {
+            Some((queue_key, _)) => {
+                self.queue.reset(queue_key, timeout);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn cancel(&mut self, key: &K) -> Option<V> {
+        let (queue_key, value) = self.scheduled.remove(key)?;
+        self.queue.remove(&queue_key);
+        Some(value)
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.scheduled.get(key).map(|(_, value)| value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.scheduled.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scheduled.is_empty()
+    }
+
+    /// None if nothing is scheduled, it's not waked by the values inserted later.
+    pub fn poll_expired(&mut self, cx: &mut Context<'_>) -> Poll<Option<(K, V)>> {
+        match self.queue.poll_expired(cx) {
+            Poll::Ready(Some(expired)) => {
+                let key = expired.into_inner();
+                let (_, value) = self
+                    .scheduled
+                    .remove(&key)
+                    .expect("expired key is scheduled");
+                Poll::Ready(Some((key, value)))
+            }
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    /// Wait for the next expired, see `poll_expired`.
+    pub async fn expired(&mut self) -> Option<(K, V)> {
+        poll_fn(|cx| self.poll_expired(cx)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Instant;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expired_in_order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.insert("b", 2, Duration::from_millis(20));
+        scheduler.insert("a", 1, Duration::from_millis(10));
+        scheduler.insert("c", 3, Duration::from_millis(30));
+        assert_eq!(scheduler.cancel(&"c"), Some(3));
+
+        let start = Instant::now();
+        assert_eq!(scheduler.expired().await, Some(("a", 1)));
+        assert_eq!(scheduler.expired().await, Some(("b", 2)));
+        assert_eq!(start.elapsed(), Duration::from_millis(20));
+        assert_eq!(scheduler.expired().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reset_and_replace() {
+        let mut scheduler = Scheduler::new();
+        scheduler.insert("a", 1, Duration::from_millis(10));
+        scheduler.insert("b", 2, Duration::from_millis(20));
+        assert!(scheduler.reset(&"a", Duration::from_millis(30)));
+        assert!(!scheduler.reset(&"c", Duration::from_millis(30)));
+        assert_eq!(scheduler.insert("b", 3, Duration::from_millis(5)), Some(2));
+        assert_eq!(scheduler.len(), 2);
+
+        assert_eq!(scheduler.expired().await, Some(("b", 3)));
+        assert_eq!(scheduler.expired().await, Some(("a", 1)));
+        assert!(scheduler.is_empty());
+    }
+}
@@ -0,0 +1,27 @@
+use std::future::Future;
+use std::time::Duration;
+
+/// Await the future in the duration, or return the error made by `on_timeout`. It's
+/// `tokio::time::timeout` without the nested result.
+///
+/// ```
+/// use egccri_future_util::timeout_with;
+/// use std::time::Duration;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let pending = std::future::pending::<Result<(), String>>();
+/// let result = timeout_with(Duration::from_millis(10), pending, || "timeout".to_string()).await;
+/// assert_eq!(result, Err("timeout".to_string()));
+/// # }
+/// ```
+pub async fn timeout_with<T, E>(
+    duration: Duration,
+    future: impl Future<Output = Result<T, E>>,
+    on_timeout: impl FnOnce() -> E,
+) -> Result<T, E> {
+    match tokio::time::timeout(duration, future).await {
+        Ok(result) => result,
+        Err(_) => Err(on_timeout()),
+    }
+}
@@ -0,0 +1,202 @@
+// Timers shared by tasks, like a deadline of every connection, run by one background task.
+use crate::scheduler::Scheduler;
+use std::future::Future;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::{mpsc, oneshot};
+
+// Distinguish the timers inserted with the same key.
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+enum Command<K> {
+    Insert {
+        key: K,
+        id: u64,
+        timeout: Duration,
+        fire: oneshot::Sender<()>,
+    },
+    Reset {
+        key: K,
+        timeout: Duration,
+    },
+    // Cancel the timer of the id only, if any.
+    Cancel {
+        key: K,
+        id: Option<u64>,
+    },
+}
+
+/// Handle of the timers, clones share the same timers. The background task is stopped when
+/// all handles and timers dropped.
+#[derive(Debug)]
+pub struct Timers<K> {
+    commands: mpsc::UnboundedSender<Command<K>>,
+}
+
+impl<K> Clone for Timers<K> {
+    fn clone(&self) -> Self {
+        Timers {
+            commands: self.commands.clone(),
+        }
+    }
+}
+
+impl<K> Timers<K>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+{
+    pub fn spawn() -> Timers<K> {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run(receiver));
+        Timers { commands }
+    }
+
+    /// The timer completes when the timeout elapsed, it's cancelled when dropped. The timer
+    /// inserted with the same key is replaced, it never completes.
+    pub fn insert(&self, key: K, timeout: Duration) -> Timer<K> {
+        let id = NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed);
+        let (fire, fired) = oneshot::channel();
+        let _ = self.commands.send(Command::Insert {
+            key: key.clone(),
+            id,
+            timeout,
+            fire,
+        });
+        Timer {
+            key: Some(key),
+            id,
+            timers: self.clone(),
+            fired: Some(fired),
+        }
+    }
+
+    /// Delay the timer of the key to the timeout from now.
+    pub fn reset(&self, key: K, timeout: Duration) {
+        let _ = self.commands.send(Command::Reset { key, timeout });
+    }
+
+    pub fn cancel(&self, key: K) {
+        let _ = self.commands.send(Command::Cancel { key, id: None });
+    }
+}
+
+/// A future completes when the timer expired.
+#[derive(Debug)]
+pub struct Timer<K> {
+    // Taken when the timer fired or dropped.
+    key: Option<K>,
+    id: u64,
+    timers: Timers<K>,
+    // Taken when it's received.
+    fired: Option<oneshot::Receiver<()>>,
+}
+
+impl<K> Timer<K>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+{
+    /// Delay the timer to the timeout from now.
+    pub fn reset(&self, timeout: Duration) {
+        if let Some(key) = &self.key {
+            self.timers.reset(key.clone(), timeout);
+        }
+    }
+}
+
+impl<K: Unpin> Future for Timer<K> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let Some(fired) = &mut self.fired else {
+            return Poll::Pending;
+        };
+        let result = match Pin::new(fired).poll(cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => return Poll::Pending,
+        };
+        self.key = None;
+        self.fired = None;
+        match result {
+            Ok(()) => Poll::Ready(()),
+            // Replaced or the timers stopped, never fired.
+            Err(_) => Poll::Pending,
+        }
+    }
+}
+
+impl<K> Drop for Timer<K> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            let _ = self.timers.commands.send(Command::Cancel {
+                key,
+                id: Some(self.id),
+            });
+        }
+    }
+}
+
+async fn run<K>(mut commands: mpsc::UnboundedReceiver<Command<K>>)
+where
+    K: Hash + Eq + Clone,
+{
+    let mut scheduler = Scheduler::new();
+    loop {
+        select! {
+            command = commands.recv() => match command {
+                Some(Command::Insert { key, id, timeout, fire }) => {
+                    scheduler.insert(key, (id, fire), timeout);
+                }
+                Some(Command::Reset { key, timeout }) => {
+                    scheduler.reset(&key, timeout);
+                }
+                Some(Command::Cancel { key, id }) => {
+                    let scheduled = scheduler.get(&key).map(|(scheduled, _)| *scheduled);
+                    if scheduled.is_some() && (id.is_none() || id == scheduled) {
+                        scheduler.cancel(&key);
+                    }
+                }
+                None => return,
+            },
+            Some((_, (_, fire))) = scheduler.expired(), if !scheduler.is_empty() => {
+                let _ = fire.send(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Instant;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timer_reset() {
+        let timers = Timers::spawn();
+        let start = Instant::now();
+        let timer = timers.insert("a", Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        timer.reset(Duration::from_millis(10));
+        timer.await;
+        assert_eq!(start.elapsed(), Duration::from_millis(15));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timer_replaced_and_cancelled() {
+        let timers = Timers::spawn();
+        let replaced = timers.insert("a", Duration::from_millis(10));
+        let timer = timers.insert("a", Duration::from_millis(20));
+        // Dropping the replaced one not cancels the new one.
+        drop(replaced);
+        let cancelled = timers.insert("b", Duration::from_millis(5));
+        timers.cancel("b");
+        select! {
+            _ = timer => {}
+            _ = cancelled => panic!("cancelled timer fired"),
+        }
+    }
+}
@@ -1,6 +1,19 @@
 use crate::config::ServerConfig;
+use crate::northbound::NorthboundServer;
+use crate::server::SERVER_RESTART;
+use ::config::{Config, Environment, File};
 use std::path::PathBuf;
-use tokio::sync::{broadcast, mpsc};
+use std::time::Duration;
+use tokio::select;
+use tokio::signal::ctrl_c;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::broadcast;
+use tracing::{error, info, warn};
+
+// Environment variables like IOT__ROUTER__ROUTER_ID override the config file.
+const ENV_PREFIX: &str = "IOT";
+const ENV_SEPARATOR: &str = "__";
 
 /// Cli commands
 #[derive(clap::Parser, Debug)]
@@ -17,30 +30,164 @@ pub struct Cli {
     /// Server name
     #[arg(short = 'n', long = "server-name")]
     server_name: Option<String>,
+
+    /// Override a config key, like "router.router_id=2" or "listeners[0].bind_address=0.0.0.0:9990",
+    /// over the config file and IOT__ environment variables
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    overrides: Vec<(String, String)>,
+
+    /// Print the merged config with secrets redacted, then exit
+    #[arg(long = "print-config")]
+    print_config: bool,
+}
+
+fn parse_key_value(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!("expect KEY=VALUE, but {:?}", arg)),
+    }
 }
 
 impl Cli {
     pub async fn execute(self, server_config: ServerConfig) -> anyhow::Result<()> {
         // terminal -> server
         let (ctrl_c_tx, ctrl_c_rx) = broadcast::channel(5);
-        // server -> router
-        let (server_sender, server_receiver) = mpsc::channel(1000);
 
+        let northbound_server = server_config
+            .northbound
+            .as_ref()
+            .map(NorthboundServer::new)
+            .transpose()?;
         let server_config_clone = server_config.clone();
-        let router_client =
-            crate::server::start(server_config_clone, server_sender, ctrl_c_rx).await?;
+        let (router_client, mut supervisor) =
+            crate::server::start(server_config_clone, ctrl_c_rx).await?;
+
+        // Northbound api for applications send to devices and subscribe packets from devices.
+        if let Some(northbound_server) = northbound_server {
+            let shutdown_tx = ctrl_c_tx.clone();
+            supervisor.spawn_restartable("northbound server", SERVER_RESTART, move || {
+                let northbound_server = northbound_server.clone();
+                let router_client = router_client.clone();
+                let shutdown_rx = shutdown_tx.subscribe();
+                async move { northbound_server.start(router_client, shutdown_rx).await }
+            });
+        }
 
-        // FIXME return a channel or else other method to send rpc call or pub/sub call
-        // FIXME use router_client by yourself
+        // A fatal error of any server task stops the process.
+        let server_task = supervisor.wait();
+        tokio::pin!(server_task);
+        select! {
+            _ = Self::shutdown_signal() => {
+                info!("Shutdown signal received, stopping server");
+                let _ = ctrl_c_tx.send(());
+            }
+            stopped = &mut server_task => return Ok(stopped?),
+        }
+        let timeout = Duration::from_millis(server_config.shutdown.timeout);
+        match tokio::time::timeout(timeout, server_task).await {
+            Ok(stopped) => Ok(stopped?),
+            Err(_) => {
+                warn!("Server not stopped in {:?}, exit anyway", timeout);
+                Ok(())
+            }
+        }
+    }
 
-        Ok(())
+    // Completed on SIGINT, or SIGTERM on unix.
+    async fn shutdown_signal() {
+        #[cfg(unix)]
+        {
+            let mut terminate = match signal(SignalKind::terminate()) {
+                Ok(terminate) => terminate,
+                Err(err) => {
+                    error!("Listen SIGTERM cause a error: {}", err);
+                    let _ = ctrl_c().await;
+                    return;
+                }
+            };
+            select! {
+                _ = ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = ctrl_c().await;
     }
 
-    pub fn config_file(&self) -> PathBuf {
-        self.config_file.clone()
+    /// Merge the config file, environment variables, then the command line overrides. The
+    /// config is not validated yet.
+    pub fn load_config(&self) -> anyhow::Result<ServerConfig> {
+        self.load_config_with(
+            Environment::with_prefix(ENV_PREFIX)
+                .prefix_separator(ENV_SEPARATOR)
+                .separator(ENV_SEPARATOR)
+                .try_parsing(true),
+        )
+    }
+
+    fn load_config_with(&self, environment: Environment) -> anyhow::Result<ServerConfig> {
+        let mut builder = Config::builder()
+            .add_source(File::from(self.config_file.as_path()))
+            .add_source(environment)
+            .set_override_option("server_name", self.server_name.clone())?;
+        for (key, value) in &self.overrides {
+            builder = builder.set_override(key, value.as_str())?;
+        }
+        ServerConfig::new(builder.build()?)
+    }
+
+    pub fn print_config(&self) -> bool {
+        self.print_config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_overrides_in_order() {
+        let cli = Cli::try_parse_from([
+            "iot-server",
+            "--set",
+            "router.router_id=3",
+            "--set",
+            "listeners[0].bind_address=0.0.0.0:9980",
+        ])
+        .unwrap();
+        let variables = HashMap::from([
+            ("IOT__ROUTER__ROUTER_ID".to_string(), "2".to_string()),
+            ("IOT__RAFT__NODE_ID".to_string(), "2".to_string()),
+            (
+                "IOT__SHUTDOWN__NOTIFY_DEVICES".to_string(),
+                "false".to_string(),
+            ),
+        ]);
+        let environment = Environment::with_prefix(ENV_PREFIX)
+            .prefix_separator(ENV_SEPARATOR)
+            .separator(ENV_SEPARATOR)
+            .try_parsing(true)
+            .source(Some(variables));
+
+        let config = cli.load_config_with(environment).unwrap();
+        assert_eq!(config.router.router_id, 3);
+        assert_eq!(config.raft.unwrap().node_id, 2);
+        assert!(!config.shutdown.notify_devices);
+        assert_eq!(config.listeners[0].bind_address, "0.0.0.0:9980");
+        assert_eq!(config.router.router_server_addr, "0.0.0.0:50000");
     }
 
-    pub fn server_name(&self) -> Option<String> {
-        self.server_name.clone()
+    #[test]
+    fn test_parse_key_value() {
+        assert_eq!(
+            parse_key_value("redis=redis://host?a=b"),
+            Ok(("redis".to_string(), "redis://host?a=b".to_string()))
+        );
+        assert!(parse_key_value("router_id").is_err());
+        assert!(parse_key_value("=1").is_err());
     }
 }
@@ -0,0 +1,595 @@
+// Check the whole config before anything is started, so a bad address is not found by a task
+// spawned later. All problems are collected and reported at once.
+use crate::config::{NorthboundConfig, RateLimitConfig, ServerConfig, TlsConfig};
+use crate::server::tls::ClientAuth;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::path::Path;
+use tonic::metadata::{Ascii, MetadataValue};
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    #[error("{field}: {address:?} is not a socket address like \"0.0.0.0:9990\", {cause}")]
+    InvalidAddress {
+        field: String,
+        address: String,
+        cause: String,
+    },
+
+    #[error("{field}: {address} is also bound by {other}")]
+    AddressInUse {
+        field: String,
+        address: SocketAddr,
+        other: String,
+    },
+
+    #[error("{field}: {value:?} is not unique")]
+    Duplicated { field: String, value: String },
+
+    #[error("{field}: {reason}")]
+    Invalid { field: String, reason: String },
+
+    #[error("{field}: file {path:?} is not found")]
+    FileNotFound { field: String, path: String },
+
+    #[error("[{section}] is required {reason}")]
+    MissingSection {
+        section: &'static str,
+        reason: &'static str,
+    },
+
+    #[error("[{section}] is configured but {reason}")]
+    UnusedSection {
+        section: &'static str,
+        reason: &'static str,
+    },
+}
+
+/// All problems found in the config.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl Display for ConfigErrors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid config, {} problems found:", self.0.len())?;
+        for err in &self.0 {
+            write!(f, "\n  - {}", err)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+impl ServerConfig {
+    pub fn validate(&self) -> Result<(), ConfigErrors> {
+        let mut validator = Validator::default();
+        validator.listeners(self);
+        if let Some(limits) = &self.limits {
+            for (field, limit) in [
+                ("limits.sign_in_rate", &limits.sign_in_rate),
+                ("limits.inbound_packet_rate", &limits.inbound_packet_rate),
+                ("limits.inbound_byte_rate", &limits.inbound_byte_rate),
+            ] {
+                if let Some(limit) = limit {
+                    validator.rate_limit(field, limit);
+                }
+            }
+            if let Some(max_connections) = limits.max_connections {
+                validator.positive("limits.max_connections", max_connections as u64);
+            }
+            if let Some(max_connections_per_ip) = limits.max_connections_per_ip {
+                validator.positive(
+                    "limits.max_connections_per_ip",
+                    max_connections_per_ip as u64,
+                );
+            }
+        }
+
+        validator.address("router.router_server_addr", &self.router.router_server_addr);
+        if let Some(northbound) = &self.northbound {
+            validator.northbound(northbound);
+        }
+        if let Some(offline_queue) = &self.offline_queue {
+            validator.positive("offline_queue.ttl", offline_queue.ttl);
+            validator.positive("offline_queue.max_depth", offline_queue.max_depth as u64);
+        }
+        validator.positive("delivery.ack_timeout", self.delivery.ack_timeout);
+        validator.positive(
+            "delivery.in_flight_window",
+            self.delivery.in_flight_window as u64,
+        );
+        validator.positive("outbound.capacity", self.outbound.capacity as u64);
+        if self.shutdown.drain_timeout > self.shutdown.timeout {
+            validator.invalid(
+                "shutdown.drain_timeout",
+                format!(
+                    "{} is larger than shutdown.timeout {}, the server exits before drained",
+                    self.shutdown.drain_timeout, self.shutdown.timeout
+                ),
+            );
+        }
+
+        validator.raft(self);
+        validator.cluster(self);
+        if self.redis.is_some() {
+            // Channel routes, routers, groups and offline packets are kept by the raft storage.
+            let reason = if cfg!(feature = "redis") {
+                "the redis router storage is not supported yet"
+            } else {
+                "the redis feature is not enabled"
+            };
+            validator.push(ConfigError::UnusedSection {
+                section: "redis",
+                reason,
+            });
+        }
+        validator.finish()
+    }
+}
+
+#[derive(Default)]
+struct Validator {
+    errors: Vec<ConfigError>,
+    // Addresses bound by this server, with the field binding it.
+    bound: Vec<(String, SocketAddr)>,
+}
+
+impl Validator {
+    fn push(&mut self, err: ConfigError) {
+        self.errors.push(err);
+    }
+
+    fn invalid(&mut self, field: &str, reason: String) {
+        self.push(ConfigError::Invalid {
+            field: field.to_string(),
+            reason,
+        });
+    }
+
+    fn positive(&mut self, field: &str, value: u64) {
+        if value == 0 {
+            self.invalid(field, "must be larger than 0".to_string());
+        }
+    }
+
+    fn file(&mut self, field: &str, path: &str) {
+        if !Path::new(path).exists() {
+            self.push(ConfigError::FileNotFound {
+                field: field.to_string(),
+                path: path.to_string(),
+            });
+        }
+    }
+
+    // Parse the address to bind, it must not be bound by other servers of this node.
+    fn address(&mut self, field: &str, address: &str) {
+        let socket_addr = match address.parse::<SocketAddr>() {
+            Ok(socket_addr) => socket_addr,
+            Err(err) => {
+                self.push(ConfigError::InvalidAddress {
+                    field: field.to_string(),
+                    address: address.to_string(),
+                    cause: err.to_string(),
+                });
+                return;
+            }
+        };
+        let other = self
+            .bound
+            .iter()
+            .find(|(_, bound)| conflicts(bound, &socket_addr))
+            .map(|(other, _)| other.clone());
+        match other {
+            Some(other) => self.push(ConfigError::AddressInUse {
+                field: field.to_string(),
+                address: socket_addr,
+                other,
+            }),
+            None => self.bound.push((field.to_string(), socket_addr)),
+        }
+    }
+
+    fn listeners(&mut self, config: &ServerConfig) {
+        // Taken as the default listener when no listeners configured.
+        if config.bind_address.is_some() && !config.listeners.is_empty() {
+            self.invalid(
+                "bind_address",
+                "is replaced by [[listeners]], remove it".to_string(),
+            );
+        }
+        if config.listeners.is_empty() {
+            self.push(ConfigError::MissingSection {
+                section: "[listeners]",
+                reason: "for devices to connect",
+            });
+        }
+        let byte_burst = config
+            .limits
+            .as_ref()
+            .and_then(|limits| limits.inbound_byte_rate.as_ref())
+            .map(|limit| limit.burst as usize);
+        for (i, listener) in config.listeners.iter().enumerate() {
+            let field = format!("listeners[{}]", i);
+            if config.listeners[..i]
+                .iter()
+                .any(|other| other.name == listener.name)
+            {
+                self.push(ConfigError::Duplicated {
+                    field: format!("{}.name", field),
+                    value: listener.name.clone(),
+                });
+            }
+            self.address(&format!("{}.bind_address", field), &listener.bind_address);
+            if let Some(max_frame_length) = listener.max_frame_length {
+                self.positive(
+                    &format!("{}.max_frame_length", field),
+                    max_frame_length as u64,
+                );
+                // Frames larger than the burst never pass the byte rate limit.
+                if let Some(burst) = byte_burst.filter(|burst| *burst < max_frame_length) {
+                    self.invalid(
+                        "limits.inbound_byte_rate.burst",
+                        format!(
+                            "{} is less than the max_frame_length {} of listener {:?}",
+                            burst, max_frame_length, listener.name
+                        ),
+                    );
+                }
+            }
+            if let Some(max_connections) = listener.max_connections {
+                self.positive(
+                    &format!("{}.max_connections", field),
+                    max_connections as u64,
+                );
+            }
+            if let Some(tls) = &listener.tls {
+                self.tls(&format!("{}.tls", field), tls);
+            }
+        }
+    }
+
+    fn tls(&mut self, field: &str, tls: &TlsConfig) {
+        self.file(&format!("{}.cert_path", field), &tls.cert_path);
+        self.file(&format!("{}.key_path", field), &tls.key_path);
+        match &tls.client_ca_path {
+            Some(client_ca_path) => self.file(&format!("{}.client_ca_path", field), client_ca_path),
+            None if tls.client_auth != ClientAuth::Off => self.invalid(
+                &format!("{}.client_ca_path", field),
+                "is required when client_auth is not off".to_string(),
+            ),
+            None => {}
+        }
+        if tls.bind_client_id && tls.client_auth == ClientAuth::Off {
+            self.invalid(
+                &format!("{}.bind_client_id", field),
+                "devices present no certificate when client_auth is off, all sign ins would \
+                fail"
+                    .to_string(),
+            );
+        }
+    }
+
+    fn rate_limit(&mut self, field: &str, limit: &RateLimitConfig) {
+        self.positive(&format!("{}.rate", field), limit.rate as u64);
+        self.positive(&format!("{}.burst", field), limit.burst as u64);
+    }
+
+    fn raft(&mut self, config: &ServerConfig) {
+        let Some(raft) = &config.raft else {
+            if cfg!(feature = "raft-store") {
+                self.push(ConfigError::MissingSection {
+                    section: "raft",
+                    reason: "by the raft-store feature",
+                });
+            }
+            return;
+        };
+        if !cfg!(feature = "raft-store") {
+            self.push(ConfigError::UnusedSection {
+                section: "raft",
+                reason: "the raft-store feature is not enabled",
+            });
+        }
+        self.address("raft.raft_network_addr", &raft.raft_network_addr);
+        self.positive("raft.heartbeat_interval", raft.heartbeat_interval as u64);
+        // Followers must receive heartbeats before they start an election.
+        if raft.election_timeout_min <= raft.heartbeat_interval {
+            self.invalid(
+                "raft.election_timeout_min",
+                format!(
+                    "{} must be larger than raft.heartbeat_interval {}",
+                    raft.election_timeout_min, raft.heartbeat_interval
+                ),
+            );
+        }
+        if raft.election_timeout_max <= raft.election_timeout_min {
+            self.invalid(
+                "raft.election_timeout_max",
+                format!(
+                    "{} must be larger than raft.election_timeout_min {}",
+                    raft.election_timeout_max, raft.election_timeout_min
+                ),
+            );
+        }
+        for (i, member) in raft.members.iter().enumerate() {
+            let field = format!("raft.members[{}]", i);
+            if raft.members[..i]
+                .iter()
+                .any(|other| other.node_id == member.node_id)
+            {
+                self.push(ConfigError::Duplicated {
+                    field: format!("{}.node_id", field),
+                    value: member.node_id.to_string(),
+                });
+            }
+            // Addresses of the other nodes, they're not bound by this server.
+            let field = format!("{}.raft_network_addr", field);
+            match member.raft_network_addr.parse::<SocketAddr>() {
+                Ok(addr)
+                    if raft.members[..i]
+                        .iter()
+                        .any(|other| other.raft_network_addr.parse().ok() == Some(addr)) =>
+                {
+                    self.push(ConfigError::Duplicated {
+                        field,
+                        value: member.raft_network_addr.clone(),
+                    })
+                }
+                Ok(_) => {}
+                Err(err) => self.push(ConfigError::InvalidAddress {
+                    field,
+                    address: member.raft_network_addr.clone(),
+                    cause: err.to_string(),
+                }),
+            }
+        }
+        if !raft.members.is_empty()
+            && !raft
+                .members
+                .iter()
+                .any(|member| member.node_id == raft.node_id)
+        {
+            self.invalid(
+                "raft.node_id",
+                format!("{} is not in raft.members", raft.node_id),
+            );
+        }
+        // Routers are registered by the id in the raft storage, the node id is unique in the
+        // cluster so the router id is too.
+        if config.router.router_id != raft.node_id {
+            self.invalid(
+                "router.router_id",
+                format!(
+                    "{} must be the same as raft.node_id {}",
+                    config.router.router_id, raft.node_id
+                ),
+            );
+        }
+    }
+
+    fn northbound(&mut self, northbound: &NorthboundConfig) {
+        self.address("northbound.server_addr", &northbound.server_addr);
+        match &northbound.token {
+            Some(token) if token.is_empty() => {
+                self.invalid("northbound.token", "is empty".to_string())
+            }
+            // The token itself is never printed.
+            Some(token)
+                if format!("Bearer {}", token)
+                    .parse::<MetadataValue<Ascii>>()
+                    .is_err() =>
+            {
+                self.invalid(
+                    "northbound.token",
+                    "must be visible ascii characters".to_string(),
+                )
+            }
+            Some(_) => {}
+            None => {
+                let client_auth = northbound.tls.as_ref().map(|tls| tls.client_auth);
+                if client_auth != Some(ClientAuth::Required) {
+                    self.invalid(
+                        "northbound.token",
+                        "is required unless northbound.tls.client_auth is required, \
+                        applications can kick and send to devices"
+                            .to_string(),
+                    );
+                }
+            }
+        }
+        if let Some(tls) = &northbound.tls {
+            self.tls("northbound.tls", tls);
+        }
+    }
+
+    fn cluster(&mut self, config: &ServerConfig) {
+        if let Some(security) = &config.cluster_security {
+            match &security.secret {
+                Some(secret) if secret.is_empty() => {
+                    self.invalid("cluster_security.secret", "is empty".to_string())
+                }
+                // The secret itself is never printed.
+                Some(secret) if secret.parse::<MetadataValue<Ascii>>().is_err() => self.invalid(
+                    "cluster_security.secret",
+                    "must be visible ascii characters".to_string(),
+                ),
+                _ => {}
+            }
+            if let Some(tls) = &security.tls {
+                self.file("cluster_security.tls.ca_path", &tls.ca_path);
+                self.file("cluster_security.tls.cert_path", &tls.cert_path);
+                self.file("cluster_security.tls.key_path", &tls.key_path);
+                if tls.server_name.is_empty() {
+                    self.invalid("cluster_security.tls.server_name", "is empty".to_string());
+                }
+            }
+        }
+        if let Some(channel_pool) = &config.channel_pool {
+            self.positive(
+                "channel_pool.max_channels",
+                channel_pool.max_channels as u64,
+            );
+            if channel_pool.min_channels > channel_pool.max_channels {
+                self.invalid(
+                    "channel_pool.min_channels",
+                    format!(
+                        "{} is larger than channel_pool.max_channels {}",
+                        channel_pool.min_channels, channel_pool.max_channels
+                    ),
+                );
+            }
+            self.positive(
+                "channel_pool.busy_in_flight",
+                channel_pool.busy_in_flight as u64,
+            );
+            if let Some(idle_timeout) = channel_pool.idle_timeout {
+                self.positive("channel_pool.idle_timeout", idle_timeout);
+            }
+            self.positive("channel_pool.check_interval", channel_pool.check_interval);
+        }
+    }
+
+    fn finish(self) -> Result<(), ConfigErrors> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigErrors(self.errors))
+        }
+    }
+}
+
+// Binding both fails, the unspecified ip binds the port of all ips. Port 0 is picked by the os.
+fn conflicts(a: &SocketAddr, b: &SocketAddr) -> bool {
+    a.port() == b.port()
+        && a.port() != 0
+        && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{Config, File, FileFormat};
+
+    fn parse(toml: &str) -> ServerConfig {
+        let setting = Config::builder()
+            .add_source(File::from_str(toml, FileFormat::Toml))
+            .build()
+            .unwrap();
+        setting.try_deserialize().unwrap()
+    }
+
+    #[test]
+    fn test_shipped_configs_valid() {
+        for toml in [
+            include_str!("../../config/config.toml"),
+            include_str!("../../config/cluster/cluster_1.toml"),
+            include_str!("../../config/cluster/cluster_2.toml"),
+            include_str!("../../config/cluster/cluster_3.toml"),
+        ] {
+            assert_eq!(parse(toml).validate(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_all_problems_reported() {
+        let mut config = parse(include_str!("../../config/config.toml"));
+        let mut listener = config.listeners[0].clone();
+        listener.bind_address = "127.0.0.1:9990".to_string();
+        config.listeners.push(listener);
+        config.router.router_server_addr = "localhost:50000".to_string();
+        let raft = config.raft.as_mut().unwrap();
+        raft.election_timeout_min = raft.heartbeat_interval;
+        config.shutdown.drain_timeout = config.shutdown.timeout + 1;
+        config.redis = Some("redis://127.0.0.1:6379".to_string());
+
+        let errors = config.validate().unwrap_err().0;
+        assert_eq!(errors.len(), 6, "{:?}", errors);
+        assert_eq!(
+            errors[0],
+            ConfigError::Duplicated {
+                field: "listeners[1].name".to_string(),
+                value: "default".to_string(),
+            }
+        );
+        assert_eq!(
+            errors[1],
+            ConfigError::AddressInUse {
+                field: "listeners[1].bind_address".to_string(),
+                address: "127.0.0.1:9990".parse().unwrap(),
+                other: "listeners[0].bind_address".to_string(),
+            }
+        );
+        assert!(matches!(
+            &errors[2],
+            ConfigError::InvalidAddress { field, .. } if field == "router.router_server_addr"
+        ));
+        assert!(matches!(
+            &errors[3],
+            ConfigError::Invalid { field, .. } if field == "shutdown.drain_timeout"
+        ));
+        assert!(matches!(
+            &errors[4],
+            ConfigError::Invalid { field, .. } if field == "raft.election_timeout_min"
+        ));
+        assert!(matches!(
+            &errors[5],
+            ConfigError::UnusedSection { section, .. } if *section == "redis"
+        ));
+    }
+
+    #[test]
+    fn test_raft_members() {
+        let mut config = parse(include_str!("../../config/cluster/cluster_1.toml"));
+        config.router.router_id = 2;
+        let raft = config.raft.as_mut().unwrap();
+        raft.node_id = 4;
+        raft.members[1].node_id = 1;
+        raft.members[2].raft_network_addr = "127.0.0.1:9091".to_string();
+
+        let errors = config.validate().unwrap_err().0;
+        assert_eq!(
+            errors,
+            vec![
+                ConfigError::Duplicated {
+                    field: "raft.members[1].node_id".to_string(),
+                    value: "1".to_string(),
+                },
+                ConfigError::Duplicated {
+                    field: "raft.members[2].raft_network_addr".to_string(),
+                    value: "127.0.0.1:9091".to_string(),
+                },
+                ConfigError::Invalid {
+                    field: "raft.node_id".to_string(),
+                    reason: "4 is not in raft.members".to_string(),
+                },
+                ConfigError::Invalid {
+                    field: "router.router_id".to_string(),
+                    reason: "2 must be the same as raft.node_id 4".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_northbound_auth_required() {
+        let mut config = parse(include_str!("../../config/config.toml"));
+        let northbound = config.northbound.as_mut().unwrap();
+        northbound.token = None;
+        let errors = config.validate().unwrap_err().0;
+        assert!(matches!(
+            &errors[..],
+            [ConfigError::Invalid { field, .. }] if field == "northbound.token"
+        ));
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let conflicted = |a: &str, b: &str| conflicts(&a.parse().unwrap(), &b.parse().unwrap());
+        assert!(conflicted("0.0.0.0:9990", "127.0.0.1:9990"));
+        assert!(conflicted("[::]:9990", "0.0.0.0:9990"));
+        assert!(!conflicted("127.0.0.1:9990", "127.0.0.2:9990"));
+        assert!(!conflicted("0.0.0.0:9990", "0.0.0.0:9991"));
+        assert!(!conflicted("0.0.0.0:0", "0.0.0.0:0"));
+    }
+}
@@ -1,4 +1,3 @@
-use ::config::{Config, File};
 use clap::Parser;
 use time::UtcOffset;
 use tracing::{info, Level};
@@ -9,9 +8,11 @@ use tracing_subscriber::util::SubscriberInitExt;
 
 mod cli;
 mod config;
+mod northbound;
 mod panic_hook;
 pub(crate) mod protocol;
 mod router;
+mod security;
 pub mod server;
 mod storage;
 
@@ -19,12 +20,14 @@ mod storage;
 async fn main() -> anyhow::Result<()> {
     panic_hook::set_panic_hook();
     let cli = cli::Cli::parse();
-    let setting = Config::builder()
-        .add_source(File::from(cli.config_file()))
-        .set_override_option("server_name", cli.server_name())
-        .unwrap()
-        .build()?;
-    let config = config::ServerConfig::new(setting)?;
+    let config = cli.load_config()?;
+    // The merged config is printed even it's invalid, with the problems after it.
+    let validated = config.validate();
+    if cli.print_config() {
+        println!("{}", config);
+        return Ok(validated?);
+    }
+    validated?;
     let daily_appender =
         tracing_appender::rolling::daily("./logs", config.server_name.clone() + ".log");
     let (daily_no_blocking, _guard) = tracing_appender::non_blocking(daily_appender);
@@ -59,6 +62,11 @@ async fn main() -> anyhow::Result<()> {
     #[cfg(not(feature = "console"))]
     layers.init();
     info!("Print server config: \n {}", &config);
-    info!("Iot server start at: {}", &config.bind_address.clone());
+    for listener in &config.listeners {
+        info!(
+            "Iot server listener {} start at: {}",
+            listener.name, listener.bind_address
+        );
+    }
     cli.execute(config).await
 }
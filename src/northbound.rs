@@ -0,0 +1,275 @@
+// A grpc server for applications send packets to devices and subscribe packets sent by devices.
+use crate::config::NorthboundConfig;
+use crate::northbound::northbound_service::northbound_service_server::{
+    NorthboundService, NorthboundServiceServer,
+};
+use crate::northbound::northbound_service::{
+    broadcast_request, BroadcastReply, BroadcastRequest, Device, DeviceReply, DeviceRequest,
+    FailedDevice, KickDeviceReply, KickDeviceRequest, ListDevicesReply, ListDevicesRequest,
+    SubscribeUplinkRequest, UpdateGroupReply, UpdateGroupRequest, UplinkPacket as UplinkMessage,
+};
+use crate::protocol::packets::RawPacket;
+use crate::router::broadcast::Target;
+use crate::router::uplink::OverflowPolicy;
+use crate::router::{Qos, RouterClient, RouterError, RouterStorage, SendStatus};
+use crate::security::{server_tls, SecurityError, TokenVerifier};
+use crate::server::channel::ChannelId;
+use crate::server::ServerError;
+use futures::Stream;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tonic::service::interceptor;
+use tonic::transport::{Server, ServerTlsConfig};
+use tonic::{Request, Response, Status};
+use tracing::{info, warn};
+
+mod northbound_service;
+
+// Used when the request not set timeout.
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub struct NorthboundServer {
+    addr: String,
+    tls: Option<ServerTlsConfig>,
+    verifier: TokenVerifier,
+}
+
+impl NorthboundServer {
+    pub fn new(config: &NorthboundConfig) -> Result<NorthboundServer, SecurityError> {
+        Ok(NorthboundServer {
+            addr: config.server_addr.clone(),
+            tls: config.tls.as_ref().map(server_tls).transpose()?,
+            verifier: TokenVerifier::new(config.token.as_deref())?,
+        })
+    }
+
+    pub async fn start<Storage>(
+        &self,
+        router_client: RouterClient<Storage>,
+        mut shutdown_rx: broadcast::Receiver<()>,
+    ) -> Result<(), RouterError>
+    where
+        Storage: RouterStorage,
+    {
+        let socket_addr = self.addr.as_str().parse()?;
+        let northbound_service = NorthboundSvc::new(router_client);
+        info!("Northbound server starting with addr: {}", &self.addr);
+        let mut server = Server::builder();
+        if let Some(tls) = &self.tls {
+            server = server.tls_config(tls.clone())?;
+        }
+        server
+            .layer(interceptor(self.verifier.clone()))
+            .add_service(NorthboundServiceServer::new(northbound_service))
+            .serve_with_shutdown(socket_addr, async move {
+                let _ = shutdown_rx.recv().await;
+            })
+            .await?;
+        Ok(())
+    }
+}
+
+pub struct NorthboundSvc<Storage> {
+    router_client: RouterClient<Storage>,
+}
+
+impl<Storage> NorthboundSvc<Storage> {
+    pub fn new(router_client: RouterClient<Storage>) -> NorthboundSvc<Storage> {
+        NorthboundSvc { router_client }
+    }
+}
+
+type UplinkStream = Pin<Box<dyn Stream<Item = Result<UplinkMessage, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl<Storage> NorthboundService for NorthboundSvc<Storage>
+where
+    Storage: RouterStorage,
+{
+    async fn send_to_device(
+        &self,
+        request: Request<DeviceRequest>,
+    ) -> Result<Response<DeviceReply>, Status> {
+        let request = request.into_inner();
+        let raw_packet = RawPacket::read(request.packet)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let qos = match request.at_least_once {
+            true => Qos::AtLeastOnce,
+            false => Qos::AtMostOnce,
+        };
+        let send_status = self
+            .router_client
+            .send(raw_packet, qos)
+            .await
+            .map_err(into_status)?;
+        Ok(Response::new(DeviceReply {
+            packet: "".to_string(),
+            queued: send_status == SendStatus::Queued,
+        }))
+    }
+
+    async fn request_device(
+        &self,
+        request: Request<DeviceRequest>,
+    ) -> Result<Response<DeviceReply>, Status> {
+        let request = request.into_inner();
+        let raw_packet = RawPacket::read(request.packet)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let timeout = match request.timeout {
+            0 => DEFAULT_REQUEST_TIMEOUT,
+            timeout => Duration::from_millis(timeout),
+        };
+        let reply = self
+            .router_client
+            .request(raw_packet, timeout)
+            .await
+            .map_err(into_status)?;
+        Ok(Response::new(DeviceReply {
+            packet: reply
+                .write()
+                .map_err(|err| Status::internal(err.to_string()))?,
+            queued: false,
+        }))
+    }
+
+    async fn list_devices(
+        &self,
+        _request: Request<ListDevicesRequest>,
+    ) -> Result<Response<ListDevicesReply>, Status> {
+        let devices = self
+            .router_client
+            .list_channels()
+            .await
+            .map_err(into_status)?
+            .into_iter()
+            .map(|value| Device {
+                client_id: value.channel_id.to_string(),
+                router_id: value.router.router_id(),
+                status: value.channel_status.to_string(),
+            })
+            .collect();
+        Ok(Response::new(ListDevicesReply { devices }))
+    }
+
+    async fn kick_device(
+        &self,
+        request: Request<KickDeviceRequest>,
+    ) -> Result<Response<KickDeviceReply>, Status> {
+        let channel_id = ChannelId::from(request.into_inner().client_id);
+        let kicked = self
+            .router_client
+            .kick(channel_id)
+            .await
+            .map_err(into_status)?;
+        Ok(Response::new(KickDeviceReply { kicked }))
+    }
+
+    async fn broadcast(
+        &self,
+        request: Request<BroadcastRequest>,
+    ) -> Result<Response<BroadcastReply>, Status> {
+        let request = request.into_inner();
+        let target = match request.target {
+            Some(broadcast_request::Target::Group(group)) => Target::Group(group),
+            Some(broadcast_request::Target::Tag(tag)) => Target::Tag(tag),
+            Some(broadcast_request::Target::All(true)) => Target::All,
+            _ => return Err(Status::invalid_argument("Broadcast target is not set")),
+        };
+        let raw_packet = RawPacket::read(request.packet)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let report = self
+            .router_client
+            .broadcast(target, raw_packet.packet())
+            .await
+            .map_err(into_status)?;
+        Ok(Response::new(BroadcastReply {
+            delivered: report.delivered.into_iter().map(|id| id.into()).collect(),
+            failed: report
+                .failed
+                .into_iter()
+                .map(|(channel_id, error)| FailedDevice {
+                    client_id: channel_id.into(),
+                    error,
+                })
+                .collect(),
+            offline: report.offline.into_iter().map(|id| id.into()).collect(),
+        }))
+    }
+
+    async fn update_group(
+        &self,
+        request: Request<UpdateGroupRequest>,
+    ) -> Result<Response<UpdateGroupReply>, Status> {
+        let request = request.into_inner();
+        let members = request
+            .client_ids
+            .into_iter()
+            .map(ChannelId::from)
+            .collect();
+        self.router_client
+            .update_group(request.group, members)
+            .await
+            .map_err(into_status)?;
+        Ok(Response::new(UpdateGroupReply {}))
+    }
+
+    type SubscribeUplinkStream = UplinkStream;
+
+    async fn subscribe_uplink(
+        &self,
+        request: Request<SubscribeUplinkRequest>,
+    ) -> Result<Response<Self::SubscribeUplinkStream>, Status> {
+        let request = request.into_inner();
+        let policy = match request.block {
+            true => OverflowPolicy::Block,
+            false => OverflowPolicy::Drop,
+        };
+        let subscription = self
+            .router_client
+            .subscribe_uplink(request.capacity as usize, policy);
+        let stream = futures::stream::unfold(
+            (subscription, 0),
+            |(mut subscription, mut dropped)| async move {
+                let uplink_packet = subscription.recv().await?;
+                if subscription.dropped() > dropped {
+                    warn!(
+                        "Uplink subscriber is slow, dropped {} packets",
+                        subscription.dropped() - dropped
+                    );
+                    dropped = subscription.dropped();
+                }
+                let message = UplinkMessage {
+                    client_id: uplink_packet.channel_id.to_string(),
+                    packet: uplink_packet.raw,
+                    router_id: uplink_packet.router_id,
+                    timestamp: uplink_packet.timestamp,
+                };
+                Some((Ok(message), (subscription, dropped)))
+            },
+        );
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+fn into_status(err: RouterError) -> Status {
+    match err {
+        RouterError::ChannelRouterNotFound(_) => Status::not_found(err.to_string()),
+        RouterError::SendMessageError(_) => Status::invalid_argument(err.to_string()),
+        RouterError::ReplyErrorStatus(status) => status,
+        RouterError::LocalSessionError(ServerError::ChannelNotFound(_)) => {
+            Status::not_found(err.to_string())
+        }
+        RouterError::LocalSessionError(ServerError::RequestTimeout(_)) => {
+            Status::deadline_exceeded(err.to_string())
+        }
+        RouterError::LocalSessionError(ServerError::DeliveryFailed(_)) => {
+            Status::deadline_exceeded(err.to_string())
+        }
+        RouterError::LocalSessionError(ServerError::UncorrelatedPacket(_)) => {
+            Status::invalid_argument(err.to_string())
+        }
+        _ => Status::unavailable(err.to_string()),
+    }
+}
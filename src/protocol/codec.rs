@@ -1,6 +1,148 @@
 mod decoder;
 mod encoder;
 
+use bytes::{Bytes, BytesMut};
+use serde::Deserialize;
+use std::io;
 pub use tokio_util::codec::BytesCodec;
 pub use tokio_util::codec::LengthDelimitedCodec;
 pub use tokio_util::codec::LinesCodec;
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodecError, LinesCodecError};
+
+/// Framing of packets on the device connection.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CodecKind {
+    // One packet per line.
+    #[default]
+    Lines,
+    // Packets prefixed with the big endian u32 length.
+    LengthDelimited,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CodecError {
+    #[error("Frame is longer than the max frame length.")]
+    FrameTooLong,
+
+    #[error("Frame is not valid utf-8.")]
+    InvalidUtf8,
+
+    #[error("Codec I/O error, cause by: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl From<LinesCodecError> for CodecError {
+    fn from(err: LinesCodecError) -> Self {
+        match err {
+            LinesCodecError::MaxLineLengthExceeded => CodecError::FrameTooLong,
+            LinesCodecError::Io(err) => CodecError::Io(err),
+        }
+    }
+}
+
+// Length delimited codec reports too long frames with an I/O error.
+fn length_delimited_error(err: io::Error) -> CodecError {
+    let too_long = err
+        .get_ref()
+        .is_some_and(|inner| inner.is::<LengthDelimitedCodecError>());
+    if too_long {
+        CodecError::FrameTooLong
+    } else {
+        CodecError::Io(err)
+    }
+}
+
+/// Codec of a listener, frames are the raw packets.
+#[derive(Debug, Clone)]
+pub enum FrameCodec {
+    Lines(LinesCodec),
+    LengthDelimited(LengthDelimitedCodec),
+}
+
+impl FrameCodec {
+    /// Frames are unlimited for lines and 8MB for length delimited if no max frame length.
+    pub fn new(kind: CodecKind, max_frame_length: Option<usize>) -> FrameCodec {
+        match (kind, max_frame_length) {
+            (CodecKind::Lines, Some(max_frame_length)) => {
+                FrameCodec::Lines(LinesCodec::new_with_max_length(max_frame_length))
+            }
+            (CodecKind::Lines, None) => FrameCodec::Lines(LinesCodec::new()),
+            (CodecKind::LengthDelimited, Some(max_frame_length)) => FrameCodec::LengthDelimited(
+                LengthDelimitedCodec::builder()
+                    .max_frame_length(max_frame_length)
+                    .new_codec(),
+            ),
+            (CodecKind::LengthDelimited, None) => {
+                FrameCodec::LengthDelimited(LengthDelimitedCodec::new())
+            }
+        }
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = String;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self {
+            FrameCodec::Lines(codec) => Ok(codec.decode(src)?),
+            FrameCodec::LengthDelimited(codec) => {
+                match codec.decode(src).map_err(length_delimited_error)? {
+                    Some(frame) => String::from_utf8(frame.to_vec())
+                        .map(Some)
+                        .map_err(|_| CodecError::InvalidUtf8),
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+}
+
+impl Encoder<String> for FrameCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: String, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match self {
+            FrameCodec::Lines(codec) => Ok(codec.encode(item, dst)?),
+            FrameCodec::LengthDelimited(codec) => codec
+                .encode(Bytes::from(item), dst)
+                .map_err(length_delimited_error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_length_delimited_round_trip() {
+        let mut codec = FrameCodec::new(CodecKind::LengthDelimited, None);
+        let mut buf = BytesMut::new();
+        codec.encode("1,client_id".to_string(), &mut buf).unwrap();
+        assert_eq!(&buf[..4], &[0, 0, 0, 11]);
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some("1,client_id".to_string())
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_frame_too_long() {
+        let mut lines = FrameCodec::new(CodecKind::Lines, Some(4));
+        let mut buf = BytesMut::from("1,client_id\n");
+        assert!(matches!(
+            lines.decode(&mut buf),
+            Err(CodecError::FrameTooLong)
+        ));
+
+        let mut length_delimited = FrameCodec::new(CodecKind::LengthDelimited, Some(4));
+        let mut buf = BytesMut::from(&[0, 0, 0, 11][..]);
+        assert!(matches!(
+            length_delimited.decode(&mut buf),
+            Err(CodecError::FrameTooLong)
+        ));
+    }
+}
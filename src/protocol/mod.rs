@@ -1,8 +1,38 @@
 use crate::protocol::packets::Packet;
+use serde::Deserialize;
 
-mod codec;
+pub(crate) mod codec;
 pub(crate) mod packets;
 
+/// Packet format of a listener, frames are read into packets and packets written into frames.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    // Comma separated fields, led by the packet type.
+    #[default]
+    Text,
+}
+
+impl Protocol {
+    pub fn read(&self, raw: String) -> Result<Packet, PacketError> {
+        match self {
+            Protocol::Text => Packet::read(raw),
+        }
+    }
+
+    pub fn write(&self, packet: Packet) -> Result<String, PacketError> {
+        match self {
+            Protocol::Text => packet.write(),
+        }
+    }
+
+    pub fn check_sign_in_packet(&self, raw: &str) -> Result<bool, PacketError> {
+        match self {
+            Protocol::Text => Packet::check_sign_in_packet(raw),
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug, PartialEq)]
 pub enum PacketError {
     #[error("Can't parse raw to packet, raw: {raw}")]
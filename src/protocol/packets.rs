@@ -1,8 +1,14 @@
+use crate::protocol::packets::ack::AckRecv;
+use crate::protocol::packets::command::Command;
+use crate::protocol::packets::disconnect::DisconnectFire;
 use crate::protocol::packets::heartbeat::HeartbeatRecv;
 use crate::protocol::packets::sign_in::{SignInFire, SignInRecv};
 use crate::protocol::PacketError;
 use std::fmt::{Display, Formatter};
 
+mod ack;
+mod command;
+mod disconnect;
 mod heartbeat;
 mod sign_in;
 
@@ -44,19 +50,33 @@ pub enum Packet {
     SignIn(SignInRecv),
     SignInAck(SignInFire),
     HeartBeat(HeartbeatRecv),
+    Command(Command),
+    Ack(AckRecv),
+    Disconnect(DisconnectFire),
     Close(()),
 }
 
 pub const SIGN_IN: u8 = 1;
 pub const SIGN_IN_ACK: u8 = 2;
 pub const HEARTBEAT: u8 = 3;
+pub const COMMAND: u8 = 4;
+pub const ACK: u8 = 5;
+pub const DISCONNECT: u8 = 6;
 
 impl Packet {
+    pub fn disconnect(reason: &str) -> Packet {
+        Packet::Disconnect(DisconnectFire {
+            reason: reason.to_string(),
+        })
+    }
+
     pub fn read(raw: String) -> Result<Self, PacketError> {
         let header = PacketHeader::new(raw.as_str())?;
         match header.packet_type {
             SIGN_IN => Ok(Packet::SignIn(SignInRecv::try_from(raw)?)),
             HEARTBEAT => Ok(Packet::HeartBeat(HeartbeatRecv::try_from(raw)?)),
+            COMMAND => Ok(Packet::Command(Command::try_from(raw)?)),
+            ACK => Ok(Packet::Ack(AckRecv::try_from(raw)?)),
             u8::MAX | _ => Err(PacketError::UnKnowRecvPacketError { raw }),
         }
     }
@@ -68,10 +88,29 @@ impl Packet {
                 SIGN_IN_ACK,
                 <SignInFire as Into<String>>::into(sign_in_ack)
             )),
+            Packet::Command(command) => Ok(format!(
+                "{},{}",
+                COMMAND,
+                <Command as Into<String>>::into(command)
+            )),
+            Packet::Disconnect(disconnect) => Ok(format!(
+                "{},{}",
+                DISCONNECT,
+                <DisconnectFire as Into<String>>::into(disconnect)
+            )),
             _ => Err(PacketError::UnSupportFirePacketError { packet: self }),
         }
     }
 
+    // Match the reply packet with the request packet, packet without it can't be requested.
+    pub fn correlation_id(&self) -> Option<String> {
+        match self {
+            Packet::Command(command) => Some(command.message_id.clone()),
+            Packet::Ack(ack) => Some(ack.message_id.clone()),
+            _ => None,
+        }
+    }
+
     pub fn check_sign_in_packet(raw: &str) -> Result<bool, PacketError> {
         let header = PacketHeader::new(raw)?;
         Ok(header.packet_type() == SIGN_IN)
@@ -122,6 +161,15 @@ impl Display for Packet {
             Packet::HeartBeat(heartbeat) => {
                 write!(f, "Heartbeat:{:?}", heartbeat)
             }
+            Packet::Command(command) => {
+                write!(f, "Command:{:?}", command)
+            }
+            Packet::Ack(ack) => {
+                write!(f, "Ack:{:?}", ack)
+            }
+            Packet::Disconnect(disconnect) => {
+                write!(f, "Disconnect:{:?}", disconnect)
+            }
             Packet::Close(_) => {
                 write!(f, "Close")
             }
@@ -140,6 +188,19 @@ mod tests {
             client_id: "client_id".to_string(),
             username: "username".to_string(),
             password: "password".to_string(),
+            tags: vec![],
+        });
+        assert_eq!(Packet::read(raw_packet), Ok(expected_packet));
+    }
+
+    #[test]
+    fn test_read_sign_in_packet_with_tags() {
+        let raw_packet = "1,client_id,username,password,meter;v2".to_string();
+        let expected_packet = Packet::SignIn(SignInRecv {
+            client_id: "client_id".to_string(),
+            username: "username".to_string(),
+            password: "password".to_string(),
+            tags: vec!["meter".to_string(), "v2".to_string()],
         });
         assert_eq!(Packet::read(raw_packet), Ok(expected_packet));
     }
@@ -162,13 +223,38 @@ mod tests {
 
     #[test]
     fn test_read_unknown_packet() {
-        let raw_packet = "4,data".to_string();
+        let raw_packet = "9,data".to_string();
         let expected_error = PacketError::UnKnowRecvPacketError {
             raw: raw_packet.clone(),
         };
         assert_eq!(Packet::read(raw_packet), Err(expected_error));
     }
 
+    #[test]
+    fn test_read_command_packet() {
+        let raw_packet = "4,client_id,10,temperature,25".to_string();
+        let expected_packet = Packet::Command(Command {
+            client_id: "client_id".to_string(),
+            message_id: "10".to_string(),
+            payload: "temperature,25".to_string(),
+        });
+        assert_eq!(Packet::read(raw_packet), Ok(expected_packet));
+    }
+
+    #[test]
+    fn test_read_ack_packet() {
+        let raw_packet = "5,client_id,10".to_string();
+        let expected_packet = Packet::Ack(AckRecv {
+            client_id: "client_id".to_string(),
+            message_id: "10".to_string(),
+        });
+        assert_eq!(Packet::read(raw_packet.clone()), Ok(expected_packet));
+        assert_eq!(
+            Packet::read(raw_packet).unwrap().correlation_id(),
+            Some("10".to_string())
+        );
+    }
+
     #[test]
     fn test_write_sign_in_ack_packet() {
         let packet = Packet::SignInAck(SignInFire {
@@ -178,6 +264,23 @@ mod tests {
         assert_eq!(packet.write(), Ok(expected_raw_packet));
     }
 
+    #[test]
+    fn test_write_command_packet() {
+        let packet = Packet::Command(Command {
+            client_id: "client_id".to_string(),
+            message_id: "10".to_string(),
+            payload: "reboot".to_string(),
+        });
+        assert_eq!(packet.correlation_id(), Some("10".to_string()));
+        assert_eq!(packet.write(), Ok("4,client_id,10,reboot".to_string()));
+    }
+
+    #[test]
+    fn test_write_disconnect_packet() {
+        let packet = Packet::disconnect("shutdown");
+        assert_eq!(packet.write(), Ok("6,shutdown".to_string()));
+    }
+
     #[test]
     fn test_write_unsupported_packet() {
         let packet = Packet::HeartBeat(HeartbeatRecv { seq: 12345 });
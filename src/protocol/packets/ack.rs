@@ -0,0 +1,28 @@
+use crate::protocol::packets::Recv;
+use crate::protocol::PacketError;
+use crate::protocol::PacketError::ParsePacketError;
+
+// Device acked the packet with the message id, used by at least once delivery.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AckRecv {
+    pub client_id: String,
+    pub message_id: String,
+}
+
+impl TryFrom<String> for AckRecv {
+    type Error = PacketError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let attrs = value.split(',').collect::<Vec<&str>>();
+        if attrs.len() != 3 {
+            Err(ParsePacketError { raw: value })
+        } else {
+            Ok(AckRecv {
+                client_id: attrs[1].to_string(),
+                message_id: attrs[2].to_string(),
+            })
+        }
+    }
+}
+
+impl Recv for AckRecv {}
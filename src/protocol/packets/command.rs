@@ -0,0 +1,38 @@
+use crate::protocol::packets::{Fire, Recv};
+use crate::protocol::PacketError;
+use crate::protocol::PacketError::ParsePacketError;
+
+// Command is sent to device and device reply with the same message id.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    pub client_id: String,
+    pub message_id: String,
+    pub payload: String,
+}
+
+impl TryFrom<String> for Command {
+    type Error = PacketError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let attrs = value.splitn(4, ',').collect::<Vec<&str>>();
+        if attrs.len() != 4 {
+            Err(ParsePacketError { raw: value })
+        } else {
+            Ok(Command {
+                client_id: attrs[1].to_string(),
+                message_id: attrs[2].to_string(),
+                payload: attrs[3].to_string(),
+            })
+        }
+    }
+}
+
+impl Into<String> for Command {
+    fn into(self) -> String {
+        format!("{},{},{}", self.client_id, self.message_id, self.payload)
+    }
+}
+
+impl Recv for Command {}
+
+impl Fire for Command {}
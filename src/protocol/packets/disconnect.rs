@@ -0,0 +1,15 @@
+use crate::protocol::packets::Fire;
+
+// Sent to devices before the server closes the connection, devices should reconnect later.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisconnectFire {
+    pub reason: String,
+}
+
+impl Into<String> for DisconnectFire {
+    fn into(self) -> String {
+        self.reason
+    }
+}
+
+impl Fire for DisconnectFire {}
@@ -7,6 +7,8 @@ pub struct SignInRecv {
     pub client_id: String,
     pub username: String,
     pub password: String,
+    // Optional, separated by ';', used to select devices when broadcasting.
+    pub tags: Vec<String>,
 }
 
 impl TryFrom<String> for SignInRecv {
@@ -14,13 +16,22 @@ impl TryFrom<String> for SignInRecv {
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
         let attrs = value.split(',').collect::<Vec<&str>>();
-        if attrs.len() != 4 {
+        if attrs.len() != 4 && attrs.len() != 5 {
             Err(ParsePacketError { raw: value })
         } else {
+            let tags = match attrs.get(4) {
+                Some(tags) => tags
+                    .split(';')
+                    .filter(|tag| !tag.is_empty())
+                    .map(|tag| tag.to_string())
+                    .collect(),
+                None => vec![],
+            };
             Ok(SignInRecv {
                 client_id: attrs[1].to_string(),
                 username: attrs[2].to_string(),
                 password: attrs[3].to_string(),
+                tags,
             })
         }
     }
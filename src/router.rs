@@ -1,21 +1,45 @@
 use crate::server::channel::{ChannelId, ChannelStatus};
+use pool::PoolConfig;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::net::AddrParseError;
+use std::time::Duration;
+use tokio::select;
+use tokio::task::JoinHandle;
 use tonic::codegen::http::uri::InvalidUri;
 use tonic::transport::Error;
 use tonic::Status;
+use tracing::{debug, info, warn};
 
+pub mod broadcast;
+pub mod offline;
 mod remote;
 mod router_service;
 pub mod server;
 mod storage;
+mod stream;
+pub mod uplink;
 
-use crate::protocol::packets::RawPacket;
+use crate::config::{DeliveryConfig, OfflineQueueConfig};
+use crate::protocol::packets::{Packet, RawPacket};
 use crate::protocol::PacketError;
+use crate::router::broadcast::{DeliveryReport, Target};
+use crate::router::offline::OfflinePacket;
 use crate::router::remote::Remotes;
+use crate::router::uplink::{
+    OverflowPolicy, UplinkHub, UplinkPacket, UplinkSink, UplinkSubscription,
+};
+use crate::security::{ClusterSecurity, SecurityError};
 use crate::server::session::SharedSession;
+use crate::server::ServerError;
 pub use storage::RouterStorage;
 
+// Retry times of compare and swap when some routers take over the channel at the same time.
+const TAKEOVER_RETRY_TIMES: usize = 3;
+
+// Interval of finding routers joined the cluster and reconnecting broken uplink streams.
+const UPLINK_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
 #[derive(thiserror::Error, Debug)]
 pub enum RouterError {
     #[error("Send packet error cause by {0}.")]
@@ -35,6 +59,27 @@ pub enum RouterError {
 
     #[error("Raft server api start error: cause by {0}")]
     TonicServerError(#[from] Error),
+
+    #[error("No router found for channel {0}.")]
+    ChannelRouterNotFound(String),
+
+    #[error("Router storage error, cause by {0}.")]
+    StorageError(String),
+
+    #[error("Router storage {0} doesn't support {1}.")]
+    StorageUnsupported(&'static str, &'static str),
+
+    #[error("Channel {0} is taken over by other routers at the same time.")]
+    TakeoverConflict(String),
+
+    #[error(transparent)]
+    LocalSessionError(#[from] ServerError),
+
+    #[error("Packet stream error, cause by {0}.")]
+    PacketStreamError(String),
+
+    #[error(transparent)]
+    SecurityError(#[from] SecurityError),
 }
 
 /// router saved the connection and channel map state
@@ -60,12 +105,35 @@ pub struct Value {
     pub channel_id: ChannelId,
     pub router: Router,
     pub channel_status: ChannelStatus,
+    // Tags of the device signed in, used to select devices when broadcasting.
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Result of sending a packet to a device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SendStatus {
+    Sent,
+    // The device is offline, packet is delivered after it signed in again.
+    Queued,
+}
+
+/// Delivery guarantee of sending a packet to a device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Qos {
+    AtMostOnce,
+    // Retransmit until the device acked the message id.
+    AtLeastOnce,
 }
 
 #[derive(Debug, Clone)]
 pub struct RouterClient<Storage> {
-    router_id: RouterId,
+    router: Router,
+    takeover_timeout: Duration,
+    offline_queue: Option<OfflineQueueConfig>,
+    delivery: DeliveryConfig,
     local: SharedSession,
+    uplink: UplinkHub,
     remotes: Remotes,
     storage: Storage,
 }
@@ -74,34 +142,479 @@ impl<Storage> RouterClient<Storage>
 where
     Storage: RouterStorage + Clone,
 {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
-        router_id: RouterId,
+        router: Router,
+        takeover_timeout: Duration,
+        offline_queue: Option<OfflineQueueConfig>,
+        delivery: DeliveryConfig,
         session: SharedSession,
+        uplink: UplinkHub,
+        security: ClusterSecurity,
+        channel_pool: PoolConfig,
         storage: Storage,
     ) -> RouterClient<Storage> {
         RouterClient {
-            router_id,
+            router,
+            takeover_timeout,
+            offline_queue,
+            delivery,
             local: session,
-            remotes: Remotes::new().await,
+            uplink,
+            remotes: Remotes::new(security, channel_pool).await,
             storage,
         }
     }
 
     // Split local and remote message here.
-    // Process local to the local broker session.
-    pub async fn send(&self, raw_packet: RawPacket) -> Result<(), RouterError> {
+    // Process local to the local broker session. With at least once, the result is reported
+    // after the device acked the packet or the router holding it gave up retransmitting.
+    pub async fn send(&self, raw_packet: RawPacket, qos: Qos) -> Result<SendStatus, RouterError> {
+        let channel_id = ChannelId::from(raw_packet.header().client_id());
+        let value = self.storage.get_channel_router(channel_id.clone()).await?;
+        let online = value.as_ref().map_or(false, |value| {
+            value.channel_status == ChannelStatus::Established
+        });
+        if !online && self.offline_queue.is_some() {
+            return self.queue_offline(channel_id, raw_packet.packet()).await;
+        }
+        let value =
+            value.ok_or_else(|| RouterError::ChannelRouterNotFound(channel_id.to_string()))?;
+        match (self.router.router == value.router.router, qos) {
+            (true, Qos::AtMostOnce) => self.local.send(&channel_id, raw_packet.packet()).await?,
+            (true, Qos::AtLeastOnce) => {
+                self.local
+                    .deliver(&channel_id, raw_packet.packet(), &self.delivery)
+                    .await?
+            }
+            (false, qos) => self.remotes.send(value, raw_packet.packet(), qos).await?,
+        }
+        Ok(SendStatus::Sent)
+    }
+
+    async fn queue_offline(
+        &self,
+        channel_id: ChannelId,
+        packet: Packet,
+    ) -> Result<SendStatus, RouterError> {
+        let Some(offline_queue) = &self.offline_queue else {
+            return Err(RouterError::ChannelRouterNotFound(channel_id.to_string()));
+        };
+        let ttl = Duration::from_secs(offline_queue.ttl);
+        let offline_packet = OfflinePacket::new(packet.write()?, ttl);
+        self.storage
+            .push_offline_packet(channel_id, offline_packet, offline_queue.max_depth)
+            .await?;
+        Ok(SendStatus::Queued)
+    }
+
+    // Packets queued when the channel was offline in order, the expired are skipped.
+    pub async fn take_offline_packets(
+        &self,
+        channel_id: ChannelId,
+    ) -> Result<Vec<Packet>, RouterError> {
+        if self.offline_queue.is_none() {
+            return Ok(vec![]);
+        }
+        let now = chrono::Utc::now().timestamp_millis();
+        let offline_packets = self.storage.take_offline_packets(channel_id).await?;
+        Ok(offline_packets
+            .into_iter()
+            .filter(|offline_packet| !offline_packet.is_expired(now))
+            .filter_map(|offline_packet| match Packet::read(offline_packet.raw) {
+                Ok(packet) => Some(packet),
+                Err(err) => {
+                    warn!("Read offline packet cause a error: {}", err);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    // Send a request packet to the device and wait for it's reply, the reply is correlated
+    // by the router holding the channel.
+    pub async fn request(
+        &self,
+        raw_packet: RawPacket,
+        timeout: Duration,
+    ) -> Result<Packet, RouterError> {
         let channel_id = ChannelId::from(raw_packet.header().client_id());
-        let value: Value = self.storage.get_channel_router(channel_id.clone()).await;
-        if self.router_id == value.router.router {
-            self.local.send(&channel_id, raw_packet.packet()).await;
+        let value: Value = self
+            .storage
+            .get_channel_router(channel_id.clone())
+            .await?
+            .ok_or_else(|| RouterError::ChannelRouterNotFound(channel_id.to_string()))?;
+        if self.router.router == value.router.router {
+            Ok(self
+                .local
+                .request(&channel_id, raw_packet.packet(), timeout)
+                .await?)
         } else {
-            let _ = self.remotes.send(value, raw_packet.packet()).await;
+            self.remotes
+                .request(value, raw_packet.packet(), timeout)
+                .await
         }
-        Ok(())
+    }
+
+    pub async fn list_channels(&self) -> Result<Vec<Value>, RouterError> {
+        self.storage.list_channel_nodes().await
+    }
+
+    // Close the channel on the router holding it, and mark the channel closed.
+    pub async fn kick(&self, channel_id: ChannelId) -> Result<bool, RouterError> {
+        let value: Value = self
+            .storage
+            .get_channel_router(channel_id.clone())
+            .await?
+            .ok_or_else(|| RouterError::ChannelRouterNotFound(channel_id.to_string()))?;
+        let kicked = if self.router.router == value.router.router {
+            self.local.close(&channel_id).await.is_some()
+        } else {
+            self.remotes.close(value.clone()).await?
+        };
+        if kicked {
+            let owner = value.router.router;
+            let closed = Value {
+                channel_status: ChannelStatus::Closed,
+                ..value
+            };
+            self.storage
+                .compare_and_swap_channel_node(Some(owner), closed)
+                .await?;
+        }
+        Ok(kicked)
+    }
+
+    // Send the same packet to every device of the target, channels are split by the router
+    // holding them and every router is called once.
+    pub async fn broadcast(
+        &self,
+        target: Target,
+        packet: Packet,
+    ) -> Result<DeliveryReport, RouterError> {
+        let group_members = match &target {
+            Target::Group(group) => self.storage.get_group(group.clone()).await?,
+            _ => vec![],
+        };
+        let values = self.storage.list_channel_nodes().await?;
+        let (selected, offline) = broadcast::resolve(&target, values, group_members);
+
+        let mut routers: HashMap<RouterId, (Router, Vec<ChannelId>)> = HashMap::new();
+        for value in selected {
+            routers
+                .entry(value.router.router)
+                .or_insert_with(|| (value.router.clone(), vec![]))
+                .1
+                .push(value.channel_id);
+        }
+        let reports = futures::future::join_all(
+            routers
+                .into_values()
+                .map(|(router, channel_ids)| self.broadcast_router(router, channel_ids, &packet)),
+        )
+        .await;
+
+        let mut report = DeliveryReport {
+            offline,
+            ..Default::default()
+        };
+        for router_report in reports {
+            report.merge(router_report);
+        }
+        Ok(report)
+    }
+
+    async fn broadcast_router(
+        &self,
+        router: Router,
+        channel_ids: Vec<ChannelId>,
+        packet: &Packet,
+    ) -> DeliveryReport {
+        let deliveries = if router.router == self.router.router {
+            let mut deliveries = Vec::with_capacity(channel_ids.len());
+            for channel_id in channel_ids {
+                let error = self
+                    .local
+                    .send(&channel_id, packet.clone())
+                    .await
+                    .err()
+                    .map(|err| err.to_string());
+                deliveries.push((channel_id, error));
+            }
+            deliveries
+        } else {
+            match self
+                .remotes
+                .broadcast(&router, channel_ids.clone(), packet.clone())
+                .await
+            {
+                Ok(deliveries) => deliveries,
+                Err(err) => channel_ids
+                    .into_iter()
+                    .map(|channel_id| (channel_id, Some(err.to_string())))
+                    .collect(),
+            }
+        };
+        let mut report = DeliveryReport::default();
+        for (channel_id, error) in deliveries {
+            match error {
+                Some(error) => report.failed.push((channel_id, error)),
+                None => report.delivered.push(channel_id),
+            }
+        }
+        report
+    }
+
+    pub async fn update_group(
+        &self,
+        group: String,
+        members: Vec<ChannelId>,
+    ) -> Result<(), RouterError> {
+        self.storage.update_group(group, members).await
+    }
+
+    // Publish the channel established with this router. If device sign_in in another
+    // router before, notice the old router close the channel and wait for it's reply,
+    // then move the channel to this router.
+    pub async fn register_channel(
+        &self,
+        channel_id: ChannelId,
+        tags: Vec<String>,
+    ) -> Result<Value, RouterError> {
+        let mut value = Value::new(
+            channel_id.clone(),
+            self.router.clone(),
+            ChannelStatus::Established,
+        );
+        value.tags = tags;
+        for _ in 0..TAKEOVER_RETRY_TIMES {
+            let previous = self.storage.get_channel_router(channel_id.clone()).await?;
+            let expect = previous.as_ref().map(|previous| previous.router.router);
+            if let Some(previous) = previous {
+                if previous.router.router != self.router.router
+                    && previous.channel_status != ChannelStatus::Closed
+                {
+                    self.take_over(previous).await;
+                }
+            }
+            if self
+                .storage
+                .compare_and_swap_channel_node(expect, value.clone())
+                .await?
+            {
+                return Ok(value);
+            }
+            // Another router changed the channel router at the same time, fetch again.
+        }
+        Err(RouterError::TakeoverConflict(channel_id.to_string()))
+    }
+
+    // Old router not reply in time maybe dead, the channel is moved anyway.
+    async fn take_over(&self, previous: Value) {
+        info!(
+            "Channel {} take over from router {} to router {}",
+            previous.channel_id, previous.router.router, self.router.router
+        );
+        let old_router = previous.router.router;
+        match tokio::time::timeout(self.takeover_timeout, self.remotes.close(previous)).await {
+            Ok(Ok(closed)) => {
+                debug!("Old router {} closed channel: {}", old_router, closed);
+            }
+            Ok(Err(err)) => {
+                warn!(
+                    "Notice old router {} close channel error: {}",
+                    old_router, err
+                );
+            }
+            Err(_) => {
+                warn!("Wait old router {} close channel timeout", old_router);
+            }
+        }
+    }
+
+    // Update the channel status when the connection closing or closed, skip it when
+    // the channel has been taken over by another router.
+    pub async fn release_channel(
+        &self,
+        channel_id: ChannelId,
+        channel_status: ChannelStatus,
+    ) -> Result<bool, RouterError> {
+        match self.storage.get_channel_router(channel_id).await? {
+            Some(value) => self.release(value, channel_status).await,
+            None => Ok(false),
+        }
+    }
+
+    // Only the status of the value read is changed, tags are kept for broadcasting.
+    async fn release(
+        &self,
+        value: Value,
+        channel_status: ChannelStatus,
+    ) -> Result<bool, RouterError> {
+        if value.router.router != self.router.router {
+            return Ok(false);
+        }
+        let value = Value {
+            channel_status,
+            ..value
+        };
+        self.storage
+            .compare_and_swap_channel_node(Some(self.router.router), value)
+            .await
+    }
+
+    // Registry this router, so that subscribers on other routers can find it.
+    pub async fn register_router(&self) -> Result<(), RouterError> {
+        self.storage.router_lease(self.router.clone()).await
+    }
+
+    // Release routes of channels still held by this router and remove it from the registry,
+    // called after the broker stopped when the server shutting down.
+    pub async fn deregister_router(&self) -> Result<(), RouterError> {
+        let router_id = self.router.router;
+        let values = self.storage.list_channel_nodes().await?;
+        for value in values.into_iter().filter(|value| {
+            value.router.router == router_id && value.channel_status != ChannelStatus::Closed
+        }) {
+            self.release(value, ChannelStatus::Closed).await?;
+        }
+        self.remotes.close_streams();
+        self.storage.router_release(router_id).await
+    }
+
+    // Publish a packet sent by the device connected to this router.
+    pub async fn publish_uplink(&self, uplink_packet: UplinkPacket) {
+        self.uplink.publish(uplink_packet).await
+    }
+
+    // Subscribe packets sent by devices connected to any router of the cluster. Packets of
+    // this router come from the local hub, others are streamed from the remote routers.
+    pub fn subscribe_uplink(&self, capacity: usize, policy: OverflowPolicy) -> UplinkSubscription {
+        let subscription = self.uplink.subscribe(capacity, policy);
+        let router_client = self.clone();
+        let sink = subscription.sink();
+        tokio::spawn(async move {
+            router_client.watch_remote_uplink(sink, capacity).await;
+        });
+        subscription
+    }
+
+    // Keep a stream to every other router until the subscriber gone.
+    async fn watch_remote_uplink(&self, sink: UplinkSink, capacity: usize) {
+        let mut streams: HashMap<RouterId, JoinHandle<()>> = HashMap::new();
+        loop {
+            match self.storage.list_routers().await {
+                Ok(routers) => {
+                    for router in routers {
+                        if router.router == self.router.router {
+                            continue;
+                        }
+                        let running = streams
+                            .get(&router.router)
+                            .map_or(false, |stream| !stream.is_finished());
+                        if !running {
+                            let stream = tokio::spawn(Self::forward_remote_uplink(
+                                self.remotes.clone(),
+                                router.clone(),
+                                sink.clone(),
+                                capacity,
+                            ));
+                            streams.insert(router.router, stream);
+                        }
+                    }
+                }
+                Err(err) => warn!("List routers for uplink cause a error: {}", err),
+            }
+            select! {
+                _ = sink.closed() => break,
+                _ = tokio::time::sleep(UPLINK_REFRESH_INTERVAL) => {}
+            }
+        }
+        for stream in streams.values() {
+            stream.abort();
+        }
+    }
+
+    async fn forward_remote_uplink(
+        remotes: Remotes,
+        router: Router,
+        sink: UplinkSink,
+        capacity: usize,
+    ) {
+        let mut stream = match remotes
+            .subscribe_uplink(&router, capacity, sink.policy())
+            .await
+        {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!(
+                    "Subscribe uplink of router {} cause a error: {}",
+                    router.router, err
+                );
+                return;
+            }
+        };
+        loop {
+            let message = match stream.message().await {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(status) => {
+                    warn!(
+                        "Uplink stream of router {} broken: {}",
+                        router.router, status
+                    );
+                    break;
+                }
+            };
+            let packet = match Packet::read(message.packet.clone()) {
+                Ok(packet) => packet,
+                Err(err) => {
+                    warn!("Read uplink packet cause a error: {}", err);
+                    continue;
+                }
+            };
+            let uplink_packet = UplinkPacket {
+                channel_id: ChannelId::from(message.channel_id),
+                router_id: message.router_id,
+                timestamp: message.timestamp,
+                raw: message.packet,
+                packet,
+            };
+            if !sink.deliver(uplink_packet).await {
+                break;
+            }
+        }
+    }
+
+    pub fn router(&self) -> &Router {
+        &self.router
+    }
+}
+
+impl Router {
+    pub fn new(router: RouterId, local_address: String, remote_addr: String) -> Router {
+        Router {
+            router,
+            local_address,
+            remote_addr,
+        }
+    }
+
+    pub fn router_id(&self) -> RouterId {
+        self.router
     }
 }
 
 impl Value {
+    pub fn new(channel_id: ChannelId, router: Router, channel_status: ChannelStatus) -> Value {
+        Value {
+            channel_id,
+            router,
+            channel_status,
+            tags: vec![],
+        }
+    }
+
     pub fn channel_id(&self) -> ChannelId {
         self.channel_id.clone()
     }
@@ -111,8 +624,5 @@ impl Value {
 
 // raft shared channel status
 
-// if device sign_in in another node, it will update router and notice old
-// node clear resources.
-
 // heartbeat timer task update channel status and disconnection remove channel,
 // should there acquire a distributed lock.
@@ -0,0 +1,121 @@
+use crate::router::Value;
+use crate::server::channel::{ChannelId, ChannelStatus};
+use std::collections::HashMap;
+
+/// Devices a packet is broadcast to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    // Static group saved in the router storage.
+    Group(String),
+    // Devices signed in with the tag.
+    Tag(String),
+    All,
+}
+
+/// Aggregated result of a broadcast.
+#[derive(Debug, Clone, Default)]
+pub struct DeliveryReport {
+    pub delivered: Vec<ChannelId>,
+    // Channel with the error cause the packet not delivered.
+    pub failed: Vec<(ChannelId, String)>,
+    // Group members not connected to any router.
+    pub offline: Vec<ChannelId>,
+}
+
+impl DeliveryReport {
+    pub fn merge(&mut self, other: DeliveryReport) {
+        self.delivered.extend(other.delivered);
+        self.failed.extend(other.failed);
+        self.offline.extend(other.offline);
+    }
+}
+
+/// Select the established channels of target, the group members are given by storage.
+/// Return the selected channels and the group members offline.
+pub fn resolve(
+    target: &Target,
+    values: Vec<Value>,
+    group_members: Vec<ChannelId>,
+) -> (Vec<Value>, Vec<ChannelId>) {
+    let established = values
+        .into_iter()
+        .filter(|value| value.channel_status == ChannelStatus::Established);
+    match target {
+        Target::All => (established.collect(), vec![]),
+        Target::Tag(tag) => (
+            established
+                .filter(|value| value.tags.contains(tag))
+                .collect(),
+            vec![],
+        ),
+        Target::Group(_) => {
+            let mut established = established
+                .map(|value| (value.channel_id.to_string(), value))
+                .collect::<HashMap<_, _>>();
+            let mut selected = Vec::with_capacity(group_members.len());
+            let mut offline = vec![];
+            for member in group_members {
+                match established.remove(&member.to_string()) {
+                    Some(value) => selected.push(value),
+                    None => offline.push(member),
+                }
+            }
+            (selected, offline)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::Router;
+
+    fn value(channel_id: &str, status: ChannelStatus, tags: &[&str]) -> Value {
+        let router = Router::new(
+            1,
+            "0.0.0.0:1883".to_string(),
+            "http://0.0.0.0:50051".to_string(),
+        );
+        let mut value = Value::new(ChannelId::from(channel_id.to_string()), router, status);
+        value.tags = tags.iter().map(|tag| tag.to_string()).collect();
+        value
+    }
+
+    fn channel_ids(values: &[Value]) -> Vec<String> {
+        values
+            .iter()
+            .map(|value| value.channel_id.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_resolve_tag_skip_closed() {
+        let values = vec![
+            value("a", ChannelStatus::Established, &["meter"]),
+            value("b", ChannelStatus::Closed, &["meter"]),
+            value("c", ChannelStatus::Established, &["light"]),
+        ];
+        let (selected, offline) = resolve(&Target::Tag("meter".to_string()), values, vec![]);
+        assert_eq!(channel_ids(&selected), vec!["a"]);
+        assert!(offline.is_empty());
+    }
+
+    #[test]
+    fn test_resolve_group_offline_members() {
+        let values = vec![
+            value("a", ChannelStatus::Established, &[]),
+            value("b", ChannelStatus::Closing, &[]),
+        ];
+        let members = ["a", "b", "c"]
+            .iter()
+            .map(|member| ChannelId::from(member.to_string()))
+            .collect();
+        let (selected, offline) = resolve(&Target::Group("group".to_string()), values, members);
+        assert_eq!(channel_ids(&selected), vec!["a"]);
+        let offline = offline
+            .into_iter()
+            .map(|member| member.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(offline, vec!["b", "c"]);
+    }
+}
@@ -0,0 +1,99 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+/// Packet sent to a device not connected, delivered after the device signed in again.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct OfflinePacket {
+    pub raw: String,
+    // milliseconds since unix epoch
+    pub queued_at: i64,
+    pub expire_at: i64,
+}
+
+impl OfflinePacket {
+    pub fn new(raw: String, ttl: Duration) -> OfflinePacket {
+        let queued_at = chrono::Utc::now().timestamp_millis();
+        OfflinePacket {
+            raw,
+            queued_at,
+            expire_at: queued_at + ttl.as_millis() as i64,
+        }
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expire_at <= now
+    }
+}
+
+/// Append the packet to the queue of a device. Packets expired when the new packet queued
+/// are removed, and the oldest are dropped when the queue is deeper than max depth.
+pub fn enqueue(queue: &mut VecDeque<OfflinePacket>, packet: OfflinePacket, max_depth: usize) {
+    queue.retain(|queued| !queued.is_expired(packet.queued_at));
+    queue.push_back(packet);
+    while queue.len() > max_depth {
+        queue.pop_front();
+    }
+}
+
+/// Remove the expired packets at the front of every queue and the queues left empty, so queues
+/// of devices never signed in again are not kept forever. The time is of the packet queued, not
+/// the clock of the node, every node prunes the same.
+pub fn prune(queues: &mut BTreeMap<String, VecDeque<OfflinePacket>>, now: i64) {
+    queues.retain(|_, queue| {
+        while queue.front().is_some_and(|queued| queued.is_expired(now)) {
+            queue.pop_front();
+        }
+        !queue.is_empty()
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(raw: &str, queued_at: i64, expire_at: i64) -> OfflinePacket {
+        OfflinePacket {
+            raw: raw.to_string(),
+            queued_at,
+            expire_at,
+        }
+    }
+
+    #[test]
+    fn test_enqueue_drop_oldest_when_full() {
+        let mut queue = VecDeque::new();
+        enqueue(&mut queue, packet("1", 0, 100), 2);
+        enqueue(&mut queue, packet("2", 1, 100), 2);
+        enqueue(&mut queue, packet("3", 2, 100), 2);
+        let raws = queue
+            .iter()
+            .map(|queued| queued.raw.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(raws, vec!["2", "3"]);
+    }
+
+    #[test]
+    fn test_enqueue_remove_expired() {
+        let mut queue = VecDeque::new();
+        enqueue(&mut queue, packet("1", 0, 10), 10);
+        enqueue(&mut queue, packet("2", 20, 100), 10);
+        assert_eq!(queue, VecDeque::from(vec![packet("2", 20, 100)]));
+    }
+
+    #[test]
+    fn test_prune_expired_queues() {
+        let mut queues = BTreeMap::new();
+        queues.insert(
+            "gone".to_string(),
+            VecDeque::from(vec![packet("1", 0, 10), packet("2", 5, 15)]),
+        );
+        queues.insert(
+            "back".to_string(),
+            VecDeque::from(vec![packet("3", 0, 10), packet("4", 30, 100)]),
+        );
+        prune(&mut queues, 20);
+        assert_eq!(queues.len(), 1);
+        assert_eq!(queues["back"], VecDeque::from(vec![packet("4", 30, 100)]));
+    }
+}
@@ -1,25 +1,62 @@
 use crate::protocol::packets::Packet;
 use crate::router::router_service::router_service_client::RouterServiceClient;
-use crate::router::router_service::RouterRequest;
-use crate::router::{RouterError, RouterId, Value};
+use crate::router::router_service::{
+    BroadcastRequest, CloseRequest, RouterRequest, UplinkMessage, UplinkRequest,
+};
+use crate::router::stream::{PacketStream, StreamState};
+use crate::router::uplink::OverflowPolicy;
+use crate::router::{Qos, Router, RouterError, RouterId, Value};
+use crate::security::{ClusterChannel, ClusterSecurity};
 use crate::server::channel::ChannelId;
-use pool::MutexPool;
+use crate::server::ServerError;
+use egccri_future_util::{retry_if, RetryPolicy};
+use pool::{MutexPool, PoolConfig, Pooled};
+use std::collections::HashMap;
 use std::net::IpAddr;
-use tonic::transport::{Channel, Endpoint};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tonic::{Code, Streaming};
 use tracing::info;
 
+// The remote router waits the device reply for the timeout, the deadline of the call adds a
+// margin for the network round trip, so the remote timeout is seen before the deadline.
+const REQUEST_DEADLINE_MARGIN: Duration = Duration::from_secs(1);
+
+// Dead channels are evicted by the pool checker, so they are not got again. Checked at this
+// interval if the channel pool is not configured.
+const CHANNEL_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+// Idempotent calls are retried when the remote router is unavailable, requests are not, the
+// device may have handled the packet.
+const CALL_RETRY: RetryPolicy =
+    RetryPolicy::exponential(Duration::from_millis(100), Duration::from_secs(1), 2);
+
+fn is_unavailable(err: &RouterError) -> bool {
+    match err {
+        RouterError::ChannelConnectError => true,
+        RouterError::ReplyErrorStatus(status) => status.code() == Code::Unavailable,
+        _ => false,
+    }
+}
+
 #[derive(Debug, Clone)]
-struct ChannelBuilder;
+pub(super) struct ChannelBuilder {
+    security: ClusterSecurity,
+}
+
+/// Channels to other routers, keyed by the router address.
+pub(super) type ChannelPool = MutexPool<ChannelBuilder>;
 
 #[async_trait::async_trait]
 impl pool::PoolItemBuilder for ChannelBuilder {
     type Token = String;
-    type Item = Channel;
+    type Item = ClusterChannel;
     type Error = tonic::transport::Error;
 
     async fn build(&self, addr: &Self::Token) -> Result<Self::Item, Self::Error> {
         info!("Building channel for addr: {}", &addr);
-        Endpoint::new(addr.clone())?.connect().await
+        self.security.connect(addr.clone()).await
     }
 }
 
@@ -28,57 +65,201 @@ impl pool::PoolItemBuilder for ChannelBuilder {
 /// remote: receive inner call -> send packet to the local session -> return reply packet
 #[derive(Debug, Clone)]
 pub struct Remotes {
-    inner: MutexPool<ChannelBuilder>,
+    inner: ChannelPool,
+    // Packet streams keyed by router address, created when the first packet sent.
+    streams: Arc<Mutex<HashMap<String, PacketStream>>>,
+    // Evicts the dead or idle channels, it stops itself when the pool dropped.
+    _checker: Arc<JoinHandle<()>>,
 }
 
 impl Remotes {
-    pub async fn new() -> Remotes {
-        let channel_builder = ChannelBuilder;
-        let channel_pool = MutexPool::new(channel_builder, None);
+    pub async fn new(security: ClusterSecurity, channel_pool: PoolConfig) -> Remotes {
+        let channel_builder = ChannelBuilder { security };
+        let check_interval = channel_pool
+            .check_interval
+            .unwrap_or(CHANNEL_CHECK_INTERVAL);
+        let channel_pool = MutexPool::new(channel_builder, channel_pool);
+        let checker = Arc::new(channel_pool.spawn_checker(check_interval));
         Remotes {
             inner: channel_pool,
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            _checker: checker,
         }
     }
 
-    pub async fn send(&self, value: Value, packet: Packet) -> Result<Packet, RouterError> {
+    pub async fn send(&self, value: Value, packet: Packet, qos: Qos) -> Result<(), RouterError> {
         let channel_id = value.channel_id;
-        let router_id: RouterId = value.router.router;
         let router_addr: String = value.router.remote_addr;
 
-        let channel = self
-            .inner
-            .get(&router_addr)
+        // At least once is retransmitted by the remote router, wait for it's report.
+        if qos == Qos::AtLeastOnce {
+            return self
+                .send_packet(&router_addr, channel_id, packet, true)
+                .await;
+        }
+        let stream = self.packet_stream(&router_addr);
+        if stream.state() == StreamState::Streaming {
+            return stream.send(channel_id, packet).await;
+        }
+        // Remote router not support streaming or the stream is reconnecting.
+        retry_if(
+            &CALL_RETRY,
+            || self.send_packet(&router_addr, channel_id.clone(), packet.clone(), false),
+            is_unavailable,
+        )
+        .await
+    }
+
+    // Taken from the pool for each call, so a retried call may get another channel.
+    async fn channel(&self, router_addr: &str) -> Result<Pooled<ClusterChannel>, RouterError> {
+        self.inner
+            .get(&router_addr.to_string())
             .await
-            .map_err(|err| RouterError::ChannelConnectError)?;
-        // find RouterGrpcClient
-        let reply = self.send_packet(channel, channel_id, packet).await?;
-        Ok(reply)
+            .map_err(|_| RouterError::ChannelConnectError)
+    }
+
+    fn packet_stream(&self, router_addr: &str) -> PacketStream {
+        let mut streams = self.streams.lock().unwrap();
+        // Closed streams are dropped, like the ones of routers left, and opened again if needed.
+        streams.retain(|_, stream| stream.state() != StreamState::Closed);
+        streams
+            .entry(router_addr.to_string())
+            .or_insert_with(|| PacketStream::connect(router_addr.to_string(), self.inner.clone()))
+            .clone()
+    }
+
+    /// Stop the packet streams to all routers, called when this router is deregistered.
+    pub fn close_streams(&self) {
+        self.streams.lock().unwrap().clear();
     }
 
     async fn send_packet(
         &self,
-        channel: Channel,
+        router_addr: &str,
         channel_id: ChannelId,
         packet: Packet,
+        at_least_once: bool,
+    ) -> Result<(), RouterError> {
+        let channel = self.channel(router_addr).await?;
+        let raw = packet.write()?;
+
+        let mut client = RouterServiceClient::new(channel.clone());
+        let message = RouterRequest {
+            channel_id: channel_id.into(),
+            packet: raw,
+            timeout: 0,
+            at_least_once,
+        };
+        client
+            .send_packet(tonic::Request::new(message))
+            .await
+            .map_err(RouterError::ReplyErrorStatus)?;
+        Ok(())
+    }
+
+    // Request the router holding the channel, it waits for the device reply packet.
+    pub async fn request(
+        &self,
+        value: Value,
+        packet: Packet,
+        timeout: Duration,
     ) -> Result<Packet, RouterError> {
+        let channel = self.channel(&value.router.remote_addr).await?;
         let raw = packet.write()?;
 
-        let reply = {
-            let mut client = RouterServiceClient::new(channel);
-            let message = RouterRequest {
-                channel_id: channel_id.into(),
-                packet: raw,
-            };
-            let reply = client
-                .send_packet(tonic::Request::new(message))
-                .await
-                .map_err(|status| RouterError::ReplyErrorStatus(status))?;
-            reply
+        let mut client = RouterServiceClient::new(channel.clone());
+        let channel_id = value.channel_id.to_string();
+        let message = RouterRequest {
+            channel_id: value.channel_id.into(),
+            packet: raw,
+            timeout: timeout.as_millis() as u64,
+            at_least_once: false,
         };
+        let mut request = tonic::Request::new(message);
+        request.set_timeout(timeout + REQUEST_DEADLINE_MARGIN);
+        let reply = client
+            .request_packet(request)
+            .await
+            .map_err(|status| match status.code() {
+                // Timed out waiting the device, or the remote router not replied in time.
+                Code::DeadlineExceeded => {
+                    RouterError::LocalSessionError(ServerError::RequestTimeout(channel_id))
+                }
+                _ => RouterError::ReplyErrorStatus(status),
+            })?;
 
         Ok(Packet::read(reply.into_inner().packet)?)
     }
 
+    // Notice the router in value to close the channel and clear resources.
+    pub async fn close(&self, value: Value) -> Result<bool, RouterError> {
+        retry_if(&CALL_RETRY, || self.close_channel(&value), is_unavailable).await
+    }
+
+    async fn close_channel(&self, value: &Value) -> Result<bool, RouterError> {
+        let channel = self.channel(&value.router.remote_addr).await?;
+        let mut client = RouterServiceClient::new(channel.clone());
+        let message = CloseRequest {
+            channel_id: value.channel_id.to_string(),
+        };
+        let reply = client
+            .close_channel(tonic::Request::new(message))
+            .await
+            .map_err(RouterError::ReplyErrorStatus)?;
+        Ok(reply.into_inner().closed)
+    }
+
+    // Send the packet to channels held by the router, return the error of every channel
+    // not delivered.
+    pub async fn broadcast(
+        &self,
+        router: &Router,
+        channel_ids: Vec<ChannelId>,
+        packet: Packet,
+    ) -> Result<Vec<(ChannelId, Option<String>)>, RouterError> {
+        let channel = self.channel(&router.remote_addr).await?;
+        let raw = packet.write()?;
+
+        let mut client = RouterServiceClient::new(channel.clone());
+        let message = BroadcastRequest {
+            channel_ids: channel_ids.into_iter().map(|id| id.into()).collect(),
+            packet: raw,
+        };
+        let reply = client
+            .broadcast_packet(tonic::Request::new(message))
+            .await
+            .map_err(RouterError::ReplyErrorStatus)?;
+        Ok(reply
+            .into_inner()
+            .deliveries
+            .into_iter()
+            .map(|delivery| {
+                let error = Some(delivery.error).filter(|error| !error.is_empty());
+                (ChannelId::from(delivery.channel_id), error)
+            })
+            .collect())
+    }
+
+    // Subscribe packets sent by devices connected to the router.
+    pub async fn subscribe_uplink(
+        &self,
+        router: &Router,
+        capacity: usize,
+        policy: OverflowPolicy,
+    ) -> Result<Streaming<UplinkMessage>, RouterError> {
+        let channel = self.channel(&router.remote_addr).await?;
+        let mut client = RouterServiceClient::new(channel.clone());
+        let message = UplinkRequest {
+            capacity: capacity as u32,
+            block: policy == OverflowPolicy::Block,
+        };
+        let reply = client
+            .subscribe_uplink(tonic::Request::new(message))
+            .await
+            .map_err(RouterError::ReplyErrorStatus)?;
+        Ok(reply.into_inner())
+    }
+
     // init with config routers, maybe not use
     #[warn(dead_code)]
     pub async fn init(&mut self, routers: Vec<(RouterId, IpAddr)>) -> Result<(), RouterError> {
@@ -6,13 +6,112 @@ pub struct RouterRequest {
     pub channel_id: ::prost::alloc::string::String,
     #[prost(string, tag = "2")]
     pub packet: ::prost::alloc::string::String,
+    /// milliseconds to wait for the reply packet, only used by request
+    #[prost(uint64, tag = "3")]
+    pub timeout: u64,
+    /// retransmit until the device acked, reply after acked or retransmitting exhausted
+    #[prost(bool, tag = "4")]
+    pub at_least_once: bool,
 }
+/// reply packet of request, empty if send only
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct RouterReply {
     #[prost(string, tag = "1")]
     pub packet: ::prost::alloc::string::String,
 }
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct StreamPacket {
+    #[prost(uint64, tag = "1")]
+    pub seq: u64,
+    #[prost(string, tag = "2")]
+    pub channel_id: ::prost::alloc::string::String,
+    #[prost(string, tag = "3")]
+    pub packet: ::prost::alloc::string::String,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PacketBatch {
+    #[prost(message, repeated, tag = "1")]
+    pub packets: ::prost::alloc::vec::Vec<StreamPacket>,
+}
+/// code is the grpc status code of sending the packet, zero is ok
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PacketAck {
+    #[prost(uint64, tag = "1")]
+    pub seq: u64,
+    #[prost(int32, tag = "2")]
+    pub code: i32,
+    #[prost(string, tag = "3")]
+    pub message: ::prost::alloc::string::String,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AckBatch {
+    #[prost(message, repeated, tag = "1")]
+    pub acks: ::prost::alloc::vec::Vec<PacketAck>,
+}
+/// close the channel held by the remote router
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CloseRequest {
+    #[prost(string, tag = "1")]
+    pub channel_id: ::prost::alloc::string::String,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CloseReply {
+    #[prost(bool, tag = "1")]
+    pub closed: bool,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct BroadcastRequest {
+    #[prost(string, repeated, tag = "1")]
+    pub channel_ids: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(string, tag = "2")]
+    pub packet: ::prost::alloc::string::String,
+}
+/// error is empty when delivered
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Delivery {
+    #[prost(string, tag = "1")]
+    pub channel_id: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub error: ::prost::alloc::string::String,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct BroadcastReply {
+    #[prost(message, repeated, tag = "1")]
+    pub deliveries: ::prost::alloc::vec::Vec<Delivery>,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct UplinkRequest {
+    /// packets buffered for the subscriber
+    #[prost(uint32, tag = "1")]
+    pub capacity: u32,
+    /// wait for the subscriber when buffer is full, or drop the packet
+    #[prost(bool, tag = "2")]
+    pub block: bool,
+}
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct UplinkMessage {
+    #[prost(string, tag = "1")]
+    pub channel_id: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "2")]
+    pub router_id: u64,
+    /// milliseconds since unix epoch when the router received the packet
+    #[prost(int64, tag = "3")]
+    pub timestamp: i64,
+    #[prost(string, tag = "4")]
+    pub packet: ::prost::alloc::string::String,
+}
 /// Generated client implementations.
 pub mod router_service_client {
     #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
@@ -117,6 +216,118 @@ pub mod router_service_client {
             ));
             self.inner.unary(req, path, codec).await
         }
+        /// persistent stream between routers, every packet is acked with it's sequence
+        pub async fn stream_packets(
+            &mut self,
+            request: impl tonic::IntoStreamingRequest<Message = super::PacketBatch>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::AckBatch>>,
+            tonic::Status,
+        > {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path =
+                http::uri::PathAndQuery::from_static("/router_service.RouterService/StreamPackets");
+            let mut req = request.into_streaming_request();
+            req.extensions_mut().insert(GrpcMethod::new(
+                "router_service.RouterService",
+                "StreamPackets",
+            ));
+            self.inner.streaming(req, path, codec).await
+        }
+        /// send packet and wait for the device reply packet
+        pub async fn request_packet(
+            &mut self,
+            request: impl tonic::IntoRequest<super::RouterRequest>,
+        ) -> std::result::Result<tonic::Response<super::RouterReply>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path =
+                http::uri::PathAndQuery::from_static("/router_service.RouterService/RequestPacket");
+            let mut req = request.into_request();
+            req.extensions_mut().insert(GrpcMethod::new(
+                "router_service.RouterService",
+                "RequestPacket",
+            ));
+            self.inner.unary(req, path, codec).await
+        }
+        pub async fn close_channel(
+            &mut self,
+            request: impl tonic::IntoRequest<super::CloseRequest>,
+        ) -> std::result::Result<tonic::Response<super::CloseReply>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path =
+                http::uri::PathAndQuery::from_static("/router_service.RouterService/CloseChannel");
+            let mut req = request.into_request();
+            req.extensions_mut().insert(GrpcMethod::new(
+                "router_service.RouterService",
+                "CloseChannel",
+            ));
+            self.inner.unary(req, path, codec).await
+        }
+        /// send the packet to channels held by the router in one call
+        pub async fn broadcast_packet(
+            &mut self,
+            request: impl tonic::IntoRequest<super::BroadcastRequest>,
+        ) -> std::result::Result<tonic::Response<super::BroadcastReply>, tonic::Status> {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/router_service.RouterService/BroadcastPacket",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut().insert(GrpcMethod::new(
+                "router_service.RouterService",
+                "BroadcastPacket",
+            ));
+            self.inner.unary(req, path, codec).await
+        }
+        /// packets sent by devices connected to the router
+        pub async fn subscribe_uplink(
+            &mut self,
+            request: impl tonic::IntoRequest<super::UplinkRequest>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::UplinkMessage>>,
+            tonic::Status,
+        > {
+            self.inner.ready().await.map_err(|e| {
+                tonic::Status::new(
+                    tonic::Code::Unknown,
+                    format!("Service was not ready: {}", e.into()),
+                )
+            })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/router_service.RouterService/SubscribeUplink",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut().insert(GrpcMethod::new(
+                "router_service.RouterService",
+                "SubscribeUplink",
+            ));
+            self.inner.server_streaming(req, path, codec).await
+        }
     }
 }
 /// Generated server implementations.
@@ -130,6 +341,38 @@ pub mod router_service_server {
             &self,
             request: tonic::Request<super::RouterRequest>,
         ) -> std::result::Result<tonic::Response<super::RouterReply>, tonic::Status>;
+        /// Server streaming response type for the StreamPackets method.
+        type StreamPacketsStream: futures_core::Stream<Item = std::result::Result<super::AckBatch, tonic::Status>>
+            + Send
+            + 'static;
+        /// persistent stream between routers, every packet is acked with it's sequence
+        async fn stream_packets(
+            &self,
+            request: tonic::Request<tonic::Streaming<super::PacketBatch>>,
+        ) -> std::result::Result<tonic::Response<Self::StreamPacketsStream>, tonic::Status>;
+        /// send packet and wait for the device reply packet
+        async fn request_packet(
+            &self,
+            request: tonic::Request<super::RouterRequest>,
+        ) -> std::result::Result<tonic::Response<super::RouterReply>, tonic::Status>;
+        async fn close_channel(
+            &self,
+            request: tonic::Request<super::CloseRequest>,
+        ) -> std::result::Result<tonic::Response<super::CloseReply>, tonic::Status>;
+        /// send the packet to channels held by the router in one call
+        async fn broadcast_packet(
+            &self,
+            request: tonic::Request<super::BroadcastRequest>,
+        ) -> std::result::Result<tonic::Response<super::BroadcastReply>, tonic::Status>;
+        /// Server streaming response type for the SubscribeUplink method.
+        type SubscribeUplinkStream: futures_core::Stream<Item = std::result::Result<super::UplinkMessage, tonic::Status>>
+            + Send
+            + 'static;
+        /// packets sent by devices connected to the router
+        async fn subscribe_uplink(
+            &self,
+            request: tonic::Request<super::UplinkRequest>,
+        ) -> std::result::Result<tonic::Response<Self::SubscribeUplinkStream>, tonic::Status>;
     }
     #[derive(Debug)]
     pub struct RouterServiceServer<T: RouterService> {
@@ -245,6 +488,205 @@ pub mod router_service_server {
                     };
                     Box::pin(fut)
                 }
+                "/router_service.RouterService/StreamPackets" => {
+                    #[allow(non_camel_case_types)]
+                    struct StreamPacketsSvc<T: RouterService>(pub Arc<T>);
+                    impl<T: RouterService> tonic::server::StreamingService<super::PacketBatch> for StreamPacketsSvc<T> {
+                        type Response = super::AckBatch;
+                        type ResponseStream = T::StreamPacketsStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<tonic::Streaming<super::PacketBatch>>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move { (*inner).stream_packets(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = StreamPacketsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/router_service.RouterService/RequestPacket" => {
+                    #[allow(non_camel_case_types)]
+                    struct RequestPacketSvc<T: RouterService>(pub Arc<T>);
+                    impl<T: RouterService> tonic::server::UnaryService<super::RouterRequest> for RequestPacketSvc<T> {
+                        type Response = super::RouterReply;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::RouterRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move { (*inner).request_packet(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = RequestPacketSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/router_service.RouterService/CloseChannel" => {
+                    #[allow(non_camel_case_types)]
+                    struct CloseChannelSvc<T: RouterService>(pub Arc<T>);
+                    impl<T: RouterService> tonic::server::UnaryService<super::CloseRequest> for CloseChannelSvc<T> {
+                        type Response = super::CloseReply;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::CloseRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move { (*inner).close_channel(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = CloseChannelSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/router_service.RouterService/BroadcastPacket" => {
+                    #[allow(non_camel_case_types)]
+                    struct BroadcastPacketSvc<T: RouterService>(pub Arc<T>);
+                    impl<T: RouterService> tonic::server::UnaryService<super::BroadcastRequest>
+                        for BroadcastPacketSvc<T>
+                    {
+                        type Response = super::BroadcastReply;
+                        type Future = BoxFuture<tonic::Response<Self::Response>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::BroadcastRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move { (*inner).broadcast_packet(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = BroadcastPacketSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/router_service.RouterService/SubscribeUplink" => {
+                    #[allow(non_camel_case_types)]
+                    struct SubscribeUplinkSvc<T: RouterService>(pub Arc<T>);
+                    impl<T: RouterService>
+                        tonic::server::ServerStreamingService<super::UplinkRequest>
+                        for SubscribeUplinkSvc<T>
+                    {
+                        type Response = super::UplinkMessage;
+                        type ResponseStream = T::SubscribeUplinkStream;
+                        type Future =
+                            BoxFuture<tonic::Response<Self::ResponseStream>, tonic::Status>;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::UplinkRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move { (*inner).subscribe_uplink(request).await };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SubscribeUplinkSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 _ => Box::pin(async move {
                     Ok(http::Response::builder()
                         .status(200)
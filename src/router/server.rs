@@ -1,33 +1,69 @@
 // A grpc server that used for transfer income operation that need send packet to the remote.
+use crate::config::DeliveryConfig;
 use crate::protocol::packets::Packet;
 use crate::router::router_service::router_service_server::{RouterService, RouterServiceServer};
-use crate::router::router_service::{RouterReply, RouterRequest};
+use crate::router::router_service::{
+    AckBatch, BroadcastReply, BroadcastRequest, CloseReply, CloseRequest, Delivery, PacketAck,
+    PacketBatch, RouterReply, RouterRequest, StreamPacket, UplinkMessage, UplinkRequest,
+};
+use crate::router::uplink::{OverflowPolicy, UplinkHub};
 use crate::router::{RouterError, RouterId};
+use crate::security::ClusterSecurity;
 use crate::server::channel::ChannelId;
 use crate::server::session::SharedSession;
-use tonic::transport::Server;
-use tonic::{Request, Response, Status};
+use crate::server::ServerError;
+use futures::Stream;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tonic::{Code, Request, Response, Status, Streaming};
+use tracing::warn;
 
 pub struct RouterServer {
     id: RouterId,
     addr: String,
+    security: ClusterSecurity,
 }
 
 impl RouterServer {
-    pub fn new(id: RouterId, addr: String) -> RouterServer {
-        RouterServer { id, addr }
+    pub fn new(id: RouterId, addr: String, security: ClusterSecurity) -> RouterServer {
+        RouterServer { id, addr, security }
     }
 
     pub async fn start_router_server(
         &self,
         local_session: SharedSession,
+        uplink_hub: UplinkHub,
+        delivery: DeliveryConfig,
+        shutdown: Arc<Notify>,
+        drain_timeout: Duration,
     ) -> Result<(), RouterError> {
         let socket_addr = self.addr.as_str().parse()?;
-        let router_service = RouterSvc::new(local_session);
-        Server::builder()
-            .add_service(RouterServiceServer::new(router_service))
-            .serve(socket_addr)
-            .await?;
+        let router_service = RouterSvc::new(local_session, uplink_hub, delivery);
+        let (draining_sender, draining_receiver) = oneshot::channel();
+        let serve = self
+            .security
+            .server()?
+            .add_service(RouterServiceServer::with_interceptor(
+                router_service,
+                self.security.verifier(),
+            ))
+            .serve_with_shutdown(socket_addr, async move {
+                shutdown.notified().await;
+                let _ = draining_sender.send(());
+            });
+        // In-flight requests are completed before the server stopped, but uplink streams
+        // subscribed by other routers never finish themselves.
+        let drained = async {
+            let _ = draining_receiver.await;
+            tokio::time::sleep(drain_timeout).await;
+        };
+        select! {
+            served = serve => served?,
+            _ = drained => warn!("Router {} aborted requests not finished", self.id),
+        }
         Ok(())
     }
 }
@@ -35,14 +71,43 @@ impl RouterServer {
 #[derive(Debug)]
 pub struct RouterSvc {
     local_session: SharedSession,
+    uplink_hub: UplinkHub,
+    delivery: DeliveryConfig,
 }
 
 impl RouterSvc {
-    pub fn new(local_session: SharedSession) -> RouterSvc {
-        RouterSvc { local_session }
+    async fn send_local(
+        local_session: &SharedSession,
+        stream_packet: StreamPacket,
+    ) -> Result<(), Status> {
+        let packet = Packet::read(stream_packet.packet)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let channel_id = ChannelId::from(stream_packet.channel_id);
+        local_session
+            .send(&channel_id, packet)
+            .await
+            .map_err(into_status)
+    }
+
+    pub fn new(
+        local_session: SharedSession,
+        uplink_hub: UplinkHub,
+        delivery: DeliveryConfig,
+    ) -> RouterSvc {
+        RouterSvc {
+            local_session,
+            uplink_hub,
+            delivery,
+        }
     }
 }
 
+type UplinkStream = Pin<Box<dyn Stream<Item = Result<UplinkMessage, Status>> + Send>>;
+type AckStream = Pin<Box<dyn Stream<Item = Result<AckBatch, Status>> + Send>>;
+
+// Ack batches buffered for the sender router, stop reading packets when it's full.
+const ACK_CAPACITY: usize = 64;
+
 #[tonic::async_trait]
 impl RouterService for RouterSvc {
     async fn send_packet(
@@ -50,11 +115,150 @@ impl RouterService for RouterSvc {
         request: Request<RouterRequest>,
     ) -> Result<Response<RouterReply>, Status> {
         let request = request.into_inner();
-        let packet: Packet = Packet::read(request.packet).unwrap();
+        let packet: Packet = Packet::read(request.packet)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
         let channel_id = ChannelId::from(request.channel_id);
-        self.local_session.send(&channel_id, packet.clone()).await;
+        if request.at_least_once {
+            self.local_session
+                .deliver(&channel_id, packet, &self.delivery)
+                .await
+                .map_err(into_status)?;
+        } else {
+            self.local_session
+                .send(&channel_id, packet)
+                .await
+                .map_err(into_status)?;
+        }
         Ok(Response::new(RouterReply {
-            packet: packet.write().unwrap(),
+            packet: "".to_string(),
         }))
     }
+
+    type StreamPacketsStream = AckStream;
+
+    async fn stream_packets(
+        &self,
+        request: Request<Streaming<PacketBatch>>,
+    ) -> Result<Response<Self::StreamPacketsStream>, Status> {
+        let mut batches = request.into_inner();
+        let local_session = self.local_session.clone();
+        let (ack_sender, ack_receiver) = mpsc::channel(ACK_CAPACITY);
+        tokio::spawn(async move {
+            while let Ok(Some(batch)) = batches.message().await {
+                let mut acks = Vec::with_capacity(batch.packets.len());
+                for stream_packet in batch.packets {
+                    let seq = stream_packet.seq;
+                    let ack = match Self::send_local(&local_session, stream_packet).await {
+                        Ok(_) => PacketAck {
+                            seq,
+                            code: Code::Ok as i32,
+                            message: "".to_string(),
+                        },
+                        Err(status) => PacketAck {
+                            seq,
+                            code: status.code() as i32,
+                            message: status.message().to_string(),
+                        },
+                    };
+                    acks.push(ack);
+                }
+                if ack_sender.send(Ok(AckBatch { acks })).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let stream = futures::stream::unfold(ack_receiver, |mut ack_receiver| async move {
+            let ack_batch = ack_receiver.recv().await?;
+            Some((ack_batch, ack_receiver))
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn request_packet(
+        &self,
+        request: Request<RouterRequest>,
+    ) -> Result<Response<RouterReply>, Status> {
+        let request = request.into_inner();
+        let packet: Packet = Packet::read(request.packet)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let channel_id = ChannelId::from(request.channel_id);
+        let timeout = Duration::from_millis(request.timeout);
+        let reply = self
+            .local_session
+            .request(&channel_id, packet, timeout)
+            .await
+            .map_err(into_status)?;
+        Ok(Response::new(RouterReply {
+            packet: reply
+                .write()
+                .map_err(|err| Status::internal(err.to_string()))?,
+        }))
+    }
+
+    async fn close_channel(
+        &self,
+        request: Request<CloseRequest>,
+    ) -> Result<Response<CloseReply>, Status> {
+        let channel_id = ChannelId::from(request.into_inner().channel_id);
+        let closed = self.local_session.close(&channel_id).await.is_some();
+        Ok(Response::new(CloseReply { closed }))
+    }
+
+    async fn broadcast_packet(
+        &self,
+        request: Request<BroadcastRequest>,
+    ) -> Result<Response<BroadcastReply>, Status> {
+        let request = request.into_inner();
+        let packet: Packet = Packet::read(request.packet)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let mut deliveries = Vec::with_capacity(request.channel_ids.len());
+        for channel_id in request.channel_ids {
+            let error = match self
+                .local_session
+                .send(&ChannelId::from(channel_id.clone()), packet.clone())
+                .await
+            {
+                Ok(_) => "".to_string(),
+                Err(err) => err.to_string(),
+            };
+            deliveries.push(Delivery { channel_id, error });
+        }
+        Ok(Response::new(BroadcastReply { deliveries }))
+    }
+
+    type SubscribeUplinkStream = UplinkStream;
+
+    // Only packets of devices connected to this router, the subscriber merges all routers.
+    async fn subscribe_uplink(
+        &self,
+        request: Request<UplinkRequest>,
+    ) -> Result<Response<Self::SubscribeUplinkStream>, Status> {
+        let request = request.into_inner();
+        let policy = match request.block {
+            true => OverflowPolicy::Block,
+            false => OverflowPolicy::Drop,
+        };
+        let subscription = self.uplink_hub.subscribe(request.capacity as usize, policy);
+        let stream = futures::stream::unfold(subscription, |mut subscription| async move {
+            let uplink_packet = subscription.recv().await?;
+            let message = UplinkMessage {
+                channel_id: uplink_packet.channel_id.into(),
+                router_id: uplink_packet.router_id,
+                timestamp: uplink_packet.timestamp,
+                packet: uplink_packet.raw,
+            };
+            Some((Ok(message), subscription))
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+fn into_status(err: ServerError) -> Status {
+    match err {
+        ServerError::ChannelNotFound(_) => Status::not_found(err.to_string()),
+        ServerError::RequestTimeout(_) => Status::deadline_exceeded(err.to_string()),
+        ServerError::DeliveryFailed(_) => Status::deadline_exceeded(err.to_string()),
+        ServerError::UncorrelatedPacket(_) => Status::invalid_argument(err.to_string()),
+        _ => Status::unavailable(err.to_string()),
+    }
 }
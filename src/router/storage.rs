@@ -1,14 +1,54 @@
-use crate::router::{Key, RouterId, Value};
+use crate::router::offline::OfflinePacket;
+use crate::router::{Key, Router, RouterError, RouterId, Value};
+use crate::server::channel::ChannelId;
 use async_trait::async_trait;
 
 /// Define all state that need
 #[async_trait]
-pub trait RouterStorage: Clone {
-    // fetch channel in which router.
-    async fn get_channel_router(&self, key: Key) -> Value;
+pub trait RouterStorage: Clone + Send + Sync + 'static {
+    // fetch channel in which router, none if the channel never signed in.
+    async fn get_channel_router(&self, key: Key) -> Result<Option<Value>, RouterError>;
 
-    async fn update_or_insert_channel_node(&self, value: Value) -> Value;
+    async fn update_or_insert_channel_node(&self, value: Value) -> Result<Value, RouterError>;
 
-    // registry router
-    async fn router_lease(&self, router: RouterId) -> Option<RouterId>;
+    // all channels signed in the cluster, include the closed.
+    async fn list_channel_nodes(&self) -> Result<Vec<Value>, RouterError>;
+
+    // swap channel router only if the current router is expected, none expect the channel
+    // is absent. Return false when the swap is skipped.
+    async fn compare_and_swap_channel_node(
+        &self,
+        expect: Option<RouterId>,
+        value: Value,
+    ) -> Result<bool, RouterError>;
+
+    // registry router, other routers find it's address by the router id.
+    async fn router_lease(&self, router: Router) -> Result<(), RouterError>;
+
+    // remove the router from registry, when the router shutting down.
+    async fn router_release(&self, router_id: RouterId) -> Result<(), RouterError>;
+
+    // all routers registered in the cluster, include this router.
+    async fn list_routers(&self) -> Result<Vec<Router>, RouterError>;
+
+    // replace members of the static group, empty members remove the group.
+    async fn update_group(&self, group: String, members: Vec<ChannelId>)
+        -> Result<(), RouterError>;
+
+    // members of the static group, empty if the group not exists.
+    async fn get_group(&self, group: String) -> Result<Vec<ChannelId>, RouterError>;
+
+    // queue the packet sent to the offline channel, see `offline::enqueue`.
+    async fn push_offline_packet(
+        &self,
+        channel_id: ChannelId,
+        packet: OfflinePacket,
+        max_depth: usize,
+    ) -> Result<(), RouterError>;
+
+    // remove and return the packets queued for the channel in order.
+    async fn take_offline_packets(
+        &self,
+        channel_id: ChannelId,
+    ) -> Result<Vec<OfflinePacket>, RouterError>;
 }
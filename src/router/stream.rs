@@ -0,0 +1,237 @@
+// A persistent stream forwarding packets to a remote router, packets are sent in batches
+// and every packet waits for it's ack.
+use crate::protocol::packets::Packet;
+use crate::router::remote::ChannelPool;
+use crate::router::router_service::router_service_client::RouterServiceClient;
+use crate::router::router_service::{PacketAck, PacketBatch, StreamPacket};
+use crate::router::RouterError;
+use crate::security::ClusterChannel;
+use crate::server::channel::ChannelId;
+use egccri_future_util::{timeout_with, Backoff};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tonic::{Code, Status};
+use tracing::{info, warn};
+
+// Packets sent in one batch at most.
+const MAX_BATCH_SIZE: usize = 128;
+
+// Packets waiting for ack at most, senders wait when the window is full.
+const MAX_IN_FLIGHT: usize = 4096;
+
+// Wait for the ack of a packet at most.
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Jittered, so streams broken together are not reconnected together.
+const RECONNECT_BACKOFF: Backoff = Backoff::Exponential {
+    initial: Duration::from_millis(100),
+    max: Duration::from_secs(5),
+    jitter: true,
+};
+
+// Reconnects failed in a row before the stream is closed, like the router left the cluster.
+const MAX_RECONNECTS: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamState {
+    Connecting,
+    Streaming,
+    // The remote router not support streaming, unary call is used.
+    Unsupported,
+    // Reconnects exhausted or the stream removed, a new stream is opened by the next send.
+    Closed,
+}
+
+#[derive(Debug)]
+struct Outgoing {
+    channel_id: String,
+    packet: String,
+    ack: oneshot::Sender<Result<(), RouterError>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PacketStream {
+    sender: mpsc::Sender<Outgoing>,
+    state: Arc<Mutex<StreamState>>,
+    window: Arc<Semaphore>,
+}
+
+impl PacketStream {
+    /// Open the stream in background, reconnect when it's broken. Stopped when all the clones
+    /// dropped or the reconnects exhausted.
+    pub fn connect(router_addr: String, channels: ChannelPool) -> PacketStream {
+        let (sender, receiver) = mpsc::channel(MAX_IN_FLIGHT);
+        let state = Arc::new(Mutex::new(StreamState::Connecting));
+        tokio::spawn(Self::run(router_addr, channels, receiver, state.clone()));
+        PacketStream {
+            sender,
+            state,
+            window: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+        }
+    }
+
+    pub fn state(&self) -> StreamState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Send the packet and wait for the remote router acked it.
+    pub async fn send(&self, channel_id: ChannelId, packet: Packet) -> Result<(), RouterError> {
+        let raw = packet.write()?;
+        let _permit = self
+            .window
+            .acquire()
+            .await
+            .map_err(|_| RouterError::ChannelConnectError)?;
+        let (ack, ack_receiver) = oneshot::channel();
+        let outgoing = Outgoing {
+            channel_id: channel_id.into(),
+            packet: raw,
+            ack,
+        };
+        self.sender
+            .send(outgoing)
+            .await
+            .map_err(|_| RouterError::ChannelConnectError)?;
+        let acked = async {
+            match ack_receiver.await {
+                Ok(result) => result,
+                Err(_) => Err(RouterError::PacketStreamError(
+                    "stream broken before acked".to_string(),
+                )),
+            }
+        };
+        timeout_with(ACK_TIMEOUT, acked, || {
+            RouterError::PacketStreamError("wait for ack timeout".to_string())
+        })
+        .await
+    }
+
+    async fn run(
+        router_addr: String,
+        channels: ChannelPool,
+        mut receiver: mpsc::Receiver<Outgoing>,
+        state: Arc<Mutex<StreamState>>,
+    ) {
+        let mut reconnects = 0;
+        loop {
+            // Removed from the remotes while reconnecting.
+            if receiver.is_closed() {
+                *state.lock().unwrap() = StreamState::Closed;
+                return;
+            }
+            // Taken from the pool on every connect, so the channel is balanced and a dead one
+            // evicted by the pool is not reused.
+            let status = match channels.get(&router_addr).await {
+                Ok(channel) => {
+                    match Self::stream((*channel).clone(), &mut receiver, &state).await {
+                        // All senders dropped.
+                        Ok(()) => {
+                            *state.lock().unwrap() = StreamState::Closed;
+                            return;
+                        }
+                        Err(status) => status,
+                    }
+                }
+                Err(err) => Status::unavailable(err.to_string()),
+            };
+            if status.code() == Code::Unimplemented {
+                info!(
+                    "Router {} not support packet stream, fallback to unary",
+                    &router_addr
+                );
+                *state.lock().unwrap() = StreamState::Unsupported;
+                return;
+            }
+            let connected = *state.lock().unwrap() == StreamState::Streaming;
+            *state.lock().unwrap() = StreamState::Connecting;
+            warn!("Packet stream to {} broken: {}", &router_addr, status);
+            // Queued packets are failed, senders are not blocked until reconnected.
+            while let Ok(outgoing) = receiver.try_recv() {
+                let _ = outgoing.ack.send(Err(RouterError::PacketStreamError(
+                    status.message().to_string(),
+                )));
+            }
+            if connected {
+                reconnects = 0;
+            }
+            if reconnects >= MAX_RECONNECTS {
+                warn!(
+                    "Packet stream to {} closed after {} reconnects failed",
+                    &router_addr, reconnects
+                );
+                *state.lock().unwrap() = StreamState::Closed;
+                return;
+            }
+            tokio::time::sleep(RECONNECT_BACKOFF.delay(reconnects)).await;
+            reconnects += 1;
+        }
+    }
+
+    async fn stream(
+        channel: ClusterChannel,
+        receiver: &mut mpsc::Receiver<Outgoing>,
+        state: &Mutex<StreamState>,
+    ) -> Result<(), Status> {
+        let mut client = RouterServiceClient::new(channel);
+        let (batch_sender, batch_receiver) = mpsc::channel::<PacketBatch>(MAX_IN_FLIGHT);
+        let batches = futures::stream::unfold(batch_receiver, |mut batch_receiver| async move {
+            let batch = batch_receiver.recv().await?;
+            Some((batch, batch_receiver))
+        });
+        let mut acks = client.stream_packets(batches).await?.into_inner();
+        *state.lock().unwrap() = StreamState::Streaming;
+
+        let mut pending: HashMap<u64, oneshot::Sender<Result<(), RouterError>>> = HashMap::new();
+        let mut seq: u64 = 0;
+        loop {
+            select! {
+                outgoing = receiver.recv() => {
+                    let Some(outgoing) = outgoing else {
+                        return Ok(());
+                    };
+                    // Batch the packets queued at the moment.
+                    let mut packets = Vec::with_capacity(MAX_BATCH_SIZE);
+                    let mut next = Some(outgoing);
+                    while let Some(outgoing) = next.take() {
+                        seq += 1;
+                        pending.insert(seq, outgoing.ack);
+                        packets.push(StreamPacket {
+                            seq,
+                            channel_id: outgoing.channel_id,
+                            packet: outgoing.packet,
+                        });
+                        if packets.len() < MAX_BATCH_SIZE {
+                            next = receiver.try_recv().ok();
+                        }
+                    }
+                    if batch_sender.send(PacketBatch { packets }).await.is_err() {
+                        return Err(Status::unavailable("packet stream request closed"));
+                    }
+                }
+                ack_batch = acks.message() => {
+                    let Some(ack_batch) = ack_batch? else {
+                        return Err(Status::unavailable("packet stream closed by remote"));
+                    };
+                    for ack in ack_batch.acks {
+                        if let Some(ack_sender) = pending.remove(&ack.seq) {
+                            let _ = ack_sender.send(into_result(ack));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn into_result(ack: PacketAck) -> Result<(), RouterError> {
+    match Code::from(ack.code) {
+        Code::Ok => Ok(()),
+        code => Err(RouterError::ReplyErrorStatus(Status::new(
+            code,
+            ack.message,
+        ))),
+    }
+}
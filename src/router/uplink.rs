@@ -0,0 +1,193 @@
+use crate::protocol::packets::Packet;
+use crate::router::RouterId;
+use crate::server::channel::ChannelId;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+// Packets buffered for a subscriber not set the capacity.
+pub const DEFAULT_UPLINK_CAPACITY: usize = 1024;
+
+/// Packet sent by device, published by the router holding the device connection.
+#[derive(Debug, Clone)]
+pub struct UplinkPacket {
+    pub channel_id: ChannelId,
+    pub router_id: RouterId,
+    // milliseconds since unix epoch when the router received the packet
+    pub timestamp: i64,
+    pub raw: String,
+    pub packet: Packet,
+}
+
+impl UplinkPacket {
+    pub fn new(channel_id: ChannelId, router_id: RouterId, raw: String, packet: Packet) -> Self {
+        UplinkPacket {
+            channel_id,
+            router_id,
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            raw,
+            packet,
+        }
+    }
+}
+
+/// What to do when the buffer of a subscriber is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    // Drop the new packet and count it.
+    Drop,
+    // Wait for the subscriber, the device reader is blocked until then.
+    Block,
+}
+
+/// Sender side of a subscriber, cloned to every router stream feeding the subscriber.
+#[derive(Debug, Clone)]
+pub struct UplinkSink {
+    sender: mpsc::Sender<UplinkPacket>,
+    policy: OverflowPolicy,
+    dropped: Arc<AtomicU64>,
+}
+
+impl UplinkSink {
+    /// Return false when the subscriber has gone.
+    pub async fn deliver(&self, uplink_packet: UplinkPacket) -> bool {
+        match self.policy {
+            OverflowPolicy::Drop => match self.sender.try_send(uplink_packet) {
+                Ok(_) => true,
+                Err(TrySendError::Full(_)) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    true
+                }
+                Err(TrySendError::Closed(_)) => false,
+            },
+            OverflowPolicy::Block => self.sender.send(uplink_packet).await.is_ok(),
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    pub async fn closed(&self) {
+        self.sender.closed().await
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+}
+
+/// Local uplink packets fan out to the subscribers of this router.
+#[derive(Debug, Clone, Default)]
+pub struct UplinkHub {
+    subscribers: Arc<Mutex<HashMap<u64, UplinkSink>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl UplinkHub {
+    pub fn new() -> UplinkHub {
+        UplinkHub::default()
+    }
+
+    /// Subscriber holds a bounded buffer with capacity, the default is used when zero.
+    pub fn subscribe(&self, capacity: usize, policy: OverflowPolicy) -> UplinkSubscription {
+        let capacity = match capacity {
+            0 => DEFAULT_UPLINK_CAPACITY,
+            capacity => capacity,
+        };
+        let (sender, receiver) = mpsc::channel(capacity);
+        let sink = UplinkSink {
+            sender,
+            policy,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.subscribers.lock().unwrap().insert(id, sink.clone());
+        UplinkSubscription {
+            id,
+            receiver,
+            sink,
+            hub: self.clone(),
+        }
+    }
+
+    pub async fn publish(&self, uplink_packet: UplinkPacket) {
+        let sinks = self
+            .subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, sink)| (*id, sink.clone()))
+            .collect::<Vec<_>>();
+        for (id, sink) in sinks {
+            if !sink.deliver(uplink_packet.clone()).await {
+                self.unsubscribe(id);
+            }
+        }
+    }
+
+    fn unsubscribe(&self, id: u64) {
+        self.subscribers.lock().unwrap().remove(&id);
+    }
+}
+
+/// Receive uplink packets, unsubscribe when dropped.
+#[derive(Debug)]
+pub struct UplinkSubscription {
+    id: u64,
+    receiver: mpsc::Receiver<UplinkPacket>,
+    sink: UplinkSink,
+    hub: UplinkHub,
+}
+
+impl UplinkSubscription {
+    pub async fn recv(&mut self) -> Option<UplinkPacket> {
+        self.receiver.recv().await
+    }
+
+    /// Packets dropped because of the buffer is full.
+    pub fn dropped(&self) -> u64 {
+        self.sink.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn sink(&self) -> UplinkSink {
+        self.sink.clone()
+    }
+}
+
+impl Drop for UplinkSubscription {
+    fn drop(&mut self) {
+        self.hub.unsubscribe(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uplink_packet(seq: u32) -> UplinkPacket {
+        let raw = format!("3,{}", seq);
+        let packet = Packet::read(raw.clone()).unwrap();
+        UplinkPacket::new(ChannelId::from("client_id".to_string()), 1, raw, packet)
+    }
+
+    #[tokio::test]
+    async fn test_publish_drop_when_full() {
+        let hub = UplinkHub::new();
+        let mut subscription = hub.subscribe(1, OverflowPolicy::Drop);
+        hub.publish(uplink_packet(1)).await;
+        hub.publish(uplink_packet(2)).await;
+        assert_eq!(subscription.dropped(), 1);
+        assert_eq!(subscription.recv().await.unwrap().raw, "3,1");
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribe_when_dropped() {
+        let hub = UplinkHub::new();
+        let subscription = hub.subscribe(1, OverflowPolicy::Block);
+        drop(subscription);
+        assert!(hub.subscribers.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,267 @@
+// Secure grpc traffic between cluster nodes, with mutual tls and a shared secret. Applications
+// of the northbound api are authenticated by a token or client certificates.
+use crate::config::{ClusterSecurityConfig, TlsConfig};
+use crate::server::tls::ClientAuth;
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+use tonic::transport::{
+    Certificate, Channel, ClientTlsConfig, Endpoint, Identity, Server, ServerTlsConfig,
+};
+use tonic::{Request, Status};
+use tracing::warn;
+
+// Metadata key of the shared secret.
+const SECRET_KEY: &str = "x-cluster-secret";
+// Metadata key of the application token.
+const TOKEN_KEY: &str = "authorization";
+
+/// Channel to other nodes, the secret is attached to every request.
+pub type ClusterChannel = InterceptedService<Channel, SecretInterceptor>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum SecurityError {
+    #[error("Read {0} failed, cause by: {1}")]
+    ReadFileError(String, std::io::Error),
+
+    #[error("Cluster secret is not a valid metadata value.")]
+    InvalidSecret,
+
+    #[error("Northbound token is not a valid metadata value.")]
+    InvalidToken,
+
+    #[error(transparent)]
+    TransportError(#[from] tonic::transport::Error),
+}
+
+// Never printed in logs.
+#[derive(Clone)]
+struct Secret(MetadataValue<Ascii>);
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
+
+/// Shared by the router and raft servers and clients of this node.
+#[derive(Debug, Clone, Default)]
+pub struct ClusterSecurity {
+    server_tls: Option<ServerTlsConfig>,
+    client_tls: Option<ClientTlsConfig>,
+    secret: Option<Secret>,
+}
+
+impl ClusterSecurity {
+    /// Plaintext and unauthenticated when it's not configured.
+    pub fn new(config: Option<&ClusterSecurityConfig>) -> Result<ClusterSecurity, SecurityError> {
+        let Some(config) = config else {
+            warn!("Cluster security is not configured, nodes accept unauthenticated requests");
+            return Ok(ClusterSecurity::default());
+        };
+        let secret = match &config.secret {
+            Some(secret) => Some(Secret(
+                secret.parse().map_err(|_| SecurityError::InvalidSecret)?,
+            )),
+            None => None,
+        };
+        let (server_tls, client_tls) = match &config.tls {
+            Some(tls) => {
+                let ca = Certificate::from_pem(read(&tls.ca_path)?);
+                let identity = Identity::from_pem(read(&tls.cert_path)?, read(&tls.key_path)?);
+                // Peers must present a certificate signed by the cluster CA.
+                let server_tls = ServerTlsConfig::new()
+                    .identity(identity.clone())
+                    .client_ca_root(ca.clone());
+                let client_tls = ClientTlsConfig::new()
+                    .ca_certificate(ca)
+                    .identity(identity)
+                    .domain_name(tls.server_name.clone());
+                (Some(server_tls), Some(client_tls))
+            }
+            None => (None, None),
+        };
+        Ok(ClusterSecurity {
+            server_tls,
+            client_tls,
+            secret,
+        })
+    }
+
+    /// Builder of grpc servers serving other nodes.
+    pub fn server(&self) -> Result<Server, SecurityError> {
+        let server = Server::builder();
+        Ok(match &self.server_tls {
+            Some(server_tls) => server.tls_config(server_tls.clone())?,
+            None => server,
+        })
+    }
+
+    pub async fn connect(&self, addr: String) -> Result<ClusterChannel, tonic::transport::Error> {
+        let mut endpoint = Endpoint::new(addr)?;
+        if let Some(client_tls) = &self.client_tls {
+            endpoint = endpoint.tls_config(client_tls.clone())?;
+        }
+        let channel = endpoint.connect().await?;
+        Ok(InterceptedService::new(channel, self.interceptor()))
+    }
+
+    pub fn interceptor(&self) -> SecretInterceptor {
+        SecretInterceptor {
+            secret: self.secret.clone(),
+        }
+    }
+
+    pub fn verifier(&self) -> SecretVerifier {
+        SecretVerifier {
+            secret: self.secret.clone(),
+        }
+    }
+}
+
+/// Server tls of the northbound api, client certificates are verified by the client CA.
+pub fn server_tls(tls: &TlsConfig) -> Result<ServerTlsConfig, SecurityError> {
+    let identity = Identity::from_pem(read(&tls.cert_path)?, read(&tls.key_path)?);
+    let server_tls = ServerTlsConfig::new().identity(identity);
+    Ok(match (&tls.client_ca_path, tls.client_auth) {
+        (Some(client_ca_path), ClientAuth::Optional | ClientAuth::Required) => server_tls
+            .client_ca_root(Certificate::from_pem(read(client_ca_path)?))
+            .client_auth_optional(tls.client_auth == ClientAuth::Optional),
+        _ => server_tls,
+    })
+}
+
+fn read(path: &str) -> Result<Vec<u8>, SecurityError> {
+    fs::read(path).map_err(|err| SecurityError::ReadFileError(path.to_string(), err))
+}
+
+/// Attach the secret to requests sent to other nodes.
+#[derive(Debug, Clone)]
+pub struct SecretInterceptor {
+    secret: Option<Secret>,
+}
+
+impl Interceptor for SecretInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(secret) = &self.secret {
+            request.metadata_mut().insert(SECRET_KEY, secret.0.clone());
+        }
+        Ok(request)
+    }
+}
+
+/// Reject requests without the secret of the cluster.
+#[derive(Debug, Clone)]
+pub struct SecretVerifier {
+    secret: Option<Secret>,
+}
+
+impl Interceptor for SecretVerifier {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let Some(secret) = &self.secret else {
+            return Ok(request);
+        };
+        match request.metadata().get(SECRET_KEY) {
+            Some(presented) if constant_time_eq(presented.as_bytes(), secret.0.as_bytes()) => {
+                Ok(request)
+            }
+            _ => Err(Status::unauthenticated("invalid cluster secret")),
+        }
+    }
+}
+
+/// Reject requests without the bearer token of applications.
+#[derive(Debug, Clone)]
+pub struct TokenVerifier {
+    token: Option<Secret>,
+}
+
+impl TokenVerifier {
+    /// Requests are accepted without the token when it's not configured, like authenticated by
+    /// client certificates.
+    pub fn new(token: Option<&str>) -> Result<TokenVerifier, SecurityError> {
+        let token = match token {
+            Some(token) => Some(Secret(
+                format!("Bearer {}", token)
+                    .parse()
+                    .map_err(|_| SecurityError::InvalidToken)?,
+            )),
+            None => None,
+        };
+        Ok(TokenVerifier { token })
+    }
+}
+
+impl Interceptor for TokenVerifier {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let Some(token) = &self.token else {
+            return Ok(request);
+        };
+        match request.metadata().get(TOKEN_KEY) {
+            Some(presented) if constant_time_eq(presented.as_bytes(), token.0.as_bytes()) => {
+                Ok(request)
+            }
+            _ => Err(Status::unauthenticated("invalid token")),
+        }
+    }
+}
+
+// Compare without leaking the matched prefix length by timing.
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    if left.len() != right.len() {
+        return false;
+    }
+    left.iter()
+        .zip(right)
+        .fold(0u8, |diff, (left, right)| diff | (left ^ right))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn security(secret: &str) -> ClusterSecurity {
+        ClusterSecurity {
+            secret: Some(Secret(secret.parse().unwrap())),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_verify_secret_attached_by_interceptor() {
+        let security = security("secret");
+        let request = security.interceptor().call(Request::new(())).unwrap();
+        assert!(security.verifier().call(request).is_ok());
+    }
+
+    #[test]
+    fn test_reject_wrong_or_missing_secret() {
+        let request = self::security("other")
+            .interceptor()
+            .call(Request::new(()))
+            .unwrap();
+        let mut verifier = self::security("secret").verifier();
+        assert!(verifier.call(request).is_err());
+        assert!(verifier.call(Request::new(())).is_err());
+    }
+
+    #[test]
+    fn test_verify_bearer_token() {
+        let mut verifier = TokenVerifier::new(Some("token")).unwrap();
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert(TOKEN_KEY, "Bearer token".parse().unwrap());
+        assert!(verifier.call(request).is_ok());
+
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert(TOKEN_KEY, "Bearer other".parse().unwrap());
+        assert!(verifier.call(request).is_err());
+        assert!(verifier.call(Request::new(())).is_err());
+    }
+}
@@ -1,102 +1,224 @@
 use crate::config::ServerConfig;
-use crate::protocol::packets::Packet;
+use crate::protocol::codec::CodecError;
 use crate::protocol::PacketError;
 use crate::router::server::RouterServer;
-use crate::router::{RouterClient, RouterStorage};
+use crate::router::uplink::UplinkHub;
+use crate::router::{Router, RouterClient, RouterStorage};
+use crate::security::{ClusterSecurity, SecurityError};
+use crate::server::auth::DefaultAuthenticator;
 use crate::server::broker::BrokerServer;
+use crate::server::limits::Admission;
+use crate::server::listener::Listener;
 use crate::server::session::SharedSession;
+use crate::server::supervisor::{Restart, Supervisor};
 use crate::storage::raft::client::RaftClient;
 use crate::storage::raft::{RaftServer, RaftStorage};
+use pool::PoolConfig;
 use std::io;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::join;
 use tokio::sync::broadcast::Receiver;
-use tokio::sync::mpsc::Sender;
-use tokio_util::codec::LinesCodecError;
-use tracing::info;
+use tokio::sync::Notify;
+use tracing::{error, info};
 
+pub mod auth;
 mod broker;
 pub mod channel;
+pub mod limits;
+pub mod listener;
+pub mod outbound;
+pub mod proxy;
 pub mod session;
+pub mod supervisor;
+pub mod tls;
 
-pub struct Cluster {
-    broker: BrokerServer,
-    router: RouterServer,
-    #[cfg(feature = "raft-store")]
-    raft: RaftServer,
-}
+// Wait for the raft leader elected before serving devices.
+const RAFT_READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Restart grpc servers failed, like the address in use for a while.
+pub(crate) const SERVER_RESTART: Restart = Restart {
+    max_restarts: 5,
+    backoff: Duration::from_secs(1),
+};
 
+// Return the router client and the supervisor of broker, router server and raft tasks.
 pub async fn start(
     server_config: ServerConfig,
-    server_sender: Sender<Packet>,
     ctrl_c_rx: Receiver<()>,
-) -> Result<RouterClient<impl RouterStorage>, ServerSideError> {
+) -> Result<(RouterClient<impl RouterStorage>, Supervisor), ServerSideError> {
     let session = SharedSession::init().await;
+    let uplink_hub = UplinkHub::new();
+    let mut supervisor = Supervisor::new();
+    let security = ClusterSecurity::new(server_config.cluster_security.as_ref())?;
+    let channel_pool = server_config
+        .channel_pool
+        .as_ref()
+        .map(PoolConfig::from)
+        .unwrap_or_default();
 
-    // Raft node start
-    #[cfg(feature = "raft-store")]
-    if server_config.raft.is_none() {
-        panic!("Miss raft config when raft-store feature is turn on.")
-    }
-    let raft_config = server_config.raft.unwrap();
+    // Raft node start, the section is required by the config validation.
+    let Some(raft_config) = server_config.raft.as_ref() else {
+        return Err(ServerSideError::MissingConfig("raft"));
+    };
     let raft_node_id = raft_config.node_id;
     let raft_network_addr = raft_config.raft_network_addr.clone();
-    let mut raft_server = RaftServer::new(raft_node_id, raft_network_addr.clone());
-    let mut raft_server_clone = raft_server.clone();
+    let mut raft_server =
+        RaftServer::new(raft_config.clone(), security.clone(), channel_pool.clone());
     info!(
         "Raft Storage Server {} starting with cli config addr: {}",
         raft_node_id, raft_network_addr
     );
-    // FIXME error handle, add task handle
-    let raft_client = raft_server_clone.start().await.unwrap();
+    let raft_client = raft_server
+        .start()
+        .await
+        .map_err(|err| ServerSideError::RaftNotReady(err.to_string()))?;
+    let raft_api_server = raft_server.clone();
+    supervisor.spawn_restartable("raft api server", SERVER_RESTART, move || {
+        let raft_api_server = raft_api_server.clone();
+        async move { raft_api_server.serve_api().await }
+    });
 
     // Build a raft storage
     let raft_storage = RaftStorage::new(raft_client);
 
-    tokio::time::sleep(Duration::from_secs(2)).await;
+    // Routes can't be written until the leader elected.
+    let leader = raft_server
+        .wait_for_leader(RAFT_READY_TIMEOUT)
+        .await
+        .map_err(|err| ServerSideError::RaftNotReady(err.to_string()))?;
+    info!(
+        "Raft Storage Server {} is ready, leader: {}",
+        raft_node_id, leader
+    );
 
     // Router server side start for remote packet received.
     let router_id = server_config.router.router_id;
     let router_server_addr = server_config.router.router_server_addr.clone();
-    let router_server = RouterServer::new(router_id, router_server_addr.clone());
+    let router_server = Arc::new(RouterServer::new(
+        router_id,
+        router_server_addr.clone(),
+        security.clone(),
+    ));
     info!(
         "Router {} starting with cli config addr: {}",
         router_id, router_server_addr
     );
     let session_router = session.clone();
-    let router_task = tokio::spawn(async move {
-        // FIXME error handle
-        let _ = router_server.start_router_server(session_router).await;
+    let uplink_hub_router = uplink_hub.clone();
+    let delivery_router = server_config.delivery.clone();
+    let router_shutdown = Arc::new(Notify::new());
+    let router_server_shutdown = router_shutdown.clone();
+    let drain_timeout = Duration::from_millis(server_config.shutdown.drain_timeout);
+    supervisor.spawn_restartable("router server", SERVER_RESTART, move || {
+        let router_server = router_server.clone();
+        let session_router = session_router.clone();
+        let uplink_hub_router = uplink_hub_router.clone();
+        let delivery_router = delivery_router.clone();
+        let router_server_shutdown = router_server_shutdown.clone();
+        async move {
+            router_server
+                .start_router_server(
+                    session_router,
+                    uplink_hub_router,
+                    delivery_router,
+                    router_server_shutdown,
+                    drain_timeout,
+                )
+                .await
+        }
     });
 
     // Build a router client for top use
+    let listen_addresses = server_config
+        .listeners
+        .iter()
+        .map(|listener| listener.bind_address.as_str())
+        .collect::<Vec<_>>()
+        .join(",");
+    let router = Router::new(
+        router_id,
+        listen_addresses,
+        format!("http://{}", router_server_addr),
+    );
     let session_router_client = session.clone();
-    let router_client = RouterClient::new(router_id, session_router_client, raft_storage).await;
+    let takeover_timeout = Duration::from_millis(server_config.router.takeover_timeout);
+    let router_client = RouterClient::new(
+        router,
+        takeover_timeout,
+        server_config.offline_queue.clone(),
+        server_config.delivery.clone(),
+        session_router_client,
+        uplink_hub,
+        security,
+        channel_pool,
+        raft_storage,
+    )
+    .await;
+    if let Err(err) = router_client.register_router().await {
+        error!("Register router {} cause a error: {}", router_id, err);
+    }
 
     // Iot broker start
-    info!(
-        "Server starting with cli config addr: {:?}",
-        server_config.bind_address
-    );
-    let iot_server = BrokerServer::bind(
-        server_config.bind_address.as_str(),
+    let mut listeners = Vec::with_capacity(server_config.listeners.len());
+    for listener_config in &server_config.listeners {
+        let bind_client_id = listener_config
+            .tls
+            .as_ref()
+            .is_some_and(|tls| tls.bind_client_id);
+        let authenticator = Arc::new(DefaultAuthenticator::new(bind_client_id));
+        listeners.push(Listener::bind(listener_config, authenticator).await?);
+    }
+    let admission = Admission::new(server_config.limits.clone().unwrap_or_default());
+    let keep_alive = (server_config.router.keep_alive_timeout > 0)
+        .then(|| Duration::from_secs(server_config.router.keep_alive_timeout as u64));
+    let iot_server = BrokerServer::new(
+        listeners,
+        admission,
         ctrl_c_rx,
-        server_sender,
+        server_config.outbound.clone(),
+        server_config.shutdown.clone(),
+        keep_alive,
         session,
-    )
-    .await?;
-    let iot_server_task = tokio::spawn(async move {
+        router_client.clone(),
+    );
+
+    // Stop in order when the broker stopped: release routes left by aborted connections and
+    // this router, drain the router server, then stop the raft node.
+    let deregister_client = router_client.clone();
+    supervisor.spawn("broker", async move {
         iot_server.start().await;
+        if let Err(err) = deregister_client.deregister_router().await {
+            error!("Deregister router {} cause a error: {}", router_id, err);
+        }
+        router_shutdown.notify_one();
+        raft_server.shutdown().await;
+        Ok::<(), ServerSideError>(())
     });
-
-    join!(iot_server_task, router_task);
-    Ok(router_client)
+    Ok((router_client, supervisor))
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum ServerError {
     #[error("channel send error with I/O : {0}")]
     ChannelSendError(#[from] io::Error),
+
+    #[error("Channel {0} is not found in local session.")]
+    ChannelNotFound(String),
+
+    #[error("Channel {0} has been closed.")]
+    ChannelClosed(String),
+
+    #[error("Packet without correlation id can't be requested: {0}")]
+    UncorrelatedPacket(String),
+
+    #[error("Request channel {0} wait for reply timeout.")]
+    RequestTimeout(String),
+
+    #[error("Channel {0} not acked the packet after retransmitting.")]
+    DeliveryFailed(String),
+
+    #[error("Outbound queue of channel {0} is full, the packet is dropped.")]
+    OutboundQueueFull(String),
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -105,7 +227,7 @@ pub enum ServerSideError {
     ServerAcceptError(#[from] io::Error),
 
     #[error("Server codec error, cause by: {0}")]
-    ServerCodecError(#[from] LinesCodecError),
+    ServerCodecError(#[from] CodecError),
 
     #[error("Channel create fault with error: ...")]
     ChannelCreateError,
@@ -115,6 +237,27 @@ pub enum ServerSideError {
 
     #[error("New stream first packet is not 'sign_in': {0}")]
     FirstPacketError(String),
+
+    #[error("Tls error, cause by: {0}")]
+    TlsError(String),
+
+    #[error("Device authentication failed: {0}")]
+    AuthenticationFailed(String),
+
+    #[error("Cluster security error, cause by: {0}")]
+    SecurityError(#[from] SecurityError),
+
+    #[error("Missing [{0}] config.")]
+    MissingConfig(&'static str),
+
+    #[error("Raft storage is not ready, cause by: {0}")]
+    RaftNotReady(String),
+
+    #[error("Task {0} failed, cause by: {1}")]
+    TaskFailed(&'static str, String),
+
+    #[error("Task {0} panicked.")]
+    TaskPanicked(&'static str),
 }
 
 // FIXME split read and write packet, read should bu ClientSideError
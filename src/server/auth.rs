@@ -0,0 +1,93 @@
+// Authenticate devices when they sign in.
+use crate::server::tls::PeerIdentity;
+use crate::server::ServerSideError;
+use async_trait::async_trait;
+use std::fmt::Debug;
+use std::net::SocketAddr;
+
+/// Presented by the device with the sign in packet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+    pub client_id: String,
+    pub username: String,
+    pub password: String,
+    // Address of the device, given by the PROXY protocol header when the listener is behind a
+    // load balancer.
+    pub remote_address: SocketAddr,
+    // Identity of the client certificate when the device connected with tls.
+    pub peer: Option<PeerIdentity>,
+}
+
+/// Implement it to check the credentials of devices, the connection is closed when failed.
+#[async_trait]
+pub trait Authenticator: Debug + Send + Sync + 'static {
+    async fn authenticate(&self, credentials: &Credentials) -> Result<(), ServerSideError>;
+}
+
+/// Accept all devices, the client id must be the common name of the client certificate when
+/// the client id is bound.
+#[derive(Debug, Clone, Default)]
+pub struct DefaultAuthenticator {
+    bind_client_id: bool,
+}
+
+impl DefaultAuthenticator {
+    pub fn new(bind_client_id: bool) -> DefaultAuthenticator {
+        DefaultAuthenticator { bind_client_id }
+    }
+}
+
+#[async_trait]
+impl Authenticator for DefaultAuthenticator {
+    async fn authenticate(&self, credentials: &Credentials) -> Result<(), ServerSideError> {
+        if !self.bind_client_id {
+            return Ok(());
+        }
+        let common_name = credentials
+            .peer
+            .as_ref()
+            .and_then(|peer| peer.common_name.as_deref());
+        match common_name {
+            Some(common_name) if common_name == credentials.client_id => Ok(()),
+            _ => Err(ServerSideError::AuthenticationFailed(format!(
+                "client id {} is not the certificate common name {:?}",
+                credentials.client_id, common_name
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials(common_name: Option<&str>) -> Credentials {
+        Credentials {
+            client_id: "client_id".to_string(),
+            username: "username".to_string(),
+            password: "password".to_string(),
+            remote_address: "127.0.0.1:1883".parse().unwrap(),
+            peer: Some(PeerIdentity {
+                common_name: common_name.map(|common_name| common_name.to_string()),
+                subject: "CN=client_id".to_string(),
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_client_id_to_common_name() {
+        let authenticator = DefaultAuthenticator::new(true);
+        assert!(authenticator
+            .authenticate(&credentials(Some("client_id")))
+            .await
+            .is_ok());
+        assert!(authenticator
+            .authenticate(&credentials(Some("other")))
+            .await
+            .is_err());
+        assert!(authenticator
+            .authenticate(&credentials(None))
+            .await
+            .is_err());
+    }
+}
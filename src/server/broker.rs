@@ -1,146 +1,551 @@
+use crate::config::{OutboundConfig, ShutdownConfig};
+use crate::protocol::codec::{CodecError, FrameCodec};
 use crate::protocol::packets::Packet;
-use crate::server::channel::Channel;
+use crate::protocol::Protocol;
+use crate::router::uplink::UplinkPacket;
+use crate::router::{RouterClient, RouterStorage};
+use crate::server::auth::Credentials;
+use crate::server::channel::{Channel, ChannelId, ChannelStatus};
+use crate::server::limits::{Admission, InboundLimiter, LimitMetrics, Verdict};
+use crate::server::listener::{ConnectionPermit, Listener, ListenerContext};
+use crate::server::outbound::{OutboundMetrics, OutboundQueue};
+use crate::server::proxy::ProxyHeader;
 use crate::server::session::SharedSession;
+use crate::server::tls::{DeviceStream, ReloadSignal};
 use crate::server::ServerSideError;
-use futures_util::stream::{SplitSink, SplitStream};
+use egccri_future_util::{Timer, Timers};
+use futures_util::stream::{self, SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
 use std::net::SocketAddr;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{broadcast, mpsc};
-use tokio::{io, select};
-use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
-use tracing::{debug, error, info};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::select;
+use tokio::sync::broadcast;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tokio_util::codec::Framed;
+use tracing::{debug, error, info, warn};
 
+type DeviceFramed = Framed<DeviceStream, FrameCodec>;
+
+// Load balancers send the PROXY header right after connected.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Metrics since the process started are logged every interval and when the broker stopped.
+const METRICS_INTERVAL: Duration = Duration::from_secs(60);
+
+// Devices finish the tls handshake and send the sign in packet in it, or closed.
+const SIGN_IN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Numbers the connections admitted, keys of the sign in deadlines.
+static ADMITTED: AtomicU64 = AtomicU64::new(0);
+
+// Deadlines of all connections are in one timer wheel.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Deadline {
+    SignIn(u64),
+    KeepAlive(u64),
+}
+
+#[derive(Debug, Clone)]
+struct Deadlines {
+    timers: Timers<Deadline>,
+    keep_alive: Option<Duration>,
+}
+
+// Packets only update the last seen, the timer is inserted again for the rest when it fired
+// before the timeout, so nothing is sent to the timers per packet.
 #[derive(Debug)]
-pub struct BrokerServer {
-    listener: TcpListener,
-    codec: LinesCodec,
+struct KeepAlive {
+    timers: Timers<Deadline>,
+    connection_id: u64,
+    timeout: Duration,
+    last_seen: Instant,
+    timer: Timer<Deadline>,
+}
+
+impl KeepAlive {
+    fn new(deadlines: &Deadlines, connection_id: u64) -> Option<KeepAlive> {
+        let timeout = deadlines.keep_alive?;
+        Some(KeepAlive {
+            timers: deadlines.timers.clone(),
+            connection_id,
+            timeout,
+            last_seen: Instant::now(),
+            timer: deadlines
+                .timers
+                .insert(Deadline::KeepAlive(connection_id), timeout),
+        })
+    }
+
+    fn touch(&mut self) {
+        self.last_seen = Instant::now();
+    }
+
+    // Never if the keep alive is off.
+    async fn expired(keep_alive: &mut Option<KeepAlive>) {
+        let Some(keep_alive) = keep_alive else {
+            return std::future::pending().await;
+        };
+        loop {
+            (&mut keep_alive.timer).await;
+            let idle = keep_alive.last_seen.elapsed();
+            if idle >= keep_alive.timeout {
+                return;
+            }
+            keep_alive.timer = keep_alive.timers.insert(
+                Deadline::KeepAlive(keep_alive.connection_id),
+                keep_alive.timeout - idle,
+            );
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BrokerServer<Storage> {
+    listeners: Vec<Listener>,
+    admission: Admission,
     ctrl_c_rx: broadcast::Receiver<()>,
-    server_sender: mpsc::Sender<Packet>,
+    outbound: OutboundConfig,
+    shutdown: ShutdownConfig,
+    // None if the keep alive is off.
+    keep_alive: Option<Duration>,
     session: SharedSession,
+    router_client: RouterClient<Storage>,
 }
 
-impl BrokerServer {
-    pub async fn bind(
-        addr: &str,
+impl<Storage> BrokerServer<Storage>
+where
+    Storage: RouterStorage,
+{
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        listeners: Vec<Listener>,
+        admission: Admission,
         ctrl_c_rx: broadcast::Receiver<()>,
-        server_sender: mpsc::Sender<Packet>,
+        outbound: OutboundConfig,
+        shutdown: ShutdownConfig,
+        keep_alive: Option<Duration>,
         session: SharedSession,
-    ) -> io::Result<Self> {
-        let listener = TcpListener::bind(addr).await?;
-        Ok(BrokerServer {
-            listener,
-            codec: LinesCodec::new(),
+        router_client: RouterClient<Storage>,
+    ) -> Self {
+        BrokerServer {
+            listeners,
+            admission,
             ctrl_c_rx,
-            server_sender,
+            outbound,
+            shutdown,
+            keep_alive,
             session,
-        })
+            router_client,
+        }
     }
 
     pub async fn start(mut self) {
+        let mut connections = JoinSet::new();
+        let mut reload_signal = ReloadSignal::listen();
+        let deadlines = Deadlines {
+            timers: Timers::spawn(),
+            keep_alive: self.keep_alive,
+        };
+        let listeners = &self.listeners;
+        let tls_enabled = listeners
+            .iter()
+            .any(|listener| listener.context().tls.is_some());
+        // Connections accepted by all listeners, with the listener accepted it.
+        let mut accepts = stream::select_all(listeners.iter().map(|listener| {
+            stream::poll_fn(move |cx| {
+                listener
+                    .poll_accept(cx)
+                    .map(|accepted| Some((listener, accepted)))
+            })
+        }));
+        let mut metrics =
+            tokio::time::interval_at(Instant::now() + METRICS_INTERVAL, METRICS_INTERVAL);
         loop {
             select! {
                 _ = self.ctrl_c_rx.recv() => {
-                    info!("Stopping server broker listener at {}", chrono::Local::now());
+                    info!("Stopping server broker listeners at {}", chrono::Local::now());
                     break;
                 }
-                _ = Self::accept(self.codec.clone(), &self.listener, self.server_sender.clone(), self.session.clone()) => {}
+                Some((listener, accepted)) = accepts.next() => {
+                    let (socket, remote) = match accepted {
+                        Ok((tcp_stream, socket_address)) => (tcp_stream, socket_address),
+                        Err(err) => {
+                            error!("{}", ServerSideError::ServerAcceptError(err));
+                            continue;
+                        }
+                    };
+                    let Some(permit) = listener.admit() else {
+                        warn!(
+                            "Listener {} reached the max connections, close connection from {}",
+                            listener.name(),
+                            remote
+                        );
+                        continue;
+                    };
+                    connections.spawn(Self::accept(
+                        socket,
+                        remote,
+                        permit,
+                        listener.context().clone(),
+                        self.admission.clone(),
+                        deadlines.clone(),
+                        self.outbound.clone(),
+                        self.session.clone(),
+                        self.router_client.clone(),
+                    ));
+                }
+                _ = reload_signal.recv(), if tls_enabled => {
+                    for listener in listeners {
+                        listener.reload_tls();
+                    }
+                }
+                _ = metrics.tick() => Self::log_metrics(),
+                // Reap finished connections.
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
             }
         }
+        drop(accepts);
+        drop(self.listeners);
+        Self::drain(&self.session, &self.shutdown, connections).await;
+        Self::log_metrics();
         info!("Server broker has stopped!");
     }
 
+    fn log_metrics() {
+        info!("Outbound metrics: {:?}", OutboundMetrics::snapshot());
+        info!("Limit metrics: {:?}", LimitMetrics::snapshot());
+    }
+
+    // Close all connections and wait for them released the routes, connections not finished
+    // before the drain timeout are aborted.
+    async fn drain(
+        session: &SharedSession,
+        shutdown: &ShutdownConfig,
+        mut connections: JoinSet<()>,
+    ) {
+        let last_packet = shutdown
+            .notify_devices
+            .then(|| Packet::disconnect("shutdown"));
+        let closing = session.close_all(last_packet).await;
+        info!("Server broker closing {} connections", closing);
+
+        let drain_timeout = Duration::from_millis(shutdown.drain_timeout);
+        let drained = tokio::time::timeout(drain_timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                "Server broker aborted {} connections not closed in {:?}",
+                connections.len(),
+                drain_timeout
+            );
+            connections.shutdown().await;
+        }
+    }
+
+    // The permits are released when the connection closed.
+    #[allow(clippy::too_many_arguments)]
     async fn accept(
-        codec: LinesCodec,
-        listener: &TcpListener,
-        server_sender: mpsc::Sender<Packet>,
+        mut socket: TcpStream,
+        remote: SocketAddr,
+        _permit: ConnectionPermit,
+        context: ListenerContext,
+        admission: Admission,
+        deadlines: Deadlines,
+        outbound: OutboundConfig,
         session: SharedSession,
+        router_client: RouterClient<Storage>,
     ) {
-        let (socket, remote) = match listener.accept().await {
-            Ok((tcp_stream, socket_address)) => (tcp_stream, socket_address),
-            Err(err) => {
-                error!("{}", ServerSideError::ServerAcceptError(err));
-                return;
+        let protocol = context.protocol;
+        let remote = if context.proxy_protocol {
+            match ProxyHeader::read(&mut socket, PROXY_HEADER_TIMEOUT).await {
+                Ok(header) => {
+                    let source = header.source.unwrap_or(remote);
+                    debug!("Connection from {} proxied by {}", source, remote);
+                    source
+                }
+                Err(err) => {
+                    error!("Proxy header from {} rejected: {}", remote, err);
+                    return;
+                }
             }
+        } else {
+            remote
         };
-
-        let (framed_writer, mut framed_reader) = Framed::new(socket, codec).split();
+        let Some(_admitted) = admission.admit(remote.ip()) else {
+            warn!(
+                "Connection from {} rejected by the connection limits",
+                remote
+            );
+            return;
+        };
+        let mut sign_in_deadline = deadlines.timers.insert(
+            Deadline::SignIn(ADMITTED.fetch_add(1, Ordering::Relaxed)),
+            SIGN_IN_TIMEOUT,
+        );
+        let stream = match context.tls {
+            Some(tls) => {
+                let accepted = select! {
+                    accepted = tls.accept(socket) => accepted,
+                    _ = &mut sign_in_deadline => {
+                        warn!("Tls handshake with {} timeout", remote);
+                        return;
+                    }
+                };
+                match accepted {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        error!("Tls handshake with {} failed: {}", remote, err);
+                        return;
+                    }
+                }
+            }
+            None => DeviceStream::Plain(socket),
+        };
+        let peer = stream.peer_identity();
+        let (mut framed_writer, mut framed_reader) = Framed::new(stream, context.codec).split();
 
         // Some protocol maybe use sign packet message to create connections, like username, password etc.
-        // There only log sign in packet.
-        let first_packet = match Self::first_packet(&mut framed_reader).await {
+        // Only the client id is logged, the credentials are never written to the logs.
+        let first_packet = select! {
+            first_packet = Self::first_packet(&mut framed_reader, protocol) => first_packet,
+            _ = &mut sign_in_deadline => {
+                warn!("Device {} not signed in {:?}", remote, SIGN_IN_TIMEOUT);
+                return;
+            }
+        };
+        drop(sign_in_deadline);
+        let first_packet = match first_packet {
             Ok(first_packet) => first_packet,
             Err(err) => {
                 error!("{}", err);
                 return;
             }
         };
-        info!("A new client sign in: {:?}", first_packet);
+        let Packet::SignIn(sign_in) = first_packet else {
+            return;
+        };
+        info!(
+            "A new client {} sign in from listener {}, remote address {}",
+            sign_in.client_id, context.name, remote
+        );
+        let credentials = Credentials {
+            client_id: sign_in.client_id.clone(),
+            username: sign_in.username,
+            password: sign_in.password,
+            remote_address: remote,
+            peer,
+        };
+        if !admission.sign_in(remote.ip()).await {
+            warn!("Device {} sign in rejected by the rate limit", remote);
+            Self::reject(&mut framed_writer, protocol, "rate limited").await;
+            return;
+        }
+        if let Err(err) = context.authenticator.authenticate(&credentials).await {
+            error!("Device {} sign in rejected: {}", remote, err);
+            Self::reject(&mut framed_writer, protocol, "unauthorized").await;
+            return;
+        }
+        let channel_id = ChannelId::from(sign_in.client_id);
 
         // FIXME Channel should hold a heartbeat timer, used to update channel status.
         // FIXME And session need a background task to clear closed channel.
-        let (client_sender, client_receiver) = broadcast::channel::<Packet>(10);
+        let outbound_queue = OutboundQueue::new(outbound.capacity, outbound.overflow_policy);
 
-        let channel = match Self::create_channel(remote, client_sender) {
+        let channel = match Self::create_channel(channel_id.clone(), remote, outbound_queue.clone())
+        {
             Ok(channel) => channel,
             Err(err) => {
                 error!("{}", err);
                 return;
             }
         };
-        session.add(channel).await;
+        let connection_id = channel.connection_id();
+        // The same device signed in this node again, old connection is closed by session.
+        if let Some(replaced) = session.add(channel).await {
+            info!(
+                "Channel {} signed in again, close the old connection.",
+                replaced
+            );
+        }
+        if let Err(err) = router_client
+            .register_channel(channel_id.clone(), sign_in.tags)
+            .await
+        {
+            error!(
+                "Register channel {} router cause a error: {}",
+                &channel_id, err
+            );
+            session.close_connection(&channel_id, connection_id).await;
+            return;
+        }
 
+        // Packets sent when the device offline are delivered before others.
+        Self::deliver_offline(&router_client, &channel_id, &mut framed_writer, protocol).await;
+
+        let write_channel_id = channel_id.clone();
         let mut write_task = tokio::spawn(async move {
-            Self::handle_writeable(framed_writer, client_receiver).await;
+            Self::handle_writeable(framed_writer, outbound_queue, write_channel_id, protocol).await;
         });
 
-        let mut read_task = tokio::spawn(async {
-            Self::handle_readable(framed_reader, server_sender).await;
+        let read_session = session.clone();
+        let read_router_client = router_client.clone();
+        let read_channel_id = channel_id.clone();
+        let inbound_limiter = admission.inbound_limiter();
+        let keep_alive = KeepAlive::new(&deadlines, connection_id);
+        let mut read_task = tokio::spawn(async move {
+            Self::handle_readable(
+                framed_reader,
+                read_router_client,
+                read_session,
+                read_channel_id,
+                protocol,
+                inbound_limiter,
+                keep_alive,
+            )
+            .await;
         });
 
-        if tokio::try_join!(&mut write_task, &mut read_task).is_err() {
-            write_task.abort();
-            read_task.abort();
+        // Reader finished means the device disconnected, notice writer to close. Writer finished
+        // means the channel closed or the connection broken, stop reading. Router is released
+        // only when the channel not taken over by another connection.
+        let write_finished = select! {
+            _ = &mut read_task => false,
+            _ = &mut write_task => {
+                read_task.abort();
+                true
+            }
+        };
+        let owned = session
+            .close_connection(&channel_id, connection_id)
+            .await
+            .is_some();
+        if owned {
+            Self::release(&router_client, &channel_id, ChannelStatus::Closing).await;
+        }
+        if !write_finished {
+            let _ = write_task.await;
+        }
+        if owned {
+            Self::release(&router_client, &channel_id, ChannelStatus::Closed).await;
+        }
+        info!("Channel {} disconnected with {}", &channel_id, remote);
+    }
+
+    // Tell the device why before closing the connection.
+    async fn reject(
+        framed_writer: &mut SplitSink<DeviceFramed, String>,
+        protocol: Protocol,
+        reason: &str,
+    ) {
+        if let Ok(raw) = protocol.write(Packet::disconnect(reason)) {
+            let _ = framed_writer.send(raw).await;
+        }
+        let _ = framed_writer.close().await;
+    }
+
+    async fn release(
+        router_client: &RouterClient<Storage>,
+        channel_id: &ChannelId,
+        channel_status: ChannelStatus,
+    ) {
+        if let Err(err) = router_client
+            .release_channel(channel_id.clone(), channel_status)
+            .await
+        {
+            error!(
+                "Release channel {} router cause a error: {}",
+                channel_id, err
+            );
+        }
+    }
+
+    async fn deliver_offline(
+        router_client: &RouterClient<Storage>,
+        channel_id: &ChannelId,
+        framed_writer: &mut SplitSink<DeviceFramed, String>,
+        protocol: Protocol,
+    ) {
+        let packets = match router_client.take_offline_packets(channel_id.clone()).await {
+            Ok(packets) => packets,
+            Err(err) => {
+                error!(
+                    "Take offline packets of channel {} cause a error: {}",
+                    channel_id, err
+                );
+                return;
+            }
+        };
+        let total = packets.len();
+        for (delivered, packet) in packets.into_iter().enumerate() {
+            let raw = match protocol.write(packet) {
+                Ok(raw) => raw,
+                Err(err) => {
+                    error!("Packet write into raw cause a error: {}", err);
+                    continue;
+                }
+            };
+            if let Err(err) = framed_writer.send(raw).await {
+                error!(
+                    "Channel {} lost {} offline packets, cause by: {}",
+                    channel_id,
+                    total - delivered,
+                    err
+                );
+                return;
+            }
+        }
+        if total > 0 {
+            info!("Channel {} delivered {} offline packets", channel_id, total);
         }
     }
 
     async fn first_packet(
-        framed_reader: &mut SplitStream<Framed<TcpStream, LinesCodec>>,
+        framed_reader: &mut SplitStream<DeviceFramed>,
+        protocol: Protocol,
     ) -> Result<Packet, ServerSideError> {
-        let Some(frame) = framed_reader.next().await
-            else { return Err(ServerSideError::FirstPacketError("None".to_string())) };
+        let Some(frame) = framed_reader.next().await else {
+            return Err(ServerSideError::FirstPacketError("None".to_string()));
+        };
         let raw = match frame {
             Ok(raw) => raw,
             Err(err) => {
                 return Err(ServerSideError::ServerCodecError(err));
             }
         };
-        let is_first_packet = Packet::check_sign_in_packet(raw.as_str())?;
+        let is_first_packet = protocol.check_sign_in_packet(raw.as_str())?;
         if is_first_packet {
-            Ok(Packet::read(raw)?)
+            Ok(protocol.read(raw)?)
         } else {
             Err(ServerSideError::FirstPacketError(raw))
         }
     }
 
     fn create_channel(
+        channel_id: ChannelId,
         remote_address: SocketAddr,
-        client_sender: broadcast::Sender<Packet>,
+        outbound_queue: OutboundQueue,
     ) -> Result<Channel, ServerSideError> {
-        Ok(Channel::new(remote_address, client_sender))
+        Ok(Channel::new(channel_id, remote_address, outbound_queue))
     }
 
     async fn handle_writeable(
-        mut framed_writer: SplitSink<Framed<TcpStream, LinesCodec>, String>,
-        mut receiver: broadcast::Receiver<Packet>,
+        mut framed_writer: SplitSink<DeviceFramed, String>,
+        outbound_queue: OutboundQueue,
+        channel_id: ChannelId,
+        protocol: Protocol,
     ) {
-        while let Ok(packet) = receiver.recv().await {
+        while let Some(packet) = outbound_queue.recv().await {
             debug!("Channel try send packet: {}", &packet);
             if packet == Packet::Close(()) {
                 let _ = framed_writer.close().await;
                 break;
             }
-            let raw = match Packet::write(packet) {
+            let raw = match protocol.write(packet) {
                 Ok(raw) => raw,
                 Err(err) => {
                     error!("Packet write into raw cause a error: {}", err);
@@ -154,10 +559,10 @@ impl BrokerServer {
                 Err(err) => {
                     error!("Channel send packet with error: {err}");
                     match err {
-                        LinesCodecError::MaxLineLengthExceeded => {
+                        CodecError::FrameTooLong | CodecError::InvalidUtf8 => {
                             continue;
                         }
-                        LinesCodecError::Io(_) => {
+                        CodecError::Io(_) => {
                             let _ = framed_writer.close().await;
                             break;
                         }
@@ -165,13 +570,43 @@ impl BrokerServer {
                 }
             }
         }
+        if outbound_queue.is_closed() {
+            warn!(
+                "Channel {} is too slow, disconnected after {} packets dropped",
+                channel_id,
+                outbound_queue.dropped()
+            );
+            let _ = framed_writer.close().await;
+        }
+        outbound_queue.close();
     }
 
     async fn handle_readable(
-        mut framed_reader: SplitStream<Framed<TcpStream, LinesCodec>>,
-        server_sender: mpsc::Sender<Packet>,
+        mut framed_reader: SplitStream<DeviceFramed>,
+        router_client: RouterClient<Storage>,
+        session: SharedSession,
+        channel_id: ChannelId,
+        protocol: Protocol,
+        mut inbound_limiter: InboundLimiter,
+        mut keep_alive: Option<KeepAlive>,
     ) {
-        while let Some(frame) = framed_reader.next().await {
+        loop {
+            let frame = select! {
+                frame = framed_reader.next() => frame,
+                _ = KeepAlive::expired(&mut keep_alive) => {
+                    warn!(
+                        "Channel {} disconnected, nothing received in the keep alive timeout",
+                        channel_id
+                    );
+                    break;
+                }
+            };
+            let Some(frame) = frame else {
+                break;
+            };
+            if let Some(keep_alive) = &mut keep_alive {
+                keep_alive.touch();
+            }
             debug!("A new frame received: {:?}", &frame);
             let raw = match frame {
                 Ok(raw) => raw,
@@ -180,17 +615,31 @@ impl BrokerServer {
                     continue;
                 }
             };
-            match Packet::read(raw) {
-                Ok(packet) => match server_sender.send(packet).await {
-                    Ok(_) => {}
-                    Err(err) => {
-                        error!("Send packet to channel error: {:?}", err);
-                    }
-                },
+            match inbound_limiter.check(raw.len()).await {
+                Verdict::Pass => {}
+                Verdict::Drop => continue,
+                Verdict::Disconnect => {
+                    warn!(
+                        "Channel {} disconnected by the inbound rate limit",
+                        channel_id
+                    );
+                    break;
+                }
+            }
+            let packet = match protocol.read(raw.clone()) {
+                Ok(packet) => packet,
                 Err(err) => {
                     error!("Write raw string to packet cause a error: {}", err);
+                    continue;
                 }
-            }
+            };
+            // Reply packet is taken by the request waiting for it.
+            let Some(packet) = session.reply(&channel_id, packet) else {
+                continue;
+            };
+            let router_id = router_client.router().router_id();
+            let uplink_packet = UplinkPacket::new(channel_id.clone(), router_id, raw, packet);
+            router_client.publish_uplink(uplink_packet).await;
         }
     }
 }
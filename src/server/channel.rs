@@ -1,16 +1,22 @@
 use crate::protocol::packets::Packet;
+use crate::server::outbound::{OutboundQueue, Pushed};
+use crate::server::ServerError;
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
 use std::net::SocketAddr;
-use tokio::sync::broadcast::Sender;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::debug;
+
+static CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
 
 // RxPacket 用泛型灵活，但心智负担重，协议相关适合用枚举，因为协议是确定有限的
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Channel {
     channel_id: ChannelId,
+    // Unique in the process, distinguish connections signed in with the same channel id.
+    connection_id: u64,
     remote_address: SocketAddr,
-    #[allow(dead_code)]
-    rx: Sender<Packet>,
+    outbound: OutboundQueue,
     channel_status: ChannelStatus,
 }
 
@@ -36,12 +42,6 @@ impl Display for ChannelId {
     }
 }
 
-impl ChannelId {
-    pub fn generate() -> Self {
-        ChannelId { id: "".to_string() }
-    }
-}
-
 impl From<String> for ChannelId {
     fn from(value: String) -> Self {
         Self { id: value }
@@ -54,7 +54,7 @@ impl Into<String> for ChannelId {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ChannelStatus {
     Established,
     Closing,
@@ -78,11 +78,12 @@ impl Display for ChannelStatus {
 }
 
 impl Channel {
-    pub fn new(remote_address: SocketAddr, sender: Sender<Packet>) -> Self {
+    pub fn new(channel_id: ChannelId, remote_address: SocketAddr, outbound: OutboundQueue) -> Self {
         Channel {
-            channel_id: ChannelId::generate(),
+            channel_id,
+            connection_id: CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
             remote_address,
-            rx: sender,
+            outbound,
             channel_status: ChannelStatus::Established,
         }
     }
@@ -90,4 +91,38 @@ impl Channel {
     pub fn channel_id(&self) -> &ChannelId {
         &self.channel_id
     }
+
+    pub fn connection_id(&self) -> u64 {
+        self.connection_id
+    }
+
+    pub fn channel_status(&self) -> &ChannelStatus {
+        &self.channel_status
+    }
+
+    pub fn set_channel_status(&mut self, channel_status: ChannelStatus) {
+        self.channel_status = channel_status;
+    }
+
+    pub fn send(&self, packet: Packet) -> Result<(), ServerError> {
+        match self.outbound.push(packet) {
+            Pushed::Queued => Ok(()),
+            Pushed::DroppedOldest => {
+                debug!("Channel {} dropped the oldest packet", self.channel_id);
+                Ok(())
+            }
+            Pushed::DroppedNewest => {
+                Err(ServerError::OutboundQueueFull(self.channel_id.to_string()))
+            }
+            Pushed::Closed => Err(ServerError::ChannelClosed(self.channel_id.to_string())),
+        }
+    }
+
+    /// Send the packet bypassing the overflow policy, only before closing the channel.
+    pub fn send_last(&self, packet: Packet) -> Result<(), ServerError> {
+        match self.outbound.push_last(packet) {
+            Pushed::Closed => Err(ServerError::ChannelClosed(self.channel_id.to_string())),
+            _ => Ok(()),
+        }
+    }
 }
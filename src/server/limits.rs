@@ -0,0 +1,375 @@
+// Admission control of the broker, connection limits and token bucket rate limits.
+use crate::config::{LimitsConfig, RateLimitConfig};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Limited of all listeners in the process, see `LimitMetrics`.
+static CONNECTIONS_REJECTED: AtomicU64 = AtomicU64::new(0);
+static SIGN_INS_LIMITED: AtomicU64 = AtomicU64::new(0);
+static PACKETS_DROPPED: AtomicU64 = AtomicU64::new(0);
+static PACKETS_DELAYED: AtomicU64 = AtomicU64::new(0);
+static CHANNELS_DISCONNECTED: AtomicU64 = AtomicU64::new(0);
+
+// Sign in buckets of addresses kept at most before the refilled ones are pruned.
+const MAX_SIGN_IN_BUCKETS: usize = 4096;
+
+/// What to do when a rate limit is exceeded.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OverLimit {
+    // Drop the packet, or close the connection signing in.
+    #[default]
+    Reject,
+    // Wait for the tokens, the connection is not read while waiting.
+    Delay,
+    Disconnect,
+}
+
+/// Tokens are refilled at the rate per second, up to the burst.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Full when created, the rate is at least 1.
+    pub fn new(rate: u32, burst: u32, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate: rate.max(1) as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            updated: now,
+        }
+    }
+
+    /// Take the tokens only if there are enough.
+    pub fn try_take(&mut self, amount: f64, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < amount {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+
+    /// Take the tokens anyway, return how long to wait until they are refilled.
+    pub fn reserve(&mut self, amount: f64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= amount;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.burst
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Connections {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    config: LimitsConfig,
+    connections: Mutex<Connections>,
+    sign_ins: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+/// Shared by all listeners, limits are not applied if they're not configured.
+#[derive(Debug, Clone)]
+pub struct Admission {
+    inner: Arc<Inner>,
+}
+
+/// Held by the connection, the connection is not counted after dropped.
+#[derive(Debug)]
+pub struct AdmissionPermit {
+    admission: Admission,
+    ip: IpAddr,
+}
+
+impl Drop for AdmissionPermit {
+    fn drop(&mut self) {
+        let mut connections = self.admission.inner.connections.lock().unwrap();
+        connections.total -= 1;
+        if let Some(count) = connections.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl Admission {
+    pub fn new(config: LimitsConfig) -> Admission {
+        Admission {
+            inner: Arc::new(Inner {
+                config,
+                connections: Mutex::new(Connections::default()),
+                sign_ins: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// None if the max connections in total or of the address is reached.
+    pub fn admit(&self, ip: IpAddr) -> Option<AdmissionPermit> {
+        let config = &self.inner.config;
+        let mut connections = self.inner.connections.lock().unwrap();
+        let of_ip = connections.per_ip.get(&ip).copied().unwrap_or_default();
+        let reached = config
+            .max_connections
+            .is_some_and(|max_connections| connections.total >= max_connections)
+            || config
+                .max_connections_per_ip
+                .is_some_and(|max_connections| of_ip >= max_connections);
+        if reached {
+            CONNECTIONS_REJECTED.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        connections.total += 1;
+        *connections.per_ip.entry(ip).or_default() += 1;
+        Some(AdmissionPermit {
+            admission: self.clone(),
+            ip,
+        })
+    }
+
+    /// False if the sign in should be refused, waits when it's delayed.
+    pub async fn sign_in(&self, ip: IpAddr) -> bool {
+        let Some(limit) = &self.inner.config.sign_in_rate else {
+            return true;
+        };
+        let now = Instant::now();
+        let wait = {
+            let mut sign_ins = self.inner.sign_ins.lock().unwrap();
+            if sign_ins.len() >= MAX_SIGN_IN_BUCKETS {
+                sign_ins.retain(|_, bucket| !bucket.is_full(now));
+            }
+            let bucket = sign_ins
+                .entry(ip)
+                .or_insert_with(|| TokenBucket::new(limit.rate, limit.burst, now));
+            match limit.over_limit {
+                OverLimit::Delay => Some(bucket.reserve(1.0, now)),
+                OverLimit::Reject | OverLimit::Disconnect => {
+                    bucket.try_take(1.0, now).then_some(Duration::ZERO)
+                }
+            }
+        };
+        match wait {
+            Some(wait) if !wait.is_zero() => {
+                SIGN_INS_LIMITED.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(wait).await;
+                true
+            }
+            Some(_) => true,
+            None => {
+                SIGN_INS_LIMITED.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// Rate limits of the packets a channel sends.
+    pub fn inbound_limiter(&self) -> InboundLimiter {
+        let config = &self.inner.config;
+        let now = Instant::now();
+        InboundLimiter {
+            packets: config
+                .inbound_packet_rate
+                .as_ref()
+                .map(|limit| RateLimit::new(limit, now)),
+            bytes: config
+                .inbound_byte_rate
+                .as_ref()
+                .map(|limit| RateLimit::new(limit, now)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RateLimit {
+    bucket: TokenBucket,
+    over_limit: OverLimit,
+}
+
+impl RateLimit {
+    fn new(config: &RateLimitConfig, now: Instant) -> RateLimit {
+        RateLimit {
+            bucket: TokenBucket::new(config.rate, config.burst, now),
+            over_limit: config.over_limit,
+        }
+    }
+}
+
+/// Whether the received packet is handled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Pass,
+    Drop,
+    Disconnect,
+}
+
+/// Owned by the reader of a connection.
+#[derive(Debug)]
+pub struct InboundLimiter {
+    packets: Option<RateLimit>,
+    bytes: Option<RateLimit>,
+}
+
+impl InboundLimiter {
+    /// Waits when the limit exceeded is delayed.
+    pub async fn check(&mut self, bytes: usize) -> Verdict {
+        match Self::take(&mut self.packets, 1.0).await {
+            Verdict::Pass => Self::take(&mut self.bytes, bytes as f64).await,
+            verdict => verdict,
+        }
+    }
+
+    async fn take(limit: &mut Option<RateLimit>, amount: f64) -> Verdict {
+        let Some(limit) = limit else {
+            return Verdict::Pass;
+        };
+        let now = Instant::now();
+        match limit.over_limit {
+            OverLimit::Delay => {
+                let wait = limit.bucket.reserve(amount, now);
+                if !wait.is_zero() {
+                    PACKETS_DELAYED.fetch_add(1, Ordering::Relaxed);
+                    tokio::time::sleep(wait).await;
+                }
+                Verdict::Pass
+            }
+            _ if limit.bucket.try_take(amount, now) => Verdict::Pass,
+            OverLimit::Reject => {
+                PACKETS_DROPPED.fetch_add(1, Ordering::Relaxed);
+                Verdict::Drop
+            }
+            OverLimit::Disconnect => {
+                CHANNELS_DISCONNECTED.fetch_add(1, Ordering::Relaxed);
+                Verdict::Disconnect
+            }
+        }
+    }
+}
+
+/// Limited connections, sign ins and packets since the process started.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LimitMetrics {
+    pub connections_rejected: u64,
+    pub sign_ins_limited: u64,
+    pub packets_dropped: u64,
+    pub packets_delayed: u64,
+    pub channels_disconnected: u64,
+}
+
+impl LimitMetrics {
+    pub fn snapshot() -> LimitMetrics {
+        LimitMetrics {
+            connections_rejected: CONNECTIONS_REJECTED.load(Ordering::Relaxed),
+            sign_ins_limited: SIGN_INS_LIMITED.load(Ordering::Relaxed),
+            packets_dropped: PACKETS_DROPPED.load(Ordering::Relaxed),
+            packets_delayed: PACKETS_DELAYED.load(Ordering::Relaxed),
+            channels_disconnected: CHANNELS_DISCONNECTED.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate_limit(rate: u32, burst: u32, over_limit: OverLimit) -> Option<RateLimitConfig> {
+        Some(RateLimitConfig {
+            rate,
+            burst,
+            over_limit,
+        })
+    }
+
+    #[test]
+    fn test_token_bucket_refill() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10, 2, now);
+        assert!(bucket.try_take(1.0, now));
+        assert!(bucket.try_take(1.0, now));
+        assert!(!bucket.try_take(1.0, now));
+        assert!(bucket.try_take(1.0, now + Duration::from_millis(100)));
+        // Refilled up to the burst only.
+        assert!(bucket.try_take(2.0, now + Duration::from_secs(10)));
+        assert!(!bucket.try_take(1.0, now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_token_bucket_reserve() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10, 1, now);
+        assert_eq!(bucket.reserve(1.0, now), Duration::ZERO);
+        assert_eq!(bucket.reserve(1.0, now), Duration::from_millis(100));
+        assert_eq!(bucket.reserve(1.0, now), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let admission = Admission::new(LimitsConfig {
+            max_connections: Some(3),
+            max_connections_per_ip: Some(2),
+            ..Default::default()
+        });
+        let device: IpAddr = "192.168.0.1".parse().unwrap();
+        let other: IpAddr = "192.168.0.2".parse().unwrap();
+        let first = admission.admit(device).unwrap();
+        let _second = admission.admit(device).unwrap();
+        assert!(admission.admit(device).is_none());
+        let _third = admission.admit(other).unwrap();
+        assert!(admission.admit(other).is_none());
+        drop(first);
+        assert!(admission.admit(device).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_sign_in_rate_per_ip() {
+        let admission = Admission::new(LimitsConfig {
+            sign_in_rate: rate_limit(1, 1, OverLimit::Reject),
+            ..Default::default()
+        });
+        let device: IpAddr = "192.168.0.1".parse().unwrap();
+        assert!(admission.sign_in(device).await);
+        assert!(!admission.sign_in(device).await);
+        assert!(admission.sign_in("192.168.0.2".parse().unwrap()).await);
+    }
+
+    #[tokio::test]
+    async fn test_inbound_limits() {
+        let admission = Admission::new(LimitsConfig {
+            inbound_packet_rate: rate_limit(1, 2, OverLimit::Reject),
+            inbound_byte_rate: rate_limit(1, 10, OverLimit::Disconnect),
+            ..Default::default()
+        });
+        let mut limiter = admission.inbound_limiter();
+        assert_eq!(limiter.check(4).await, Verdict::Pass);
+        assert_eq!(limiter.check(8).await, Verdict::Disconnect);
+        assert_eq!(limiter.check(1).await, Verdict::Drop);
+    }
+}
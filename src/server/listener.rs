@@ -0,0 +1,114 @@
+// Device listeners, each with it's own address, framing, protocol, tls and connection limit.
+use crate::config::ListenerConfig;
+use crate::protocol::codec::FrameCodec;
+use crate::protocol::Protocol;
+use crate::server::auth::Authenticator;
+use crate::server::tls::ReloadableTlsAcceptor;
+use crate::server::ServerSideError;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{error, info};
+
+/// Shared by the connections accepted from the listener.
+#[derive(Debug, Clone)]
+pub struct ListenerContext {
+    pub name: String,
+    pub codec: FrameCodec,
+    pub protocol: Protocol,
+    // Read the PROXY protocol header before the tls handshake.
+    pub proxy_protocol: bool,
+    pub tls: Option<ReloadableTlsAcceptor>,
+    pub authenticator: Arc<dyn Authenticator>,
+}
+
+#[derive(Debug)]
+pub struct Listener {
+    listener: TcpListener,
+    context: ListenerContext,
+    // None if the connections are unlimited.
+    connections: Option<Arc<Semaphore>>,
+}
+
+/// Held by the connection until it's closed.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Listener {
+    pub async fn bind(
+        config: &ListenerConfig,
+        authenticator: Arc<dyn Authenticator>,
+    ) -> Result<Listener, ServerSideError> {
+        let tls = config
+            .tls
+            .clone()
+            .map(ReloadableTlsAcceptor::new)
+            .transpose()?;
+        let listener = TcpListener::bind(config.bind_address.as_str()).await?;
+        info!(
+            "Listener {} bound at {} with {:?} codec",
+            config.name, config.bind_address, config.codec
+        );
+        Ok(Listener {
+            listener,
+            context: ListenerContext {
+                name: config.name.clone(),
+                codec: FrameCodec::new(config.codec, config.max_frame_length),
+                protocol: config.protocol,
+                proxy_protocol: config.proxy_protocol,
+                tls,
+                authenticator,
+            },
+            connections: config
+                .max_connections
+                .map(|max_connections| Arc::new(Semaphore::new(max_connections))),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.context.name
+    }
+
+    pub fn context(&self) -> &ListenerContext {
+        &self.context
+    }
+
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(TcpStream, SocketAddr)>> {
+        self.listener.poll_accept(cx)
+    }
+
+    /// None if the listener has reached the max connections.
+    pub fn admit(&self) -> Option<ConnectionPermit> {
+        match &self.connections {
+            Some(connections) => {
+                connections
+                    .clone()
+                    .try_acquire_owned()
+                    .ok()
+                    .map(|permit| ConnectionPermit {
+                        _permit: Some(permit),
+                    })
+            }
+            None => Some(ConnectionPermit { _permit: None }),
+        }
+    }
+
+    pub fn reload_tls(&self) {
+        let Some(tls) = &self.context.tls else {
+            return;
+        };
+        match tls.reload() {
+            Ok(_) => info!("Listener {} reloaded tls certificates", self.name()),
+            Err(err) => error!(
+                "Listener {} reload tls certificates cause a error: {}",
+                self.name(),
+                err
+            ),
+        }
+    }
+}
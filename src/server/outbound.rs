@@ -0,0 +1,210 @@
+// Bounded queue of packets waiting to be written to a device connection.
+use crate::protocol::packets::Packet;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+// Drops of all channels in the process, see `OutboundMetrics`.
+static DROPPED_OLDEST: AtomicU64 = AtomicU64::new(0);
+static DROPPED_NEWEST: AtomicU64 = AtomicU64::new(0);
+static DISCONNECTED: AtomicU64 = AtomicU64::new(0);
+
+/// What to do when the queue of a slow device is full.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    DropOldest,
+    DropNewest,
+    // Close the connection of the slow device.
+    Disconnect,
+}
+
+/// Result of pushing a packet into the queue.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pushed {
+    Queued,
+    // Queued, but the oldest packet is dropped.
+    DroppedOldest,
+    DroppedNewest,
+    // The queue is closed, the packet is not queued.
+    Closed,
+}
+
+#[derive(Debug)]
+struct Inner {
+    packets: Mutex<VecDeque<Packet>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    notify: Notify,
+    closed: AtomicBool,
+    dropped: AtomicU64,
+}
+
+/// Cloned into the channel for pushing, and the writer of the connection for receiving.
+#[derive(Debug, Clone)]
+pub struct OutboundQueue {
+    inner: Arc<Inner>,
+}
+
+impl OutboundQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> OutboundQueue {
+        OutboundQueue {
+            inner: Arc::new(Inner {
+                packets: Mutex::new(VecDeque::with_capacity(capacity)),
+                capacity,
+                policy,
+                notify: Notify::new(),
+                closed: AtomicBool::new(false),
+                dropped: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Close packet is always queued, it's the last packet the writer received.
+    pub fn push(&self, packet: Packet) -> Pushed {
+        if self.is_closed() {
+            return Pushed::Closed;
+        }
+        let pushed = {
+            let mut packets = self.inner.packets.lock().unwrap();
+            if packets.len() < self.inner.capacity || packet == Packet::Close(()) {
+                packets.push_back(packet);
+                Pushed::Queued
+            } else {
+                match self.inner.policy {
+                    OverflowPolicy::DropOldest => {
+                        packets.pop_front();
+                        packets.push_back(packet);
+                        DROPPED_OLDEST.fetch_add(1, Ordering::Relaxed);
+                        Pushed::DroppedOldest
+                    }
+                    OverflowPolicy::DropNewest => {
+                        DROPPED_NEWEST.fetch_add(1, Ordering::Relaxed);
+                        Pushed::DroppedNewest
+                    }
+                    OverflowPolicy::Disconnect => {
+                        self.inner.closed.store(true, Ordering::Release);
+                        DISCONNECTED.fetch_add(1, Ordering::Relaxed);
+                        Pushed::Closed
+                    }
+                }
+            }
+        };
+        if matches!(pushed, Pushed::DroppedOldest | Pushed::DroppedNewest) {
+            self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.inner.notify.notify_one();
+        pushed
+    }
+
+    /// Queued even when the queue is full like the close packet, it's the notice to the device
+    /// right before closing the connection.
+    pub fn push_last(&self, packet: Packet) -> Pushed {
+        if self.is_closed() {
+            return Pushed::Closed;
+        }
+        self.inner.packets.lock().unwrap().push_back(packet);
+        self.inner.notify.notify_one();
+        Pushed::Queued
+    }
+
+    /// None when the queue is closed, the remaining packets are discarded.
+    pub async fn recv(&self) -> Option<Packet> {
+        loop {
+            if self.is_closed() {
+                return None;
+            }
+            if let Some(packet) = self.inner.packets.lock().unwrap().pop_front() {
+                return Some(packet);
+            }
+            self.inner.notify.notified().await;
+        }
+    }
+
+    pub fn close(&self) {
+        self.inner.closed.store(true, Ordering::Release);
+        self.inner.notify.notify_one();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.closed.load(Ordering::Acquire)
+    }
+
+    /// Packets dropped of this channel.
+    pub fn dropped(&self) -> u64 {
+        self.inner.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Drops of all channels since the process started.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OutboundMetrics {
+    pub dropped_oldest: u64,
+    pub dropped_newest: u64,
+    pub disconnected: u64,
+}
+
+impl OutboundMetrics {
+    pub fn snapshot() -> OutboundMetrics {
+        OutboundMetrics {
+            dropped_oldest: DROPPED_OLDEST.load(Ordering::Relaxed),
+            dropped_newest: DROPPED_NEWEST.load(Ordering::Relaxed),
+            disconnected: DISCONNECTED.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heartbeat(seq: u32) -> Packet {
+        Packet::read(format!("3,{}", seq)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_when_full() {
+        let queue = OutboundQueue::new(2, OverflowPolicy::DropOldest);
+        queue.push(heartbeat(1));
+        queue.push(heartbeat(2));
+        assert_eq!(queue.push(heartbeat(3)), Pushed::DroppedOldest);
+        assert_eq!(queue.recv().await, Some(heartbeat(2)));
+        assert_eq!(queue.recv().await, Some(heartbeat(3)));
+        assert_eq!(queue.dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_drop_newest_when_full() {
+        let queue = OutboundQueue::new(1, OverflowPolicy::DropNewest);
+        queue.push(heartbeat(1));
+        assert_eq!(queue.push(heartbeat(2)), Pushed::DroppedNewest);
+        assert_eq!(queue.recv().await, Some(heartbeat(1)));
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_when_full() {
+        let queue = OutboundQueue::new(1, OverflowPolicy::Disconnect);
+        queue.push(heartbeat(1));
+        assert_eq!(queue.push(heartbeat(2)), Pushed::Closed);
+        assert_eq!(queue.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_close_packet_queued_when_full() {
+        let queue = OutboundQueue::new(1, OverflowPolicy::DropNewest);
+        queue.push(heartbeat(1));
+        assert_eq!(queue.push(Packet::Close(())), Pushed::Queued);
+    }
+
+    #[tokio::test]
+    async fn test_last_packet_queued_when_full() {
+        let queue = OutboundQueue::new(1, OverflowPolicy::DropNewest);
+        queue.push(heartbeat(1));
+        assert_eq!(queue.push_last(heartbeat(2)), Pushed::Queued);
+        assert_eq!(queue.recv().await, Some(heartbeat(1)));
+        assert_eq!(queue.recv().await, Some(heartbeat(2)));
+        assert_eq!(queue.dropped(), 0);
+    }
+}
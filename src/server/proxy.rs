@@ -0,0 +1,263 @@
+// HAProxy PROXY protocol v1 and v2, sent by L4 load balancers before the device data.
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// The longest v1 header, with the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LENGTH: usize = 16;
+// Peeked again after it when the header is split into segments, the bytes peeked are returned
+// at once until more arrived.
+const PEEK_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(thiserror::Error, Debug)]
+pub enum ProxyError {
+    #[error("Read proxy header cause a error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Proxy header is not received in {0:?}.")]
+    Timeout(Duration),
+
+    #[error("Proxy header is incomplete.")]
+    Incomplete,
+
+    #[error("Invalid proxy header: {0}")]
+    Invalid(String),
+}
+
+/// Addresses of the connection between the device and the load balancer, none if the balancer
+/// connected by itself, like health checks, or the address family is not supported.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProxyHeader {
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+}
+
+impl ProxyHeader {
+    const LOCAL: ProxyHeader = ProxyHeader {
+        source: None,
+        destination: None,
+    };
+
+    /// Consume the header from the socket, the data after it is left to the codec. A header
+    /// split into segments is waited until complete or the timeout.
+    pub async fn read(
+        socket: &mut TcpStream,
+        timeout: Duration,
+    ) -> Result<ProxyHeader, ProxyError> {
+        tokio::time::timeout(timeout, async {
+            let mut prefix = [0u8; V1_MAX_LENGTH];
+            let length = loop {
+                let peeked = socket.peek(&mut prefix).await?;
+                if peeked == 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                match header_length(&prefix[..peeked]) {
+                    Err(ProxyError::Incomplete) => tokio::time::sleep(PEEK_INTERVAL).await,
+                    length => break length?,
+                }
+            };
+            let mut header = vec![0u8; length];
+            socket.read_exact(&mut header).await?;
+            ProxyHeader::parse(&header)
+        })
+        .await
+        .map_err(|_| ProxyError::Timeout(timeout))?
+    }
+
+    /// Parse a complete header, v1 or v2.
+    pub fn parse(header: &[u8]) -> Result<ProxyHeader, ProxyError> {
+        if header.starts_with(V2_SIGNATURE) {
+            parse_v2(header)
+        } else if header.starts_with(V1_PREFIX) {
+            parse_v1(header)
+        } else {
+            Err(ProxyError::Invalid("unknown signature".to_string()))
+        }
+    }
+}
+
+// Length of the header at the beginning of the bytes.
+fn header_length(prefix: &[u8]) -> Result<usize, ProxyError> {
+    if prefix.starts_with(V2_SIGNATURE) {
+        if prefix.len() < V2_HEADER_LENGTH {
+            return Err(ProxyError::Incomplete);
+        }
+        let length = u16::from_be_bytes([prefix[14], prefix[15]]) as usize;
+        Ok(V2_HEADER_LENGTH + length)
+    } else if prefix.starts_with(V1_PREFIX) {
+        match prefix.windows(2).position(|crlf| crlf == b"\r\n") {
+            Some(position) => Ok(position + 2),
+            None if prefix.len() >= V1_MAX_LENGTH => {
+                Err(ProxyError::Invalid("v1 header is too long".to_string()))
+            }
+            None => Err(ProxyError::Incomplete),
+        }
+    } else if V2_SIGNATURE.starts_with(prefix) || V1_PREFIX.starts_with(prefix) {
+        Err(ProxyError::Incomplete)
+    } else {
+        Err(ProxyError::Invalid("unknown signature".to_string()))
+    }
+}
+
+// PROXY TCP4|TCP6 <source ip> <destination ip> <source port> <destination port>\r\n
+// PROXY UNKNOWN ...\r\n
+fn parse_v1(header: &[u8]) -> Result<ProxyHeader, ProxyError> {
+    let invalid = || ProxyError::Invalid(String::from_utf8_lossy(header).to_string());
+    let line = std::str::from_utf8(header)
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .ok_or_else(invalid)?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(ProxyHeader::LOCAL),
+        ["PROXY", family @ ("TCP4" | "TCP6"), addresses @ ..] if addresses.len() == 4 => {
+            let address = |ip: &str, port: &str| -> Result<SocketAddr, ProxyError> {
+                let ip = ip.parse::<IpAddr>().map_err(|_| invalid())?;
+                if ip.is_ipv4() != (*family == "TCP4") {
+                    return Err(invalid());
+                }
+                Ok(SocketAddr::new(ip, port.parse().map_err(|_| invalid())?))
+            };
+            Ok(ProxyHeader {
+                source: Some(address(addresses[0], addresses[2])?),
+                destination: Some(address(addresses[1], addresses[3])?),
+            })
+        }
+        _ => Err(invalid()),
+    }
+}
+
+// 12 bytes signature, version and command, family and transport, u16 length, then addresses
+// and TLVs, which are ignored.
+fn parse_v2(header: &[u8]) -> Result<ProxyHeader, ProxyError> {
+    if header.len() < V2_HEADER_LENGTH {
+        return Err(ProxyError::Incomplete);
+    }
+    let version = header[12] >> 4;
+    let command = header[12] & 0x0f;
+    if version != 2 {
+        return Err(ProxyError::Invalid(format!("v2 version {}", version)));
+    }
+    let addresses = &header[V2_HEADER_LENGTH..];
+    match command {
+        // LOCAL, the addresses are ignored.
+        0x0 => Ok(ProxyHeader::LOCAL),
+        // PROXY
+        0x1 => match header[13] >> 4 {
+            // AF_INET
+            0x1 if addresses.len() >= 12 => Ok(ProxyHeader {
+                source: Some(v2_address(&addresses[0..4], &addresses[8..10])),
+                destination: Some(v2_address(&addresses[4..8], &addresses[10..12])),
+            }),
+            // AF_INET6
+            0x2 if addresses.len() >= 36 => Ok(ProxyHeader {
+                source: Some(v2_address(&addresses[0..16], &addresses[32..34])),
+                destination: Some(v2_address(&addresses[16..32], &addresses[34..36])),
+            }),
+            0x1 | 0x2 => Err(ProxyError::Invalid(
+                "v2 addresses are truncated".to_string(),
+            )),
+            // AF_UNSPEC and AF_UNIX
+            _ => Ok(ProxyHeader::LOCAL),
+        },
+        _ => Err(ProxyError::Invalid(format!("v2 command {}", command))),
+    }
+}
+
+fn v2_address(ip: &[u8], port: &[u8]) -> SocketAddr {
+    let ip = match ip.len() {
+        4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap())),
+        _ => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap())),
+    };
+    SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn test_parse_v1() {
+        let header = ProxyHeader::parse(b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 9990\r\n").unwrap();
+        assert_eq!(header.source, Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(header.destination, Some("10.0.0.1:9990".parse().unwrap()));
+
+        let header = ProxyHeader::parse(b"PROXY TCP6 ::1 ::2 56324 9990\r\n").unwrap();
+        assert_eq!(header.source, Some("[::1]:56324".parse().unwrap()));
+
+        let header = ProxyHeader::parse(b"PROXY UNKNOWN\r\n").unwrap();
+        assert_eq!(header, ProxyHeader::LOCAL);
+
+        assert!(ProxyHeader::parse(b"PROXY TCP4 ::1 ::2 56324 9990\r\n").is_err());
+        assert!(ProxyHeader::parse(b"PROXY TCP4 192.168.0.1 10.0.0.1 56324\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let addresses = [192, 168, 0, 1, 10, 0, 0, 1, 0xdc, 0x04, 0x27, 0x06];
+        let header = ProxyHeader::parse(&v2(0x1, 0x11, &addresses)).unwrap();
+        assert_eq!(header.source, Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(header.destination, Some("10.0.0.1:9990".parse().unwrap()));
+
+        let header = ProxyHeader::parse(&v2(0x0, 0x00, &[])).unwrap();
+        assert_eq!(header, ProxyHeader::LOCAL);
+
+        assert!(ProxyHeader::parse(&v2(0x1, 0x11, &addresses[..8])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_split_header() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sender = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"PROXY TCP4 192.168.0.1 ").await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            stream
+                .write_all(b"10.0.0.1 56324 9990\r\n1,")
+                .await
+                .unwrap();
+            stream
+        });
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let header = ProxyHeader::read(&mut socket, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(header.source, Some("192.168.0.1:56324".parse().unwrap()));
+        let mut rest = [0u8; 2];
+        socket.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"1,");
+        drop(sender.await.unwrap());
+    }
+
+    #[test]
+    fn test_header_length() {
+        let v1 = b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 9990\r\n1,client_id";
+        assert_eq!(header_length(v1).unwrap(), 44);
+        let v2 = v2(0x1, 0x11, &[0; 12]);
+        assert_eq!(header_length(&v2).unwrap(), 28);
+        assert!(matches!(
+            header_length(b"PROXY TCP4 192.168"),
+            Err(ProxyError::Incomplete)
+        ));
+        assert!(matches!(
+            header_length(b"1,client_id\n"),
+            Err(ProxyError::Invalid(_))
+        ));
+    }
+}
@@ -1,29 +1,315 @@
+use crate::config::DeliveryConfig;
 use crate::protocol::packets::Packet;
-use crate::server::channel::{Channel, ChannelId};
+use crate::server::channel::{Channel, ChannelId, ChannelStatus};
+use crate::server::ServerError;
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::{oneshot, Semaphore};
+use tracing::debug;
 
 #[derive(Debug, Clone)]
-pub struct SharedSession(Arc<RwLock<HashMap<String, Channel>>>);
+pub struct SharedSession {
+    channels: Arc<RwLock<HashMap<String, Channel>>>,
+    // Requests waiting for reply, key is channel id and correlation id of the request packet.
+    pending: Arc<Mutex<HashMap<(String, String), oneshot::Sender<Packet>>>>,
+    // Limit unacked packets of at least once delivery, key is channel id.
+    in_flight: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
 
 impl SharedSession {
     pub async fn init() -> Self {
-        Self(Arc::new(RwLock::new(
-            HashMap::<String, Channel>::with_capacity(4096),
-        )))
+        Self {
+            channels: Arc::new(RwLock::new(HashMap::<String, Channel>::with_capacity(4096))),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        }
     }
 
+    /// Remove the channel from session and notice the channel writer to close the connection.
     pub async fn close(&self, channel_id: &ChannelId) -> Option<Channel> {
-        None
+        let mut channel = self
+            .channels
+            .write()
+            .unwrap()
+            .remove(&channel_id.to_string())?;
+        self.close_in_flight(channel_id);
+        channel.set_channel_status(ChannelStatus::Closing);
+        let _ = channel.send(Packet::Close(()));
+        Some(channel)
+    }
+
+    pub async fn send(&self, channel_id: &ChannelId, packet: Packet) -> Result<(), ServerError> {
+        match self.channels.read().unwrap().get(&channel_id.to_string()) {
+            Some(channel) => channel.send(packet),
+            None => Err(ServerError::ChannelNotFound(channel_id.to_string())),
+        }
+    }
+
+    /// Send a packet to the channel and wait for the reply packet with the same correlation id.
+    pub async fn request(
+        &self,
+        channel_id: &ChannelId,
+        packet: Packet,
+        timeout: Duration,
+    ) -> Result<Packet, ServerError> {
+        let correlation_id = packet
+            .correlation_id()
+            .ok_or_else(|| ServerError::UncorrelatedPacket(packet.to_string()))?;
+        let key = (channel_id.to_string(), correlation_id);
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(key.clone(), reply_sender);
+
+        if let Err(err) = self.send(channel_id, packet).await {
+            self.pending.lock().unwrap().remove(&key);
+            return Err(err);
+        }
+        let result = tokio::time::timeout(timeout, reply_receiver).await;
+        self.pending.lock().unwrap().remove(&key);
+        match result {
+            Ok(Ok(reply)) => Ok(reply),
+            Ok(Err(_)) => Err(ServerError::ChannelClosed(channel_id.to_string())),
+            Err(_) => Err(ServerError::RequestTimeout(channel_id.to_string())),
+        }
+    }
+
+    /// At least once, the packet is retransmitted until the device acked it's correlation id
+    /// or retransmitting exhausted. Senders wait when the in-flight window of channel is full.
+    pub async fn deliver(
+        &self,
+        channel_id: &ChannelId,
+        packet: Packet,
+        delivery: &DeliveryConfig,
+    ) -> Result<(), ServerError> {
+        let correlation_id = packet
+            .correlation_id()
+            .ok_or_else(|| ServerError::UncorrelatedPacket(packet.to_string()))?;
+        // Created with the channel read locked, so it's removed when the channel closed.
+        let window = {
+            let channels = self.channels.read().unwrap();
+            if !channels.contains_key(&channel_id.to_string()) {
+                return Err(ServerError::ChannelClosed(channel_id.to_string()));
+            }
+            self.in_flight
+                .lock()
+                .unwrap()
+                .entry(channel_id.to_string())
+                .or_insert_with(|| Arc::new(Semaphore::new(delivery.in_flight_window)))
+                .clone()
+        };
+        let _permit = window
+            .acquire_owned()
+            .await
+            .map_err(|_| ServerError::ChannelClosed(channel_id.to_string()))?;
+
+        let key = (channel_id.to_string(), correlation_id);
+        let (ack_sender, mut ack_receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(key.clone(), ack_sender);
+        let ack_timeout = Duration::from_millis(delivery.ack_timeout);
+        let mut result = Err(ServerError::DeliveryFailed(channel_id.to_string()));
+        for attempt in 0..=delivery.max_retransmits {
+            if attempt > 0 {
+                debug!("Retransmit packet to channel {}: {}", channel_id, attempt);
+            }
+            if let Err(err) = self.send(channel_id, packet.clone()).await {
+                result = Err(err);
+                break;
+            }
+            match tokio::time::timeout(ack_timeout, &mut ack_receiver).await {
+                Ok(Ok(_)) => {
+                    result = Ok(());
+                    break;
+                }
+                Ok(Err(_)) => {
+                    result = Err(ServerError::ChannelClosed(channel_id.to_string()));
+                    break;
+                }
+                Err(_) => {}
+            }
+        }
+        self.pending.lock().unwrap().remove(&key);
+        result
+    }
+
+    // Senders waiting for the window of the closed channel are failed.
+    fn close_in_flight(&self, channel_id: &ChannelId) {
+        if let Some(window) = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .remove(&channel_id.to_string())
+        {
+            window.close();
+        }
+    }
+
+    /// Complete the request waiting for the packet, return the packet back if no one waiting.
+    pub fn reply(&self, channel_id: &ChannelId, packet: Packet) -> Option<Packet> {
+        let Some(correlation_id) = packet.correlation_id() else {
+            return Some(packet);
+        };
+        let key = (channel_id.to_string(), correlation_id);
+        match self.pending.lock().unwrap().remove(&key) {
+            Some(reply_sender) => reply_sender.send(packet).err(),
+            None => Some(packet),
+        }
+    }
+
+    /// Remove the channel only if it still holds the connection, the channel maybe replaced
+    /// by a new connection signed in with the same channel id.
+    pub async fn close_connection(
+        &self,
+        channel_id: &ChannelId,
+        connection_id: u64,
+    ) -> Option<Channel> {
+        let mut channels = self.channels.write().unwrap();
+        match channels.get(&channel_id.to_string()) {
+            Some(channel) if channel.connection_id() == connection_id => {}
+            _ => return None,
+        }
+        let mut channel = channels.remove(&channel_id.to_string())?;
+        self.close_in_flight(channel_id);
+        channel.set_channel_status(ChannelStatus::Closing);
+        let _ = channel.send(Packet::Close(()));
+        Some(channel)
     }
 
-    pub async fn send(&self, channel_id: &ChannelId, packet: Packet) {}
+    /// Add a channel into session, the old connection with the same channel id is noticed to
+    /// close and returned.
+    pub async fn add(&self, channel: Channel) -> Option<Channel> {
+        let mut replaced = self
+            .channels
+            .write()
+            .unwrap()
+            .insert(channel.channel_id().to_string(), channel)?;
+        replaced.set_channel_status(ChannelStatus::Closing);
+        let _ = replaced.send(Packet::Close(()));
+        Some(replaced)
+    }
 
-    pub async fn add(&self, channel: Channel) {}
+    /// Notice writers of all channels to close the connections, the last packet is sent before
+    /// closing if given, even if the outbound queue is full. Channels are removed by the
+    /// connections when they closed.
+    pub async fn close_all(&self, last_packet: Option<Packet>) -> usize {
+        let channels = self.channels.read().unwrap();
+        for channel in channels.values() {
+            if let Some(last_packet) = &last_packet {
+                let _ = channel.send_last(last_packet.clone());
+            }
+            let _ = channel.send(Packet::Close(()));
+        }
+        channels.len()
+    }
 
     pub async fn find(&self, channel_id: &ChannelId) -> Option<Channel> {
-        None
+        self.channels
+            .read()
+            .unwrap()
+            .get(&channel_id.to_string())
+            .cloned()
+    }
+
+    pub async fn clear_closed_channel(&self) {
+        self.channels
+            .write()
+            .unwrap()
+            .retain(|_, channel| *channel.channel_status() != ChannelStatus::Closed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::outbound::{OutboundQueue, OverflowPolicy};
+
+    fn command(message_id: &str) -> Packet {
+        Packet::read(format!("4,client_id,{},payload", message_id)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_deliver_retransmit_until_acked() {
+        let session = SharedSession::init().await;
+        let channel_id = ChannelId::from("client_id".to_string());
+        let outbound_queue = OutboundQueue::new(10, OverflowPolicy::DropNewest);
+        let channel = Channel::new(
+            channel_id.clone(),
+            "127.0.0.1:1883".parse().unwrap(),
+            outbound_queue.clone(),
+        );
+        session.add(channel).await;
+
+        // Device acks the second transmission only.
+        let device_session = session.clone();
+        let device_channel_id = channel_id.clone();
+        let device = tokio::spawn(async move {
+            let mut received = 0;
+            while outbound_queue.recv().await.is_some() {
+                received += 1;
+                if received == 2 {
+                    let ack = Packet::read("5,client_id,1".to_string()).unwrap();
+                    device_session.reply(&device_channel_id, ack);
+                    return received;
+                }
+            }
+            received
+        });
+
+        let delivery = DeliveryConfig {
+            ack_timeout: 50,
+            max_retransmits: 3,
+            in_flight_window: 1,
+        };
+        let result = session.deliver(&channel_id, command("1"), &delivery).await;
+        assert!(result.is_ok());
+        assert_eq!(device.await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_deliver_failed_when_not_acked() {
+        let session = SharedSession::init().await;
+        let channel_id = ChannelId::from("client_id".to_string());
+        let outbound_queue = OutboundQueue::new(10, OverflowPolicy::DropNewest);
+        let channel = Channel::new(
+            channel_id.clone(),
+            "127.0.0.1:1883".parse().unwrap(),
+            outbound_queue,
+        );
+        session.add(channel).await;
+
+        let delivery = DeliveryConfig {
+            ack_timeout: 10,
+            max_retransmits: 1,
+            in_flight_window: 1,
+        };
+        let result = session.deliver(&channel_id, command("1"), &delivery).await;
+        assert!(matches!(result, Err(ServerError::DeliveryFailed(_))));
     }
 
-    pub async fn clear_closed_channel(&self) {}
+    #[tokio::test]
+    async fn test_deliver_to_closed_channel() {
+        let session = SharedSession::init().await;
+        let channel_id = ChannelId::from("client_id".to_string());
+        let outbound_queue = OutboundQueue::new(10, OverflowPolicy::DropNewest);
+        let channel = Channel::new(
+            channel_id.clone(),
+            "127.0.0.1:1883".parse().unwrap(),
+            outbound_queue,
+        );
+        session.add(channel).await;
+        session.close(&channel_id).await;
+
+        let delivery = DeliveryConfig {
+            ack_timeout: 10,
+            max_retransmits: 1,
+            in_flight_window: 1,
+        };
+        for channel_id in [channel_id, ChannelId::from("unknown".to_string())] {
+            let result = session.deliver(&channel_id, command("1"), &delivery).await;
+            assert!(matches!(result, Err(ServerError::ChannelClosed(_))));
+        }
+        assert!(session.in_flight.lock().unwrap().is_empty());
+    }
 }
@@ -0,0 +1,141 @@
+// Own the tasks of server subsystems, the process stops when any of them failed.
+use crate::server::ServerSideError;
+use futures::FutureExt;
+use std::fmt::Display;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tracing::{error, info, warn};
+
+/// Restart a failed task after the backoff, grows with the restarts.
+#[derive(Debug, Clone, Copy)]
+pub struct Restart {
+    pub max_restarts: usize,
+    pub backoff: Duration,
+}
+
+#[derive(Debug, Default)]
+pub struct Supervisor {
+    tasks: JoinSet<Result<(), ServerSideError>>,
+}
+
+impl Supervisor {
+    pub fn new() -> Supervisor {
+        Supervisor {
+            tasks: JoinSet::new(),
+        }
+    }
+
+    /// Error or panic of the task is fatal.
+    pub fn spawn<F, E>(&mut self, name: &'static str, task: F)
+    where
+        F: Future<Output = Result<(), E>> + Send + 'static,
+        E: Display + 'static,
+    {
+        self.tasks.spawn(Self::run(name, task));
+    }
+
+    /// The task created by factory is restarted when it failed or panicked, fatal after the
+    /// restarts exhausted.
+    pub fn spawn_restartable<M, F, E>(&mut self, name: &'static str, restart: Restart, factory: M)
+    where
+        M: Fn() -> F + Send + 'static,
+        F: Future<Output = Result<(), E>> + Send + 'static,
+        E: Display + 'static,
+    {
+        self.tasks.spawn(async move {
+            let mut restarts = 0;
+            loop {
+                match Self::run(name, factory()).await {
+                    Ok(()) => return Ok(()),
+                    Err(err) if restarts < restart.max_restarts => {
+                        restarts += 1;
+                        warn!(
+                            "Task {} restarting {}/{}, cause by: {}",
+                            name, restarts, restart.max_restarts, err
+                        );
+                        tokio::time::sleep(restart.backoff * restarts as u32).await;
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        });
+    }
+
+    /// Wait for all tasks finished, return the first fatal error and abort the others.
+    pub async fn wait(mut self) -> Result<(), ServerSideError> {
+        while let Some(joined) = self.tasks.join_next().await {
+            let result = match joined {
+                Ok(result) => result,
+                // Only when the supervisor is aborted.
+                Err(err) => Err(ServerSideError::TaskFailed("supervisor", err.to_string())),
+            };
+            if let Err(err) = result {
+                error!("Server stopping with a fatal error: {}", err);
+                self.tasks.shutdown().await;
+                return Err(err);
+            }
+        }
+        info!("All server tasks have finished");
+        Ok(())
+    }
+
+    async fn run<F, E>(name: &'static str, task: F) -> Result<(), ServerSideError>
+    where
+        F: Future<Output = Result<(), E>>,
+        E: Display,
+    {
+        match AssertUnwindSafe(task).catch_unwind().await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(err)) => Err(ServerSideError::TaskFailed(name, err.to_string())),
+            Err(_) => Err(ServerSideError::TaskPanicked(name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const RESTART: Restart = Restart {
+        max_restarts: 2,
+        backoff: Duration::from_millis(1),
+    };
+
+    #[tokio::test]
+    async fn test_restart_until_succeeded() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let task_attempts = attempts.clone();
+        let mut supervisor = Supervisor::new();
+        supervisor.spawn_restartable("flaky", RESTART, move || {
+            let attempts = task_attempts.clone();
+            async move {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err("failed"),
+                    1 => panic!("panicked"),
+                    _ => Ok(()),
+                }
+            }
+        });
+        assert!(supervisor.wait().await.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_fatal_error_abort_others() {
+        let mut supervisor = Supervisor::new();
+        supervisor.spawn("forever", async {
+            std::future::pending::<()>().await;
+            Ok::<(), String>(())
+        });
+        supervisor.spawn_restartable("broken", RESTART, || async { Err("failed") });
+        let result = supervisor.wait().await;
+        assert!(matches!(
+            result,
+            Err(ServerSideError::TaskFailed("broken", _))
+        ));
+    }
+}
@@ -0,0 +1,278 @@
+// TLS termination of the device listener, certificates are reloaded without restart.
+use crate::config::TlsConfig;
+use crate::server::ServerSideError;
+use serde::Deserialize;
+use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient,
+};
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// Whether devices present a certificate signed by the client CA.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuth {
+    #[default]
+    Off,
+    // Devices without certificate are accepted, but the presented one must be valid.
+    Optional,
+    Required,
+}
+
+/// Identity of the device certificate, given to the sign in authentication.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerIdentity {
+    pub common_name: Option<String>,
+    pub subject: String,
+}
+
+impl PeerIdentity {
+    // Identity of the leaf certificate, none if it's not a valid x509 certificate.
+    pub fn from_certificates(certificates: &[Certificate]) -> Option<PeerIdentity> {
+        let leaf = certificates.first()?;
+        let (_, certificate) = x509_parser::parse_x509_certificate(&leaf.0).ok()?;
+        let common_name = certificate
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|common_name| common_name.as_str().ok())
+            .map(|common_name| common_name.to_string());
+        Some(PeerIdentity {
+            common_name,
+            subject: certificate.subject().to_string(),
+        })
+    }
+}
+
+/// Shared by connections, the acceptor is replaced when the certificates reloaded.
+#[derive(Clone)]
+pub struct ReloadableTlsAcceptor {
+    config: TlsConfig,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+}
+
+impl ReloadableTlsAcceptor {
+    pub fn new(config: TlsConfig) -> Result<ReloadableTlsAcceptor, ServerSideError> {
+        let acceptor = Self::load(&config)?;
+        Ok(ReloadableTlsAcceptor {
+            config,
+            acceptor: Arc::new(RwLock::new(acceptor)),
+        })
+    }
+
+    /// Read certificates from the files again, connections accepted later use them. The old
+    /// certificates are kept when the files are invalid.
+    pub fn reload(&self) -> Result<(), ServerSideError> {
+        let acceptor = Self::load(&self.config)?;
+        *self.acceptor.write().unwrap() = acceptor;
+        Ok(())
+    }
+
+    pub async fn accept(&self, socket: TcpStream) -> Result<DeviceStream, ServerSideError> {
+        let acceptor = self.acceptor.read().unwrap().clone();
+        let tls_stream = acceptor
+            .accept(socket)
+            .await
+            .map_err(|err| ServerSideError::TlsError(err.to_string()))?;
+        Ok(DeviceStream::Tls(Box::new(tls_stream)))
+    }
+
+    fn load(config: &TlsConfig) -> Result<TlsAcceptor, ServerSideError> {
+        let certificates = load_certificates(&config.cert_path)?;
+        let key = load_private_key(&config.key_path)?;
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match config.client_auth {
+            ClientAuth::Off => builder.with_no_client_auth(),
+            ClientAuth::Optional => builder.with_client_cert_verifier(
+                AllowAnyAnonymousOrAuthenticatedClient::new(load_client_roots(config)?).boxed(),
+            ),
+            ClientAuth::Required => builder.with_client_cert_verifier(
+                AllowAnyAuthenticatedClient::new(load_client_roots(config)?).boxed(),
+            ),
+        };
+        let server_config = builder
+            .with_single_cert(certificates, key)
+            .map_err(|err| ServerSideError::TlsError(err.to_string()))?;
+        Ok(TlsAcceptor::from(Arc::new(server_config)))
+    }
+}
+
+impl Debug for ReloadableTlsAcceptor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReloadableTlsAcceptor")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+fn open(path: &str) -> Result<BufReader<File>, ServerSideError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| ServerSideError::TlsError(format!("open {} failed: {}", path, err)))
+}
+
+fn load_certificates(path: &str) -> Result<Vec<Certificate>, ServerSideError> {
+    let certificates = rustls_pemfile::certs(&mut open(path)?)
+        .map_err(|err| ServerSideError::TlsError(format!("read {} failed: {}", path, err)))?;
+    if certificates.is_empty() {
+        return Err(ServerSideError::TlsError(format!(
+            "no certificate in {}",
+            path
+        )));
+    }
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+// The first private key in the file, PKCS8, RSA and EC keys are supported.
+fn load_private_key(path: &str) -> Result<PrivateKey, ServerSideError> {
+    let mut reader = open(path)?;
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .map_err(|err| ServerSideError::TlsError(format!("read {} failed: {}", path, err)))?
+        {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => {
+                return Err(ServerSideError::TlsError(format!(
+                    "no private key in {}",
+                    path
+                )))
+            }
+        }
+    }
+}
+
+fn load_client_roots(config: &TlsConfig) -> Result<RootCertStore, ServerSideError> {
+    let Some(client_ca_path) = &config.client_ca_path else {
+        return Err(ServerSideError::TlsError(
+            "client_ca_path is required when client auth is enabled".to_string(),
+        ));
+    };
+    let mut roots = RootCertStore::empty();
+    for certificate in load_certificates(client_ca_path)? {
+        roots
+            .add(&certificate)
+            .map_err(|err| ServerSideError::TlsError(err.to_string()))?;
+    }
+    Ok(roots)
+}
+
+/// Connection of a device, plain tcp or terminated tls.
+pub enum DeviceStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl DeviceStream {
+    pub fn peer_identity(&self) -> Option<PeerIdentity> {
+        match self {
+            DeviceStream::Plain(_) => None,
+            DeviceStream::Tls(tls_stream) => {
+                PeerIdentity::from_certificates(tls_stream.get_ref().1.peer_certificates()?)
+            }
+        }
+    }
+}
+
+impl AsyncRead for DeviceStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            DeviceStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            DeviceStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for DeviceStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            DeviceStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            DeviceStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            DeviceStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            DeviceStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            DeviceStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            DeviceStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// SIGHUP asks to reload the certificates, never received on other platforms.
+pub struct ReloadSignal {
+    #[cfg(unix)]
+    hangup: Option<tokio::signal::unix::Signal>,
+}
+
+impl ReloadSignal {
+    pub fn listen() -> ReloadSignal {
+        ReloadSignal {
+            #[cfg(unix)]
+            hangup: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok(),
+        }
+    }
+
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(hangup) = &mut self.hangup {
+            if hangup.recv().await.is_some() {
+                return;
+            }
+        }
+        std::future::pending::<()>().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tls_config(client_auth: ClientAuth) -> TlsConfig {
+        TlsConfig {
+            cert_path: "config/not-exists.pem".to_string(),
+            key_path: "config/not-exists.key".to_string(),
+            client_ca_path: None,
+            client_auth,
+            bind_client_id: false,
+        }
+    }
+
+    #[test]
+    fn test_client_roots_required_with_client_auth() {
+        let result = load_client_roots(&tls_config(ClientAuth::Required));
+        assert!(matches!(result, Err(ServerSideError::TlsError(_))));
+    }
+
+    #[test]
+    fn test_load_missing_certificates() {
+        let result = ReloadableTlsAcceptor::new(tls_config(ClientAuth::Off));
+        assert!(matches!(result, Err(ServerSideError::TlsError(_))));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::security::SecurityError;
 use std::net::AddrParseError;
 use tonic::transport::Error;
 
@@ -20,4 +21,13 @@ pub enum RaftStorageError {
 
     #[error("Key value is None")]
     ClientKeyNotFoundError,
+
+    #[error("Forward to the raft leader {0} failed, cause by: {1}")]
+    ForwardError(String, String),
+
+    #[error("Raft leader changed to node {0}.")]
+    LeaderChanged(u64),
+
+    #[error(transparent)]
+    SecurityError(#[from] SecurityError),
 }
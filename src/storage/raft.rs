@@ -1,11 +1,18 @@
-use crate::router::{RouterId, RouterStorage, Value};
+use crate::router::offline::OfflinePacket;
+use crate::router::{Router, RouterError, RouterId, RouterStorage, Value};
 use async_trait::async_trait;
 use openraft::storage::Adaptor;
 use openraft::{BasicNode, Config, Entry};
+use pool::PoolConfig;
 use std::collections::BTreeMap;
 use std::io::Cursor;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::{error, warn};
 
+use crate::config::RaftConfig;
+use crate::security::ClusterSecurity;
 use crate::server::channel::ChannelId;
 use crate::storage::raft::client::RaftClient;
 use crate::storage::raft::network::NetworkManager;
@@ -57,40 +64,54 @@ openraft::declare_raft_types!(
 #[derive(Clone)]
 pub struct RaftServer {
     raft: Option<RaftCore>,
-    server_addr: String,
-    node_id: u64,
-    // FIXME add more config here
+    raft_client: Option<RaftClient>,
+    config: RaftConfig,
+    // Notified to stop the raft api server.
+    shutdown: Arc<Notify>,
+    security: ClusterSecurity,
+    channel_pool: PoolConfig,
 }
 
 // When main server start and before accept tcp connections, start the RaftStore.
 // Include start a raft server, check snapshot data to the router etc.
 impl RaftServer {
-    pub fn new(node_id: u64, server_addr: String) -> RaftServer {
+    pub fn new(
+        config: RaftConfig,
+        security: ClusterSecurity,
+        channel_pool: PoolConfig,
+    ) -> RaftServer {
         RaftServer {
             raft: None,
-            server_addr,
-            node_id,
+            raft_client: None,
+            config,
+            shutdown: Arc::new(Notify::new()),
+            security,
+            channel_pool,
         }
     }
 
-    // init nodes config in config file
+    // init nodes config in config file, the api server is served by `serve_api`.
     pub async fn start(&mut self) -> Result<RaftClient, RaftStorageError> {
         let config = Config {
-            heartbeat_interval: 500,
-            election_timeout_min: 1500,
-            election_timeout_max: 3000,
+            heartbeat_interval: self.config.heartbeat_interval as u64,
+            election_timeout_min: self.config.election_timeout_min as u64,
+            election_timeout_max: self.config.election_timeout_max as u64,
             ..Default::default()
         };
 
-        let config = Arc::new(config.validate().unwrap());
+        let config = Arc::new(
+            config
+                .validate()
+                .map_err(|err| RaftStorageError::RaftError(err.to_string()))?,
+        );
 
         let store = Arc::new(Store::new());
         let (log_store, state_machine) = Adaptor::new(store.clone());
 
-        let network = NetworkManager::new();
+        let network = NetworkManager::new(self.security.clone(), self.channel_pool.clone());
 
         let raft = openraft::Raft::new(
-            self.node_id,
+            self.config.node_id,
             config.clone(),
             network,
             log_store,
@@ -103,31 +124,135 @@ impl RaftServer {
 
         self.init().await?;
 
-        let raft_client =
-            RaftClient::new(raft.clone(), store.clone(), 1, Node::new("0.0.0.0:9091"));
-        let raft_client_clone = raft_client.clone();
-        let raft_server_addr = self.server_addr.clone();
-        tokio::spawn(async move {
-            // FIXME error handle
-            start_raft_api_server(raft_server_addr.as_str(), raft, raft_client_clone)
-                .await
-                .unwrap();
+        // Writes are sent to the first member until the leader is known by a forward.
+        let (leader_id, leader_node) = self.members().pop_first().unwrap_or_else(|| {
+            (
+                self.config.node_id,
+                Node::new(&self.config.raft_network_addr),
+            )
         });
+        let raft_client = RaftClient::new(
+            raft.clone(),
+            store.clone(),
+            leader_id,
+            leader_node,
+            self.security.clone(),
+            self.channel_pool.clone(),
+        );
+        self.raft_client = Some(raft_client.clone());
 
         Ok(raft_client)
     }
 
-    pub async fn init(&self) -> Result<(), RaftStorageError> {
-        let mut nodes = BTreeMap::new();
-        nodes.insert(1, Node::new("0.0.0.0:9091"));
-        nodes.insert(2, Node::new("0.0.0.0:9092"));
-        nodes.insert(3, Node::new("0.0.0.0:9093"));
+    // Serve raft rpc of other nodes and clients until the raft server shutdown.
+    pub async fn serve_api(&self) -> Result<(), RaftStorageError> {
+        let raft = self
+            .raft
+            .clone()
+            .ok_or(RaftStorageError::RaftServerRaftCoreIsNone)?;
+        let raft_client = self
+            .raft_client
+            .clone()
+            .ok_or(RaftStorageError::RaftServerRaftCoreIsNone)?;
+        start_raft_api_server(
+            self.config.raft_network_addr.as_str(),
+            raft,
+            raft_client,
+            self.shutdown.clone(),
+            &self.security,
+        )
+        .await
+    }
 
+    // Ready when the leader of the cluster is known, then writes can be served.
+    pub async fn wait_for_leader(&self, timeout: Duration) -> Result<NodeId, RaftStorageError> {
+        let raft = self
+            .raft
+            .as_ref()
+            .ok_or(RaftStorageError::RaftServerRaftCoreIsNone)?;
+        let mut metrics = raft.metrics();
+        let leader = async {
+            loop {
+                if let Some(leader) = metrics.borrow().current_leader {
+                    return Ok(leader);
+                }
+                metrics
+                    .changed()
+                    .await
+                    .map_err(|err| RaftStorageError::RaftError(err.to_string()))?;
+            }
+        };
+        tokio::time::timeout(timeout, leader)
+            .await
+            .map_err(|_| RaftStorageError::RaftError(format!("no leader in {:?}", timeout)))?
+    }
+
+    // Stop the raft core and the api server. Leadership can't be transferred with this version
+    // of openraft, if this node is the leader, it waits the followers replicated its last log
+    // before stopping, so the one elected after the election timeout has all the writes.
+    pub async fn shutdown(&self) {
+        if let Some(raft) = &self.raft {
+            if raft.metrics().borrow().current_leader == Some(self.config.node_id) {
+                let timeout = Duration::from_millis(self.config.election_timeout_max as u64);
+                if tokio::time::timeout(timeout, self.wait_for_replication(raft))
+                    .await
+                    .is_err()
+                {
+                    warn!(
+                        "Raft leader {} shutdown before the followers replicated in {:?}",
+                        self.config.node_id, timeout
+                    );
+                }
+            }
+            if let Err(err) = raft.shutdown().await {
+                error!(
+                    "Raft node {} shutdown cause a error: {}",
+                    self.config.node_id, err
+                );
+            }
+        }
+        self.shutdown.notify_one();
+    }
+
+    // Replication of every follower reached the last log of the leader.
+    async fn wait_for_replication(&self, raft: &RaftCore) {
+        let mut metrics = raft.metrics();
+        loop {
+            let replicated = {
+                let metrics = metrics.borrow();
+                metrics.replication.as_ref().map_or(true, |replication| {
+                    replication.values().all(|matched| {
+                        matched.as_ref().map(|log_id| log_id.index) >= metrics.last_log_index
+                    })
+                })
+            };
+            if replicated || metrics.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // Members of the cluster in the config, this node alone if none configured.
+    fn members(&self) -> BTreeMap<NodeId, Node> {
+        if self.config.members.is_empty() {
+            return BTreeMap::from([(
+                self.config.node_id,
+                Node::new(&self.config.raft_network_addr),
+            )]);
+        }
+        self.config
+            .members
+            .iter()
+            .map(|member| (member.node_id, Node::new(&member.raft_network_addr)))
+            .collect()
+    }
+
+    pub async fn init(&self) -> Result<(), RaftStorageError> {
         Ok(self
             .raft
             .clone()
             .ok_or_else(|| RaftStorageError::RaftServerRaftCoreIsNone)?
-            .initialize(nodes)
+            .initialize(self.members())
             .await
             .map_err(|err| RaftStorageError::RaftError(err.to_string()))?)
     }
@@ -148,15 +273,129 @@ impl RaftStorage {
 // Impl router operations here.
 #[async_trait]
 impl RouterStorage for RaftStorage {
-    async fn get_channel_router(&self, channel_id: ChannelId) -> Value {
-        todo!()
+    async fn get_channel_router(
+        &self,
+        channel_id: ChannelId,
+    ) -> Result<Option<Value>, RouterError> {
+        match self.raft_client.read(channel_id.into()).await {
+            Ok(json) => serde_json::from_str(json.as_str())
+                .map(Some)
+                .map_err(|err| RouterError::StorageError(err.to_string())),
+            Err(RaftStorageError::ClientKeyNotFoundError) => Ok(None),
+            Err(err) => Err(RouterError::StorageError(err.to_string())),
+        }
+    }
+
+    async fn update_or_insert_channel_node(&self, value: Value) -> Result<Value, RouterError> {
+        self.raft_client
+            .write(Request::Connect {
+                value: value.clone(),
+            })
+            .await
+            .map_err(|err| RouterError::StorageError(err.to_string()))?;
+        Ok(value)
+    }
+
+    async fn list_channel_nodes(&self) -> Result<Vec<Value>, RouterError> {
+        self.raft_client
+            .read_all()
+            .await
+            .iter()
+            .map(|json| {
+                serde_json::from_str(json.as_str())
+                    .map_err(|err| RouterError::StorageError(err.to_string()))
+            })
+            .collect()
+    }
+
+    async fn compare_and_swap_channel_node(
+        &self,
+        expect: Option<RouterId>,
+        value: Value,
+    ) -> Result<bool, RouterError> {
+        let response = self
+            .raft_client
+            .write(Request::CompareAndSwap { expect, value })
+            .await
+            .map_err(|err| RouterError::StorageError(err.to_string()))?;
+        Ok(response.data.value.is_some())
+    }
+
+    async fn router_lease(&self, router: Router) -> Result<(), RouterError> {
+        self.raft_client
+            .write(Request::RouterLease { router })
+            .await
+            .map_err(|err| RouterError::StorageError(err.to_string()))?;
+        Ok(())
+    }
+
+    async fn router_release(&self, router_id: RouterId) -> Result<(), RouterError> {
+        self.raft_client
+            .write(Request::RouterRelease { router_id })
+            .await
+            .map_err(|err| RouterError::StorageError(err.to_string()))?;
+        Ok(())
     }
 
-    async fn update_or_insert_channel_node(&self, value: Value) -> Value {
-        todo!()
+    async fn list_routers(&self) -> Result<Vec<Router>, RouterError> {
+        Ok(self.raft_client.read_routers().await)
     }
 
-    async fn router_lease(&self, router: RouterId) -> Option<RouterId> {
-        todo!()
+    async fn update_group(
+        &self,
+        group: String,
+        members: Vec<ChannelId>,
+    ) -> Result<(), RouterError> {
+        let members = members.into_iter().map(|member| member.into()).collect();
+        self.raft_client
+            .write(Request::UpdateGroup { group, members })
+            .await
+            .map_err(|err| RouterError::StorageError(err.to_string()))?;
+        Ok(())
+    }
+
+    async fn get_group(&self, group: String) -> Result<Vec<ChannelId>, RouterError> {
+        Ok(self
+            .raft_client
+            .read_group(group.as_str())
+            .await
+            .into_iter()
+            .map(ChannelId::from)
+            .collect())
+    }
+
+    async fn push_offline_packet(
+        &self,
+        channel_id: ChannelId,
+        packet: OfflinePacket,
+        max_depth: usize,
+    ) -> Result<(), RouterError> {
+        self.raft_client
+            .write(Request::PushOffline {
+                channel_id: channel_id.into(),
+                packet,
+                max_depth,
+            })
+            .await
+            .map_err(|err| RouterError::StorageError(err.to_string()))?;
+        Ok(())
+    }
+
+    async fn take_offline_packets(
+        &self,
+        channel_id: ChannelId,
+    ) -> Result<Vec<OfflinePacket>, RouterError> {
+        let response = self
+            .raft_client
+            .write(Request::TakeOffline {
+                channel_id: channel_id.into(),
+            })
+            .await
+            .map_err(|err| RouterError::StorageError(err.to_string()))?;
+        match response.data.value {
+            Some(json) => serde_json::from_str(json.as_str())
+                .map_err(|err| RouterError::StorageError(err.to_string())),
+            None => Ok(vec![]),
+        }
     }
 }
@@ -1,32 +1,38 @@
+use crate::router::Router;
+use crate::security::{ClusterChannel, ClusterSecurity};
 use crate::storage::raft::error::ForwardToLeader;
 use crate::storage::raft::raft_client_service::raft_client_service_client::RaftClientServiceClient;
 use crate::storage::raft::raft_client_service::RaftClientRequest;
 use crate::storage::raft::storage::{Request, Response, Store};
 use crate::storage::raft::{error, Node, NodeId, RaftCore, TypeConfig};
 use crate::storage::RaftStorageError;
+use egccri_future_util::{retry_if, RetryPolicy};
 use openraft::error::{ClientWriteError, RaftError};
 use openraft::raft::ClientWriteResponse;
-use pool::MutexPool;
+use pool::{MutexPool, PoolConfig, Pooled};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
-use tonic::transport::{Channel, Error};
-use tonic::Status;
+use tonic::transport::Error;
 use tracing::info;
 
+// Writes are forwarded to the new leader at once, twice at most.
+const FORWARD_RETRY: RetryPolicy = RetryPolicy::fixed(Duration::ZERO, 2);
+
 #[derive(Debug, Clone)]
-struct ForwardChannelBuilder;
+struct ForwardChannelBuilder {
+    security: ClusterSecurity,
+}
 
 #[async_trait::async_trait]
 impl pool::PoolItemBuilder for ForwardChannelBuilder {
     type Token = String;
-    type Item = Channel;
+    type Item = ClusterChannel;
     type Error = Error;
 
     async fn build(&self, addr: &Self::Token) -> Result<Self::Item, Self::Error> {
         info!("Building channel for addr: {}", &addr);
-        tonic::transport::Endpoint::new(addr.clone())?
-            .connect()
-            .await
+        self.security.connect(addr.clone()).await
     }
 }
 
@@ -46,9 +52,11 @@ impl RaftClient {
         store: Arc<Store>,
         leader_node_id: NodeId,
         leader_node: Node,
+        security: ClusterSecurity,
+        channel_pool: PoolConfig,
     ) -> RaftClient {
-        let channel_builder = ForwardChannelBuilder;
-        let channel_pool = MutexPool::new(channel_builder, None);
+        let channel_builder = ForwardChannelBuilder { security };
+        let channel_pool = MutexPool::new(channel_builder, channel_pool);
         RaftClient {
             inner: raft,
             storage: store,
@@ -57,38 +65,48 @@ impl RaftClient {
         }
     }
 
-    async fn write(
+    // Unreachable leader, rejected rpc and raft errors are returned, never panic the caller.
+    pub async fn write(
         &self,
         req: Request,
-    ) -> Result<ClientWriteResponse<TypeConfig>, RaftError<NodeId, ClientWriteError<NodeId, Node>>>
-    {
-        let mut n_retry = 3;
-        loop {
-            let leader = self.leader.lock().await;
-            let forward_channel = self.channel_pool.get(&leader.1.addr).await.unwrap();
-            let result = self.send_rpc_to_leader(req.clone(), forward_channel).await;
-
-            let rpc_err = match result {
-                Ok(x) => return Ok(x),
-                Err(rpc_err) => rpc_err,
-            };
+    ) -> Result<ClientWriteResponse<TypeConfig>, RaftStorageError> {
+        retry_if(
+            &FORWARD_RETRY,
+            || self.write_to_leader(req.clone()),
+            |err| matches!(err, RaftStorageError::LeaderChanged(_)),
+        )
+        .await
+    }
 
-            if let Some(ForwardToLeader {
+    // Forward to the leader known, it's updated when the leader changed.
+    async fn write_to_leader(
+        &self,
+        req: Request,
+    ) -> Result<ClientWriteResponse<TypeConfig>, RaftStorageError> {
+        // Release the leader lock before rpc, it will be updated when forward to leader.
+        let leader_addr = format!("http://{}", self.leader.lock().await.1.addr);
+        let forward_channel =
+            self.channel_pool.get(&leader_addr).await.map_err(|err| {
+                RaftStorageError::ForwardError(leader_addr.clone(), err.to_string())
+            })?;
+        let rpc_err = match self
+            .send_rpc_to_leader(req, &leader_addr, forward_channel)
+            .await?
+        {
+            Ok(reply) => return Ok(reply),
+            Err(rpc_err) => rpc_err,
+        };
+        match rpc_err.forward_to_leader() {
+            Some(ForwardToLeader {
                 leader_id: Some(leader_id),
                 leader_node: Some(leader_node),
-            }) = rpc_err.forward_to_leader()
-            {
+            }) => {
                 // Update target to the new leader.
-                {
-                    let mut t = self.leader.lock().await;
-                    *t = (*leader_id, leader_node.clone());
-                }
-
-                n_retry -= 1;
-                if n_retry > 0 {
-                    continue;
-                }
+                let mut t = self.leader.lock().await;
+                *t = (*leader_id, leader_node.clone());
+                Err(RaftStorageError::LeaderChanged(*leader_id))
             }
+            _ => Err(RaftStorageError::RaftError(rpc_err.to_string())),
         }
     }
 
@@ -109,25 +127,52 @@ impl RaftClient {
         Ok(a.clone())
     }
 
+    pub async fn read_all(&self) -> Vec<String> {
+        let sm = self.storage.state_machine.read().await;
+        sm.data_tree.values().cloned().collect()
+    }
+
+    pub async fn read_routers(&self) -> Vec<Router> {
+        let sm = self.storage.state_machine.read().await;
+        sm.routers.values().cloned().collect()
+    }
+
+    pub async fn read_group(&self, group: &str) -> Vec<String> {
+        let sm = self.storage.state_machine.read().await;
+        sm.groups.get(group).cloned().unwrap_or_default()
+    }
+
+    // The outer error is the rpc failed, the inner one is the raft error replied by the leader.
+    #[allow(clippy::type_complexity)]
     async fn send_rpc_to_leader(
         &self,
         request: Request,
-        forward_channel: Channel,
-    ) -> Result<ClientWriteResponse<TypeConfig>, RaftError<NodeId, ClientWriteError<NodeId, Node>>>
-    {
-        let mut client = RaftClientServiceClient::new(forward_channel);
-        let request = serde_json::to_string(&request).unwrap();
+        leader_addr: &str,
+        forward_channel: Pooled<ClusterChannel>,
+    ) -> Result<
+        Result<ClientWriteResponse<TypeConfig>, RaftError<NodeId, ClientWriteError<NodeId, Node>>>,
+        RaftStorageError,
+    > {
+        let mut client = RaftClientServiceClient::new(forward_channel.clone());
+        let request = serde_json::to_string(&request)
+            .map_err(|err| RaftStorageError::RaftError(err.to_string()))?;
         let request = tonic::Request::new(RaftClientRequest { inner: request });
-        let result = client.forward(request).await.unwrap().into_inner();
+        let result = client
+            .forward(request)
+            .await
+            .map_err(|status| {
+                RaftStorageError::ForwardError(leader_addr.to_string(), status.to_string())
+            })?
+            .into_inner();
 
         if !result.inner.is_empty() {
-            let reply: ClientWriteResponse<TypeConfig> =
-                serde_json::from_str(result.inner.as_str()).unwrap();
-            Ok(reply)
+            serde_json::from_str(result.inner.as_str())
+                .map(Ok)
+                .map_err(|err| RaftStorageError::RaftError(err.to_string()))
         } else {
-            let err: RaftError<NodeId, ClientWriteError<NodeId, Node>> =
-                serde_json::from_str(result.error.as_str()).unwrap();
-            Err(err)
+            serde_json::from_str(result.error.as_str())
+                .map(Err)
+                .map_err(|err| RaftStorageError::RaftError(err.to_string()))
         }
     }
 }
@@ -1,5 +1,6 @@
 use super::TypeConfig;
 use super::{Node, NodeId};
+use crate::security::{ClusterChannel, ClusterSecurity};
 use crate::storage::raft::raft_service::raft_service_client::RaftServiceClient;
 use crate::storage::raft::raft_service::RaftRequest;
 use openraft::async_trait::async_trait;
@@ -10,24 +11,25 @@ use openraft::raft::{
 };
 use openraft::MessageSummary;
 use openraft::{RaftNetwork, RaftNetworkFactory, RaftTypeConfig};
-use pool::MutexPool;
-use tonic::transport::{Channel, Error};
+use pool::{MutexPool, PoolConfig, Pooled};
+use std::sync::Arc;
+use tonic::transport::Error;
 use tracing::info;
 
 #[derive(Debug, Clone)]
-struct NetworkChannelBuilder;
+struct NetworkChannelBuilder {
+    security: ClusterSecurity,
+}
 
 #[async_trait::async_trait]
 impl pool::PoolItemBuilder for NetworkChannelBuilder {
     type Token = String;
-    type Item = Channel;
+    type Item = ClusterChannel;
     type Error = Error;
 
     async fn build(&self, addr: &Self::Token) -> Result<Self::Item, Self::Error> {
         info!("Building channel for addr: {}", &addr);
-        tonic::transport::Endpoint::new(addr.clone())?
-            .connect()
-            .await
+        self.security.connect(addr.clone()).await
     }
 }
 
@@ -61,13 +63,16 @@ impl RaftNetworkFactory<TypeConfig> for NetworkManager {
 }
 
 impl NetworkManager {
-    pub fn new() -> NetworkManager {
-        let channel_builder = NetworkChannelBuilder;
-        let channel_pool = MutexPool::new(channel_builder, None);
+    pub fn new(security: ClusterSecurity, channel_pool: PoolConfig) -> NetworkManager {
+        let channel_builder = NetworkChannelBuilder { security };
+        let channel_pool = MutexPool::new(channel_builder, channel_pool);
         NetworkManager { channel_pool }
     }
 
-    pub async fn make_client(&self, target_node: Node) -> Result<Channel, Error> {
+    pub async fn make_client(
+        &self,
+        target_node: Node,
+    ) -> Result<Pooled<ClusterChannel>, Arc<Error>> {
         let addr = format!("http://{}", target_node.addr);
         self.channel_pool.get(&addr).await
     }
@@ -86,7 +91,7 @@ impl NetworkManager {
             .make_client(target_node)
             .await
             .map_err(|err| RPCError::Network(NetworkError::new(&err)))?;
-        let mut client = RaftServiceClient::new(channel);
+        let mut client = RaftServiceClient::new(channel.clone());
 
         let request = RaftRequest { data: payload };
         let result = client
@@ -116,7 +121,7 @@ impl NetworkManager {
             .make_client(target_node)
             .await
             .map_err(|err| RPCError::Network(NetworkError::new(&err)))?;
-        let mut client = RaftServiceClient::new(channel);
+        let mut client = RaftServiceClient::new(channel.clone());
 
         let request = RaftRequest { data: payload };
         let result = client
@@ -139,9 +144,11 @@ impl NetworkManager {
             "Send vote call to [target: {}, node: {}], with payload {}",
             target, &target_node, &payload
         );
-        let mut client = RaftServiceClient::connect(format!("http://{}", target_node.addr))
+        let channel = self
+            .make_client(target_node)
             .await
-            .map_err(|e| RPCError::Network(NetworkError::new(&e)))?;
+            .map_err(|err| RPCError::Network(NetworkError::new(&err)))?;
+        let mut client = RaftServiceClient::new(channel.clone());
         let request = RaftRequest { data: payload };
         let result = client
             .vote(tonic::Request::new(request))
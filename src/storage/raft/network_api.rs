@@ -1,3 +1,4 @@
+use crate::security::ClusterSecurity;
 use crate::storage::raft::client::RaftClient;
 use crate::storage::raft::raft_client_service::raft_client_service_server::{
     RaftClientService, RaftClientServiceServer,
@@ -9,7 +10,8 @@ use crate::storage::raft::{NodeId, RaftCore, TypeConfig};
 use crate::storage::RaftStorageError;
 use openraft::error::RaftError;
 use openraft::raft::ClientWriteResponse;
-use tonic::transport::Server;
+use std::sync::Arc;
+use tokio::sync::Notify;
 use tonic::{Request, Response, Status};
 use tracing::info;
 
@@ -18,14 +20,23 @@ pub async fn start_raft_api_server(
     addr: &str,
     raft_core: RaftCore,
     raft_client: RaftClient,
+    shutdown: Arc<Notify>,
+    security: &ClusterSecurity,
 ) -> Result<(), RaftStorageError> {
     let socket_addr = addr.parse()?;
     let raft_service = RaftSvc::new(raft_core.clone());
     let raft_client_service = RaftClientSvc::new(raft_client);
-    Server::builder()
-        .add_service(RaftServiceServer::new(raft_service))
-        .add_service(RaftClientServiceServer::new(raft_client_service))
-        .serve(socket_addr)
+    security
+        .server()?
+        .add_service(RaftServiceServer::with_interceptor(
+            raft_service,
+            security.verifier(),
+        ))
+        .add_service(RaftClientServiceServer::with_interceptor(
+            raft_client_service,
+            security.verifier(),
+        ))
+        .serve_with_shutdown(socket_addr, shutdown.notified())
         .await?;
     Ok(())
 }
@@ -110,11 +121,13 @@ impl RaftClientService for RaftClientSvc {
     ) -> Result<Response<RaftClientReply>, Status> {
         let request = request.into_inner().inner;
         info!("Received forward request with payload {}", &request);
-        let request = serde_json::from_str(request.as_str()).unwrap();
+        let request = serde_json::from_str(request.as_str())
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
         let result = self.raft_client._write(request).await;
         match result {
             Ok(response) => {
-                let json = serde_json::to_string(&response).unwrap();
+                let json = serde_json::to_string(&response)
+                    .map_err(|err| Status::internal(err.to_string()))?;
                 let reply = RaftClientReply {
                     inner: json,
                     error: "".to_string(),
@@ -122,7 +135,8 @@ impl RaftClientService for RaftClientSvc {
                 Ok(Response::new(reply))
             }
             Err(err) => {
-                let json = serde_json::to_string(&err).unwrap();
+                let json =
+                    serde_json::to_string(&err).map_err(|err| Status::internal(err.to_string()))?;
                 let reply = RaftClientReply {
                     inner: "".to_string(),
                     error: json,
@@ -1,4 +1,5 @@
-use crate::router::Value;
+use crate::router::offline::{self, OfflinePacket};
+use crate::router::{Router, RouterId, Value};
 use crate::storage::raft::{Node, NodeId, TypeConfig};
 use openraft::async_trait::async_trait;
 use openraft::{
@@ -7,7 +8,7 @@ use openraft::{
     StoredMembership, Vote,
 };
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt::Debug;
 use std::io::Cursor;
 use std::ops::RangeBounds;
@@ -19,7 +20,37 @@ use tracing::debug;
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Request {
     // if it necessary add node_id, node id map for channel where
-    Connect { value: Value }, // replay old value
+    Connect {
+        value: Value,
+    }, // replay old value
+    // swap only if the channel router is expected, reply none if skipped
+    CompareAndSwap {
+        expect: Option<RouterId>,
+        value: Value,
+    },
+    // register the router with it's addresses, replace the old one
+    RouterLease {
+        router: Router,
+    },
+    // remove the router from registry when it shutting down
+    RouterRelease {
+        router_id: RouterId,
+    },
+    // replace members of the static group, remove the group if members is empty
+    UpdateGroup {
+        group: String,
+        members: Vec<String>,
+    },
+    // queue the packet of the offline channel
+    PushOffline {
+        channel_id: String,
+        packet: OfflinePacket,
+        max_depth: usize,
+    },
+    // remove the queued packets of the channel and reply them
+    TakeOffline {
+        channel_id: String,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -63,6 +94,18 @@ pub struct StateMachine {
     pub last_membership: StoredMembership<NodeId, Node>,
 
     pub data_tree: BTreeMap<String, String>,
+
+    // Routers registered in the cluster, key is router id.
+    #[serde(default)]
+    pub routers: BTreeMap<RouterId, Router>,
+
+    // Static groups of channels used by broadcasting, key is group name.
+    #[serde(default)]
+    pub groups: BTreeMap<String, Vec<String>>,
+
+    // Packets queued for offline channels, key is channel id.
+    #[serde(default)]
+    pub offline: BTreeMap<String, VecDeque<OfflinePacket>>,
 }
 
 pub struct StoreSnapshot {
@@ -91,6 +134,9 @@ impl StateMachine {
             last_applied_log: None,
             last_membership: Default::default(),
             data_tree: Default::default(),
+            routers: Default::default(),
+            groups: Default::default(),
+            offline: Default::default(),
         }
     }
 
@@ -310,7 +356,65 @@ impl RaftStorage<TypeConfig> for Arc<Store> {
                         sm.data_tree.insert(value.channel_id().into(), json.clone());
                         res.push(Response::new(Some(json)));
                     }
-                    _ => {}
+                    Request::CompareAndSwap { expect, value } => {
+                        let key: String = value.channel_id().into();
+                        let current = match sm.data_tree.get(&key) {
+                            Some(current) => {
+                                let current: Value = serde_json::from_str(current)
+                                    .map_err(|e| StorageIOError::read_state_machine(&e))?;
+                                Some(current.router.router_id())
+                            }
+                            None => None,
+                        };
+                        if current == *expect {
+                            let json = serde_json::to_string(&value).map_err(|e| {
+                                StorageIOError::write_log_entry(*entry.get_log_id(), &e)
+                            })?;
+
+                            sm.data_tree.insert(key, json.clone());
+                            res.push(Response::new(Some(json)));
+                        } else {
+                            res.push(Response::new(None));
+                        }
+                    }
+                    Request::RouterLease { router } => {
+                        let json = serde_json::to_string(&router).map_err(|e| {
+                            StorageIOError::write_log_entry(*entry.get_log_id(), &e)
+                        })?;
+
+                        sm.routers.insert(router.router_id(), router.clone());
+                        res.push(Response::new(Some(json)));
+                    }
+                    Request::RouterRelease { router_id } => {
+                        sm.routers.remove(router_id);
+                        res.push(Response::new(None));
+                    }
+                    Request::UpdateGroup { group, members } => {
+                        if members.is_empty() {
+                            sm.groups.remove(group);
+                        } else {
+                            sm.groups.insert(group.clone(), members.clone());
+                        }
+                        res.push(Response::new(None));
+                    }
+                    Request::PushOffline {
+                        channel_id,
+                        packet,
+                        max_depth,
+                    } => {
+                        // Queues of devices never back are dropped when their packets expired.
+                        offline::prune(&mut sm.offline, packet.queued_at);
+                        let queue = sm.offline.entry(channel_id.clone()).or_default();
+                        offline::enqueue(queue, packet.clone(), *max_depth);
+                        res.push(Response::new(None));
+                    }
+                    Request::TakeOffline { channel_id } => {
+                        let queue = sm.offline.remove(channel_id).unwrap_or_default();
+                        let json = serde_json::to_string(&queue).map_err(|e| {
+                            StorageIOError::write_log_entry(*entry.get_log_id(), &e)
+                        })?;
+                        res.push(Response::new(Some(json)));
+                    }
                 },
                 EntryPayload::Membership(ref mem) => {
                     sm.last_membership = StoredMembership::new(Some(entry.log_id), mem.clone());
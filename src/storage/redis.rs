@@ -1,21 +1,74 @@
-use crate::router::{RouterId, RouterStorage, Value};
+use crate::router::offline::OfflinePacket;
+use crate::router::{Router, RouterError, RouterId, RouterStorage, Value};
 use crate::server::channel::ChannelId;
 use async_trait::async_trait;
 
+// Not implemented yet, the config is rejected by the validation so it's never used.
 #[derive(Debug, Clone)]
 pub struct RedisStorage {}
 
 #[async_trait]
 impl RouterStorage for RedisStorage {
-    async fn get_channel_router(&self, channel_id: ChannelId) -> Value {
-        todo!()
+    async fn get_channel_router(
+        &self,
+        _channel_id: ChannelId,
+    ) -> Result<Option<Value>, RouterError> {
+        Err(RouterError::StorageUnsupported("redis", "channel routes"))
     }
 
-    async fn update_or_insert_channel_node(&self, value: Value) -> Value {
-        todo!()
+    async fn update_or_insert_channel_node(&self, _value: Value) -> Result<Value, RouterError> {
+        Err(RouterError::StorageUnsupported("redis", "channel routes"))
     }
 
-    async fn router_lease(&self, router: RouterId) -> Option<RouterId> {
-        todo!()
+    async fn list_channel_nodes(&self) -> Result<Vec<Value>, RouterError> {
+        Err(RouterError::StorageUnsupported("redis", "channel routes"))
+    }
+
+    async fn compare_and_swap_channel_node(
+        &self,
+        _expect: Option<RouterId>,
+        _value: Value,
+    ) -> Result<bool, RouterError> {
+        Err(RouterError::StorageUnsupported("redis", "channel routes"))
+    }
+
+    async fn router_lease(&self, _router: Router) -> Result<(), RouterError> {
+        Err(RouterError::StorageUnsupported("redis", "routers"))
+    }
+
+    async fn router_release(&self, _router_id: RouterId) -> Result<(), RouterError> {
+        Err(RouterError::StorageUnsupported("redis", "routers"))
+    }
+
+    async fn list_routers(&self) -> Result<Vec<Router>, RouterError> {
+        Err(RouterError::StorageUnsupported("redis", "routers"))
+    }
+
+    async fn update_group(
+        &self,
+        _group: String,
+        _members: Vec<ChannelId>,
+    ) -> Result<(), RouterError> {
+        Err(RouterError::StorageUnsupported("redis", "groups"))
+    }
+
+    async fn get_group(&self, _group: String) -> Result<Vec<ChannelId>, RouterError> {
+        Err(RouterError::StorageUnsupported("redis", "groups"))
+    }
+
+    async fn push_offline_packet(
+        &self,
+        _channel_id: ChannelId,
+        _packet: OfflinePacket,
+        _max_depth: usize,
+    ) -> Result<(), RouterError> {
+        Err(RouterError::StorageUnsupported("redis", "offline packets"))
+    }
+
+    async fn take_offline_packets(
+        &self,
+        _channel_id: ChannelId,
+    ) -> Result<Vec<OfflinePacket>, RouterError> {
+        Err(RouterError::StorageUnsupported("redis", "offline packets"))
     }
 }